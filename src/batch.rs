@@ -0,0 +1,421 @@
+//! Downloading many independent files in one process.
+
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use futures_util::stream::{self, StreamExt};
+use log::{error, warn};
+use tokio::sync::Semaphore;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::download::{
+    build_client, BandwidthLimiter, DownloadConfig, DownloadManager, DownloadOutcome, HostLimits,
+};
+use crate::progress::{
+    observer_for_mode, DownloadEvent, ProgressFinish, ProgressObserver, ProgressSnapshot,
+};
+
+const AGGREGATE_TICK: Duration = Duration::from_millis(100);
+
+/// One file of an input list: its URLs (the first is primary, the rest are
+/// mirrors) and per-entry options.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub urls: Vec<String>,
+    /// `out=`: file name, relative to `dir` or the batch output directory.
+    pub out: Option<String>,
+    /// `dir=`: directory for this entry.
+    pub dir: Option<PathBuf>,
+    /// `checksum=`: digest in `--checksum` form, e.g. aria2's `sha-256=<hex>`.
+    pub checksum: Option<String>,
+}
+
+/// Parses a download list.
+///
+/// Each non-indented line starts an entry; URLs on the same line separated by
+/// whitespace are mirrors of one file. Following lines indented with
+/// whitespace carry aria2-style `key=value` options for that entry. Blank
+/// lines and lines starting with `#` are ignored.
+pub fn parse_input_list(text: &str) -> Result<Vec<BatchEntry>> {
+    let mut entries: Vec<BatchEntry> = Vec::new();
+    for (index, raw) in text.lines().enumerate() {
+        let line_no = index + 1;
+        let trimmed = raw.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+
+        if raw.starts_with(char::is_whitespace) {
+            let entry = entries
+                .last_mut()
+                .ok_or_else(|| anyhow!("line {line_no}: option without a preceding URL"))?;
+            let (key, value) = trimmed
+                .split_once('=')
+                .ok_or_else(|| anyhow!("line {line_no}: expected key=value, got {trimmed:?}"))?;
+            let value = value.trim().to_string();
+            match key.trim() {
+                "out" => entry.out = Some(value),
+                "dir" => entry.dir = Some(PathBuf::from(value)),
+                "checksum" => entry.checksum = Some(value),
+                other => warn!("line {line_no}: ignoring unsupported option {other:?}"),
+            }
+            continue;
+        }
+
+        entries.push(BatchEntry {
+            urls: trimmed.split_whitespace().map(str::to_string).collect(),
+            ..BatchEntry::default()
+        });
+    }
+    Ok(entries)
+}
+
+/// Per-file result of a batch.
+pub struct BatchItem {
+    pub output: PathBuf,
+    pub result: Result<DownloadOutcome>,
+}
+
+/// Runs several downloads concurrently over one HTTP client, one bandwidth
+/// limiter, one connection budget and one set of per-host limits.
+pub struct BatchDownloader {
+    configs: Vec<DownloadConfig>,
+    max_concurrent: usize,
+    max_connections: usize,
+    observer: Option<Arc<dyn ProgressObserver>>,
+}
+
+impl BatchDownloader {
+    /// Client options, the bandwidth limit and the progress mode are taken
+    /// from the first configuration.
+    pub fn new(
+        configs: Vec<DownloadConfig>,
+        max_concurrent: usize,
+        max_connections: usize,
+    ) -> Self {
+        let observer = configs
+            .first()
+            .and_then(|config| observer_for_mode(config.progress));
+        Self {
+            configs,
+            max_concurrent: max_concurrent.max(1),
+            max_connections: max_connections.max(1),
+            observer,
+        }
+    }
+
+    /// Receives aggregated progress across all files instead of the
+    /// observer selected by the first configuration's progress mode.
+    pub fn with_observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    pub async fn run(self) -> Result<Vec<BatchItem>> {
+        let first = self
+            .configs
+            .first()
+            .ok_or_else(|| anyhow!("input list contains no downloads"))?;
+        let client = build_client(first.max_connections_per_host, first.timeout)?;
+        let bandwidth = first
+            .bandwidth_limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit)));
+        let budget = Arc::new(Semaphore::new(self.max_connections));
+        let hosts = HostLimits::new(first.connections_per_host());
+        let aggregate = Arc::new(Aggregate::new(self.configs.len()));
+        let ticker = self
+            .observer
+            .clone()
+            .map(|observer| aggregate.clone().spawn_ticker(observer));
+
+        let jobs = self.configs.into_iter().enumerate().map(|(index, config)| {
+            let output = config.output_path.clone();
+            let mut manager = DownloadManager::with_client(config, client.clone())
+                .with_connection_budget(budget.clone())
+                .with_host_limits(&hosts);
+            if let Some(limiter) = &bandwidth {
+                manager = manager.with_bandwidth_limiter(limiter.clone());
+            }
+            if let Some(observer) = &self.observer {
+                manager = manager.with_observer(Arc::new(JobObserver {
+                    index,
+                    output: output.clone(),
+                    aggregate: aggregate.clone(),
+                    forward: observer.clone(),
+                }));
+            }
+            async move {
+                let result = manager.run().await;
+                if let Err(err) = &result {
+                    error!("{:?}: {err}", output);
+                }
+                BatchItem { output, result }
+            }
+        });
+        let items: Vec<BatchItem> = stream::iter(jobs)
+            .buffer_unordered(self.max_concurrent)
+            .collect()
+            .await;
+
+        if let Some(ticker) = ticker {
+            ticker.abort();
+        }
+        if let Some(observer) = &self.observer {
+            let result = if items.iter().all(|item| item.result.is_ok()) {
+                ProgressFinish::Success
+            } else {
+                ProgressFinish::Failure
+            };
+            observer.on_event(&DownloadEvent::Finished {
+                result,
+                snapshot: aggregate.snapshot(),
+                mirror_failures: Vec::new(),
+            });
+        }
+        Ok(items)
+    }
+}
+
+/// Progress of every job, folded into one snapshot.
+struct Aggregate {
+    started: Instant,
+    jobs: Mutex<Vec<ProgressSnapshot>>,
+}
+
+impl Aggregate {
+    fn new(len: usize) -> Self {
+        Self {
+            started: Instant::now(),
+            jobs: Mutex::new(vec![ProgressSnapshot::default(); len]),
+        }
+    }
+
+    fn update(&self, index: usize, snapshot: &ProgressSnapshot) {
+        self.jobs.lock().unwrap()[index] = snapshot.clone();
+    }
+
+    fn set_total(&self, index: usize, total: Option<u64>) {
+        self.jobs.lock().unwrap()[index].total = total;
+    }
+
+    /// Totals only cover files whose size is already known.
+    fn snapshot(&self) -> ProgressSnapshot {
+        let jobs = self.jobs.lock().unwrap();
+        let sum = |f: fn(&ProgressSnapshot) -> Option<usize>| -> Option<usize> {
+            jobs.iter().filter_map(f).reduce(|a, b| a + b)
+        };
+        ProgressSnapshot {
+            downloaded: jobs.iter().map(|job| job.downloaded).sum(),
+            total: jobs.iter().filter_map(|job| job.total).reduce(|a, b| a + b),
+            initial: jobs.iter().map(|job| job.initial).sum(),
+            elapsed: self.started.elapsed(),
+            segments_active: sum(|job| job.segments_active),
+            segments_pending: sum(|job| job.segments_pending),
+            target_parallelism: sum(|job| job.target_parallelism),
+        }
+    }
+
+    fn spawn_ticker(
+        self: Arc<Self>,
+        observer: Arc<dyn ProgressObserver>,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ticker = interval(AGGREGATE_TICK);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            loop {
+                ticker.tick().await;
+                observer.on_event(&DownloadEvent::Progress(self.snapshot()));
+            }
+        })
+    }
+}
+
+/// Folds one job's progress into the aggregate and forwards per-file
+/// lifecycle events.
+struct JobObserver {
+    index: usize,
+    output: PathBuf,
+    aggregate: Arc<Aggregate>,
+    forward: Arc<dyn ProgressObserver>,
+}
+
+impl ProgressObserver for JobObserver {
+    fn on_event(&self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::MetadataProbed { total_bytes, .. } => {
+                self.aggregate.set_total(self.index, *total_bytes);
+            }
+            DownloadEvent::Progress(snapshot) => {
+                self.aggregate.update(self.index, snapshot);
+            }
+            DownloadEvent::Started { .. } | DownloadEvent::Verifying { .. } => {
+                self.forward.on_event(event);
+            }
+            DownloadEvent::Finished {
+                result, snapshot, ..
+            } => {
+                if snapshot.total.is_some() {
+                    self.aggregate.update(self.index, snapshot);
+                }
+                self.forward.on_event(&DownloadEvent::FileFinished {
+                    output: self.output.clone(),
+                    result: *result,
+                    bytes: snapshot.downloaded,
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::ProgressMode;
+    use crate::test_server;
+    use hyper::{Body, Method, Response};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn parses_plain_and_aria2_style_lists() {
+        let list = "\
+# nightly artifacts
+https://a.example/one.iso\thttps://b.example/one.iso
+  out=first.iso
+  checksum=sha-256=abc
+https://a.example/two.iso
+
+https://a.example/three.iso
+ dir=/tmp/three
+";
+        let entries = parse_input_list(list).expect("parse");
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].urls.len(), 2);
+        assert_eq!(entries[0].out.as_deref(), Some("first.iso"));
+        assert_eq!(entries[0].checksum.as_deref(), Some("sha-256=abc"));
+        assert_eq!(entries[1].urls, vec!["https://a.example/two.iso"]);
+        assert_eq!(entries[2].dir, Some(PathBuf::from("/tmp/three")));
+    }
+
+    #[test]
+    fn option_before_url_is_an_error() {
+        assert!(parse_input_list("  out=x\nhttps://a.example/x").is_err());
+    }
+
+    /// Counts response bodies being sent and remembers the most at once.
+    #[derive(Default)]
+    struct Concurrency {
+        active: AtomicUsize,
+        peak: AtomicUsize,
+    }
+
+    struct Streaming(Arc<Concurrency>);
+
+    impl Streaming {
+        fn start(counter: &Arc<Concurrency>) -> Self {
+            let active = counter.active.fetch_add(1, Ordering::SeqCst) + 1;
+            counter.peak.fetch_max(active, Ordering::SeqCst);
+            Self(counter.clone())
+        }
+    }
+
+    impl Drop for Streaming {
+        fn drop(&mut self) {
+            self.0.active.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Serves `data` slowly enough for concurrent bodies to overlap.
+    fn slow_server(data: Arc<Vec<u8>>, counter: Arc<Concurrency>) -> String {
+        test_server::spawn(move |req| {
+            let response = test_server::file(req, &data);
+            if req.method() != Method::GET {
+                return response;
+            }
+            let range = test_server::requested_range(req, data.len() as u64);
+            let (first, last) = range.unwrap_or((0, data.len() as u64 - 1));
+            let body = data[first as usize..=last as usize].to_vec();
+            let streaming = Streaming::start(&counter);
+            let (mut sender, stream) = Body::channel();
+            tokio::spawn(async move {
+                let _streaming = streaming;
+                for chunk in body.chunks(16 * 1024) {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    if sender.send_data(chunk.to_vec().into()).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            let (parts, _) = response.into_parts();
+            Response::from_parts(parts, stream)
+        })
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        files: Mutex<Vec<(PathBuf, u64)>>,
+        total: Mutex<Option<u64>>,
+    }
+
+    impl ProgressObserver for Recorder {
+        fn on_event(&self, event: &DownloadEvent) {
+            match event {
+                DownloadEvent::FileFinished { output, bytes, .. } => {
+                    self.files.lock().unwrap().push((output.clone(), *bytes));
+                }
+                DownloadEvent::Finished { snapshot, .. } => {
+                    *self.total.lock().unwrap() = Some(snapshot.downloaded);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Downloads three files, each with two segments, and returns the most
+    /// bodies the server sent at once.
+    async fn run_batch(max_connections: usize, per_host: usize) -> usize {
+        let data = Arc::new((0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>());
+        let counter = Arc::new(Concurrency::default());
+        let base = slow_server(data.clone(), counter.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let configs: Vec<DownloadConfig> = (0..3)
+            .map(|index| {
+                DownloadConfig::builder(format!("{base}/file{index}.bin"))
+                    .output(dir.path().join(format!("file{index}.bin")))
+                    .segments(2)
+                    .connections(per_host)
+                    .progress(ProgressMode::Quiet)
+                    .build()
+                    .unwrap()
+            })
+            .collect();
+        let recorder = Arc::new(Recorder::default());
+        let items = BatchDownloader::new(configs, 3, max_connections)
+            .with_observer(recorder.clone())
+            .run()
+            .await
+            .unwrap();
+
+        for item in &items {
+            assert_eq!(item.result.as_ref().unwrap().bytes, data.len() as u64);
+            assert_eq!(std::fs::read(&item.output).unwrap(), *data);
+        }
+        let files = recorder.files.lock().unwrap();
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|(_, bytes)| *bytes == data.len() as u64));
+        assert_eq!(*recorder.total.lock().unwrap(), Some(3 * data.len() as u64));
+        counter.peak.load(Ordering::SeqCst)
+    }
+
+    #[tokio::test]
+    async fn jobs_share_the_connection_budget() {
+        assert_eq!(run_batch(2, 4).await, 2);
+    }
+
+    #[tokio::test]
+    async fn jobs_share_per_host_limits() {
+        assert_eq!(run_batch(8, 1).await, 1);
+    }
+}
@@ -1,67 +1,425 @@
+//! Whole-file checksums: digests given on the command line and checksum
+//! manifests such as `SHA256SUMS` (GNU `<hex>  <file>` lines) or BSD-style
+//! `SHA256 (<file>) = <hex>` lines. Checksum files may also be fetched from a
+//! URL, or discovered next to the download as sidecars (`file.iso.sha256`).
+
 use std::fs::File;
-use std::io::{BufRead, BufReader, Read};
+use std::io::Read;
 use std::path::Path;
+use std::sync::Arc;
 
-use anyhow::{anyhow, Context, Result};
-use hex::FromHex;
-use sha2::{Digest, Sha256};
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use md5::Md5;
+use reqwest::{Client, Url};
+use sha1::Sha1;
+use sha2::{Digest, Sha256, Sha512};
 use tokio::task;
 
+use crate::download::{OutputSink, SinkReader};
+use crate::util::fetch_text;
+
+/// Sidecar names tried by [`ChecksumSpec::auto`]; `{}` is the remote file name.
+const SIDECAR_NAMES: [&str; 5] = [
+    "{}.sha256",
+    "{}.sha256sum",
+    "{}.sha512",
+    "SHA256SUMS",
+    "SHA512SUMS",
+];
+
+/// Digest algorithms understood by checksum and piece verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Accepts RFC 5854 names (`sha-256`) as well as the dashless forms.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "md5" => Ok(Self::Md5),
+            "sha-1" | "sha1" => Ok(Self::Sha1),
+            "sha-256" | "sha256" => Ok(Self::Sha256),
+            "sha-512" | "sha512" => Ok(Self::Sha512),
+            "blake3" | "b3" => Ok(Self::Blake3),
+            other => Err(anyhow!("unsupported hash algorithm {other:?}")),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+            Self::Blake3 => "blake3",
+        }
+    }
+
+    pub fn digest_len(self) -> usize {
+        match self {
+            Self::Md5 => 16,
+            Self::Sha1 => 20,
+            Self::Sha256 | Self::Blake3 => 32,
+            Self::Sha512 => 64,
+        }
+    }
+
+    /// Guesses the algorithm of a bare digest from its length. 32-byte
+    /// digests are taken to be SHA-256.
+    fn from_digest_len(len: usize) -> Option<Self> {
+        match len {
+            16 => Some(Self::Md5),
+            20 => Some(Self::Sha1),
+            32 => Some(Self::Sha256),
+            64 => Some(Self::Sha512),
+            _ => None,
+        }
+    }
+
+    /// Recognises names such as `SHA512SUMS`, `file.iso.sha256` or `B3SUMS`.
+    fn from_file_name(name: &str) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        [
+            ("sha512", Self::Sha512),
+            ("sha256", Self::Sha256),
+            ("sha1", Self::Sha1),
+            ("md5", Self::Md5),
+            ("blake3", Self::Blake3),
+            ("b3sum", Self::Blake3),
+        ]
+        .into_iter()
+        .find(|(tag, _)| name.contains(tag))
+        .map(|(_, algorithm)| algorithm)
+    }
+
+    /// Hashes everything `reader` yields.
+    pub fn hash_reader(self, reader: impl Read) -> Result<Vec<u8>> {
+        let mut hasher = self.hasher();
+        read_chunks(reader, |chunk| hasher.update(chunk))?;
+        Ok(hasher.finalize())
+    }
+
+    /// Incremental hasher for data that arrives in pieces.
+    pub fn hasher(self) -> Hasher {
+        match self {
+            Self::Md5 => Hasher::Md5(Md5::new()),
+            Self::Sha1 => Hasher::Sha1(Sha1::new()),
+            Self::Sha256 => Hasher::Sha256(Sha256::new()),
+            Self::Sha512 => Hasher::Sha512(Sha512::new()),
+            Self::Blake3 => Hasher::Blake3(Box::default()),
+        }
+    }
+}
+
+/// Running digest created by [`HashAlgorithm::hasher`].
+#[derive(Clone)]
+pub enum Hasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+            Self::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Sha512(hasher) => hasher.finalize().to_vec(),
+            Self::Blake3(hasher) => hasher.finalize().as_bytes().to_vec(),
+        }
+    }
+}
+
+pub(crate) fn read_chunks(mut reader: impl Read, mut consume: impl FnMut(&[u8])) -> Result<()> {
+    let mut buffer = [0u8; 8192];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(());
+        }
+        consume(&buffer[..read]);
+    }
+}
+
+/// Expected whole-file digest.
+///
+/// A spec read from a checksum file with several entries stays unresolved
+/// until [`ChecksumSpec::resolve`] picks the line for the output file;
+/// [`crate::DownloadConfigBuilder::build`] does that automatically. Remote
+/// checksum files are downloaded by [`ChecksumSpec::fetch`] when the download
+/// starts.
 #[derive(Debug, Clone)]
 pub struct ChecksumSpec {
-    expected: [u8; 32],
+    expected: Expected,
     source: String,
 }
 
+#[derive(Debug, Clone)]
+enum Expected {
+    Digest {
+        algorithm: HashAlgorithm,
+        digest: Vec<u8>,
+    },
+    Manifest(Vec<ManifestEntry>),
+    /// Checksum file still to be downloaded.
+    Remote {
+        url: Url,
+        algorithm: Option<HashAlgorithm>,
+    },
+    /// Sidecar next to the primary URL, see [`SIDECAR_NAMES`].
+    Auto,
+}
+
+#[derive(Debug, Clone)]
+struct ManifestEntry {
+    /// `None` for files that hold nothing but a digest.
+    file_name: Option<String>,
+    algorithm: HashAlgorithm,
+    digest: Vec<u8>,
+}
+
+impl ManifestEntry {
+    fn matches(&self, file_name: &str) -> bool {
+        match &self.file_name {
+            Some(name) => Path::new(name).file_name() == Some(file_name.as_ref()),
+            None => true,
+        }
+    }
+}
+
 impl ChecksumSpec {
+    pub fn from_hex(algorithm: HashAlgorithm, hex: &str) -> Result<Self> {
+        Ok(Self {
+            expected: Expected::Digest {
+                algorithm,
+                digest: decode_digest(algorithm, hex)?,
+            },
+            source: hex.trim().to_string(),
+        })
+    }
+
+    /// Looks for a checksum file next to the primary URL when the download
+    /// starts (`--checksum-url auto`).
+    pub fn auto() -> Self {
+        Self {
+            expected: Expected::Auto,
+            source: "auto".to_string(),
+        }
+    }
+
+    /// `--sha256` form: a SHA-256 hex digest or a checksum file.
     pub fn from_input(input: &str) -> Result<Self> {
+        Self::parse_with(input, Some(HashAlgorithm::Sha256))
+    }
+
+    /// `--checksum` form: `<algo>:<hex>`, aria2's `<algo>=<hex>`, a bare hex
+    /// digest, or `[<algo>:]<path|url>` of a checksum file. Without an explicit
+    /// algorithm it is taken from the file name (`SHA512SUMS`, `*.md5`), the
+    /// BSD tag or the digest length.
+    pub fn parse(input: &str) -> Result<Self> {
+        Self::parse_with(input, None)
+    }
+
+    fn parse_with(input: &str, default: Option<HashAlgorithm>) -> Result<Self> {
         let trimmed = input.trim();
         if trimmed.is_empty() {
             return Err(anyhow!("checksum value cannot be empty"));
         }
+        let (explicit, value) = match split_algorithm(trimmed) {
+            Some((algorithm, value)) => (Some(algorithm), value),
+            None => (None, trimmed),
+        };
 
-        if trimmed.len() == 64 && trimmed.chars().all(|c| c.is_ascii_hexdigit()) {
-            let bytes = <[u8; 32]>::from_hex(trimmed).map_err(|_| anyhow!("invalid hex digest"))?;
+        if is_hex(value) {
+            let algorithm = explicit
+                .or(default)
+                .or_else(|| HashAlgorithm::from_digest_len(value.len() / 2))
+                .ok_or_else(|| anyhow!("cannot tell the hash algorithm of {value:?}"))?;
+            return Self::from_hex(algorithm, value);
+        }
+
+        if value.starts_with("http://") || value.starts_with("https://") {
+            let url = Url::parse(value).with_context(|| format!("invalid URL: {value}"))?;
+            let file_name = url.path_segments().and_then(|mut s| s.next_back());
+            let algorithm = explicit
+                .or_else(|| file_name.and_then(HashAlgorithm::from_file_name))
+                .or(default);
             return Ok(Self {
-                expected: bytes,
-                source: trimmed.to_string(),
+                expected: Expected::Remote { url, algorithm },
+                source: value.to_string(),
             });
         }
 
-        let path = Path::new(trimmed);
+        let path = Path::new(value);
         if !path.exists() {
-            return Err(anyhow!("checksum file does not exist: {}", trimmed));
-        }
-
-        let file = File::open(path)
-            .with_context(|| format!("failed to open checksum file {}", trimmed))?;
-        let mut reader = BufReader::new(file);
-        let mut line = String::new();
-        reader
-            .read_line(&mut line)
-            .map_err(|_| anyhow!("failed to read checksum file"))?;
-        let token = line
-            .split_whitespace()
-            .next()
-            .ok_or_else(|| anyhow!("checksum file is empty"))?;
-        let bytes = <[u8; 32]>::from_hex(token)
-            .map_err(|_| anyhow!("invalid hex digest in checksum file"))?;
+            return Err(anyhow!("checksum file does not exist: {}", value));
+        }
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read checksum file {}", value))?;
+        let file_name = path.file_name().and_then(|name| name.to_str());
+        let algorithm = explicit
+            .or_else(|| file_name.and_then(HashAlgorithm::from_file_name))
+            .or(default);
+        Self::from_manifest(&text, algorithm, value)
+    }
+
+    fn from_manifest(text: &str, algorithm: Option<HashAlgorithm>, source: &str) -> Result<Self> {
+        let entries = parse_manifest(text, algorithm)
+            .with_context(|| format!("invalid checksum file {}", source))?;
         Ok(Self {
-            expected: bytes,
-            source: token.to_string(),
+            expected: Expected::Manifest(entries),
+            source: source.to_string(),
         })
     }
 
-    pub async fn verify_file(&self, path: &Path) -> Result<()> {
+    /// Downloads a remote checksum file through `client`, or finds a sidecar
+    /// next to `primary`, and resolves it like [`ChecksumSpec::resolve`].
+    /// Other specs are returned unchanged.
+    pub async fn fetch(self, client: &Client, primary: &Url, file_names: &[&str]) -> Result<Self> {
+        match &self.expected {
+            Expected::Remote { url, algorithm } => {
+                let text = fetch_text(client, url)
+                    .await?
+                    .ok_or_else(|| anyhow!("checksum file not found: {url}"))?;
+                Self::from_manifest(&text, *algorithm, url.as_str())?.resolve(file_names)
+            }
+            Expected::Auto => {
+                let candidates = sidecar_urls(primary);
+                for url in &candidates {
+                    // A failing server is skipped like a missing file.
+                    let text = match fetch_text(client, url).await {
+                        Ok(Some(text)) => text,
+                        Ok(None) => continue,
+                        Err(err) => {
+                            debug!("skipping checksum candidate {url}: {err:#}");
+                            continue;
+                        }
+                    };
+                    let file_name = url.path_segments().and_then(|mut s| s.next_back());
+                    let algorithm = file_name.and_then(HashAlgorithm::from_file_name);
+                    // A SUMS file without our entry is as good as a missing one.
+                    if let Ok(spec) = Self::from_manifest(&text, algorithm, url.as_str())
+                        .and_then(|spec| spec.resolve(file_names))
+                    {
+                        return Ok(spec);
+                    }
+                }
+                Err(anyhow!(
+                    "no checksum file found next to {primary} (tried {})",
+                    candidates
+                        .iter()
+                        .filter_map(|url| url.path_segments()?.next_back())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ))
+            }
+            _ => Ok(self),
+        }
+    }
+
+    /// Picks the checksum file entry for the first of `file_names` that has
+    /// one. A file with a single entry applies whatever it names.
+    pub fn resolve(self, file_names: &[&str]) -> Result<Self> {
+        let Expected::Manifest(entries) = &self.expected else {
+            // Remote checksum files are resolved once fetched.
+            return Ok(self);
+        };
+        let entry = file_names
+            .iter()
+            .find_map(|name| entries.iter().find(|entry| entry.matches(name)))
+            .or(match entries.as_slice() {
+                [only] => Some(only),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                anyhow!(
+                    "no entry for {} in checksum file {}",
+                    file_names.first().copied().unwrap_or_default(),
+                    self.source
+                )
+            })?;
+        let source = match &entry.file_name {
+            Some(name) => format!("{} ({name})", self.source),
+            None => self.source.clone(),
+        };
+        Ok(Self {
+            expected: Expected::Digest {
+                algorithm: entry.algorithm,
+                digest: entry.digest.clone(),
+            },
+            source,
+        })
+    }
+
+    /// `None` until a checksum file has been fetched and resolved.
+    pub fn algorithm(&self) -> Option<HashAlgorithm> {
+        match &self.expected {
+            Expected::Digest { algorithm, .. } => Some(*algorithm),
+            _ => None,
+        }
+    }
+
+    /// Hashes `path` and compares it to the expected digest, returning the
+    /// hex-encoded digest on success.
+    pub async fn verify_file(&self, path: &Path) -> Result<String> {
+        let algorithm = self.resolved_algorithm()?;
         let path_owned = path.to_owned();
-        let expected = self.expected;
-        let computed = task::spawn_blocking(move || compute_sha256(&path_owned)).await??;
-        if computed == expected {
-            Ok(())
+        let computed = task::spawn_blocking(move || {
+            let file = File::open(&path_owned)
+                .with_context(|| format!("failed to open {:?}", path_owned))?;
+            algorithm.hash_reader(file)
+        })
+        .await??;
+        self.check(computed)
+    }
+
+    /// Like [`ChecksumSpec::verify_file`], reading the data back from `sink`.
+    pub async fn verify_sink(&self, sink: Arc<dyn OutputSink>) -> Result<String> {
+        let algorithm = self.resolved_algorithm()?;
+        let computed =
+            task::spawn_blocking(move || algorithm.hash_reader(SinkReader::new(sink.as_ref())))
+                .await??;
+        self.check(computed)
+    }
+
+    fn resolved_algorithm(&self) -> Result<HashAlgorithm> {
+        self.algorithm()
+            .ok_or_else(|| anyhow!("checksum file {} was not matched to a file", self.source))
+    }
+
+    /// Compares a digest computed elsewhere, e.g. while downloading, and
+    /// returns it hex-encoded on success.
+    pub fn check(&self, computed: Vec<u8>) -> Result<String> {
+        let Expected::Digest { digest, .. } = &self.expected else {
+            bail!("checksum file {} was not matched to a file", self.source);
+        };
+        if &computed == digest {
+            Ok(hex::encode(computed))
         } else {
             Err(anyhow!(
                 "checksum mismatch: expected {}, got {}",
-                hex::encode(expected),
+                hex::encode(digest),
                 hex::encode(computed)
             ))
         }
@@ -70,19 +428,251 @@ impl ChecksumSpec {
     pub fn display(&self) -> String {
         self.source.clone()
     }
+
+    /// The spec in a form [`ChecksumSpec::parse`] reads back; `auto` stands
+    /// for [`ChecksumSpec::auto`].
+    pub fn to_input(&self) -> String {
+        match &self.expected {
+            Expected::Digest { algorithm, digest } => {
+                format!("{}:{}", algorithm.name(), hex::encode(digest))
+            }
+            Expected::Remote {
+                url,
+                algorithm: Some(algorithm),
+            } => format!("{}:{url}", algorithm.name()),
+            Expected::Remote {
+                url,
+                algorithm: None,
+            } => url.to_string(),
+            Expected::Manifest(_) => self.source.clone(),
+            Expected::Auto => "auto".to_string(),
+        }
+    }
 }
 
-fn compute_sha256(path: &Path) -> Result<[u8; 32]> {
-    let mut file = File::open(path).with_context(|| format!("failed to open {:?}", path))?;
-    let mut hasher = Sha256::new();
-    let mut buffer = [0u8; 8192];
-    loop {
-        let read = file.read(&mut buffer)?;
-        if read == 0 {
-            break;
+/// Candidate sidecars in the directory of `primary`, most specific first.
+fn sidecar_urls(primary: &Url) -> Vec<Url> {
+    let name = primary
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .unwrap_or_default();
+    SIDECAR_NAMES
+        .iter()
+        .filter(|pattern| !name.is_empty() || !pattern.contains("{}"))
+        .filter_map(|pattern| primary.join(&pattern.replace("{}", name)).ok())
+        .collect()
+}
+
+/// Splits `sha512:<value>` or `sha-256=<value>`; anything else, such as a
+/// Windows path, is left alone.
+fn split_algorithm(input: &str) -> Option<(HashAlgorithm, &str)> {
+    [':', '='].into_iter().find_map(|separator| {
+        let (name, value) = input.split_once(separator)?;
+        Some((HashAlgorithm::from_name(name).ok()?, value.trim()))
+    })
+}
+
+fn is_hex(value: &str) -> bool {
+    !value.is_empty()
+        && value.len().is_multiple_of(2)
+        && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub(crate) fn decode_digest(algorithm: HashAlgorithm, value: &str) -> Result<Vec<u8>> {
+    let digest = hex::decode(value.trim()).map_err(|_| anyhow!("invalid hex digest {value:?}"))?;
+    if digest.len() != algorithm.digest_len() {
+        bail!("{value:?} is not a {} digest", algorithm.name());
+    }
+    Ok(digest)
+}
+
+/// Lines that are not checksums, such as the armor of a clearsigned file,
+/// are skipped.
+fn parse_manifest(text: &str, algorithm: Option<HashAlgorithm>) -> Result<Vec<ManifestEntry>> {
+    let entries: Vec<ManifestEntry> = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| match parse_manifest_line(line, algorithm) {
+            Ok(entry) => Some(entry),
+            Err(err) => {
+                debug!("skipping checksum line {line:?}: {err}");
+                None
+            }
+        })
+        .collect();
+    if entries.is_empty() {
+        bail!("checksum file lists no checksums");
+    }
+    Ok(entries)
+}
+
+fn parse_manifest_line(line: &str, algorithm: Option<HashAlgorithm>) -> Result<ManifestEntry> {
+    // BSD: `SHA256 (name) = <hex>`, as written by `shasum --tag`.
+    if let Some((tag, rest)) = line.split_once(" (") {
+        if let (Ok(algorithm), Some((name, hex))) =
+            (HashAlgorithm::from_name(tag), rest.rsplit_once(") = "))
+        {
+            return Ok(ManifestEntry {
+                file_name: Some(name.to_string()),
+                algorithm,
+                digest: decode_digest(algorithm, hex)?,
+            });
         }
-        hasher.update(&buffer[..read]);
     }
-    let result: [u8; 32] = hasher.finalize().into();
-    Ok(result)
+
+    // GNU: `<hex>  name`, or `<hex> *name` for binary mode.
+    let (hex, file_name) = match line.split_once(char::is_whitespace) {
+        Some((hex, name)) => (hex, Some(name.trim_start().trim_start_matches('*'))),
+        None => (line, None),
+    };
+    let algorithm = algorithm
+        .or_else(|| HashAlgorithm::from_digest_len(hex.len() / 2))
+        .ok_or_else(|| anyhow!("cannot tell the hash algorithm of {hex:?}"))?;
+    Ok(ManifestEntry {
+        file_name: file_name.map(str::to_string),
+        algorithm,
+        digest: decode_digest(algorithm, hex)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHA256_ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    const MD5_ABC: &str = "900150983cd24fb0d6963f7d28e17f72";
+
+    fn verify(spec: &ChecksumSpec, data: &[u8]) -> Result<String> {
+        spec.check(spec.algorithm().unwrap().hash_reader(data)?)
+    }
+
+    #[test]
+    fn parses_algorithm_prefixes() {
+        let spec = ChecksumSpec::parse(&format!("md5:{MD5_ABC}")).unwrap();
+        assert_eq!(spec.algorithm(), Some(HashAlgorithm::Md5));
+        assert!(verify(&spec, b"abc").is_ok());
+
+        let spec = ChecksumSpec::parse(&format!("sha-256={SHA256_ABC}")).unwrap();
+        assert_eq!(spec.algorithm(), Some(HashAlgorithm::Sha256));
+        assert!(verify(&spec, b"abd").is_err());
+
+        let blake3 = hex::encode(blake3::hash(b"abc").as_bytes());
+        let spec = ChecksumSpec::parse(&format!("blake3:{blake3}")).unwrap();
+        assert!(verify(&spec, b"abc").is_ok());
+
+        assert!(ChecksumSpec::parse(&format!("sha512:{SHA256_ABC}")).is_err());
+    }
+
+    #[test]
+    fn input_form_parses_back() {
+        for input in [
+            format!("md5:{MD5_ABC}"),
+            "sha512:https://example.com/SUMS".to_string(),
+            "https://example.com/checksums.txt".to_string(),
+        ] {
+            let spec = ChecksumSpec::parse(&input).unwrap();
+            assert_eq!(spec.to_input(), input);
+            assert_eq!(
+                ChecksumSpec::parse(&spec.to_input()).unwrap().to_input(),
+                input
+            );
+        }
+        assert_eq!(ChecksumSpec::auto().to_input(), "auto");
+    }
+
+    #[test]
+    fn picks_the_matching_line_of_a_sums_file() {
+        let other = "0".repeat(64);
+        let text = format!("{other}  other.iso\n{SHA256_ABC} *dist/image.iso\n");
+        let entries = parse_manifest(&text, Some(HashAlgorithm::Sha256)).unwrap();
+        let spec = ChecksumSpec {
+            expected: Expected::Manifest(entries),
+            source: "SHA256SUMS".into(),
+        };
+        assert!(spec.clone().resolve(&["missing.iso"]).is_err());
+        let resolved = spec.resolve(&["renamed.iso", "image.iso"]).unwrap();
+        assert_eq!(resolved.display(), "SHA256SUMS (dist/image.iso)");
+        assert!(verify(&resolved, b"abc").is_ok());
+    }
+
+    #[test]
+    fn sidecars_live_next_to_the_download() {
+        let primary = Url::parse("https://host/pub/image%201.iso?token=x").unwrap();
+        let urls: Vec<String> = sidecar_urls(&primary).iter().map(Url::to_string).collect();
+        assert_eq!(urls[0], "https://host/pub/image%201.iso.sha256");
+        assert_eq!(urls[3], "https://host/pub/SHA256SUMS");
+
+        let spec = ChecksumSpec::parse("https://host/pub/image.iso.sha512").unwrap();
+        assert!(matches!(
+            spec.expected,
+            Expected::Remote {
+                algorithm: Some(HashAlgorithm::Sha512),
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn auto_skips_failing_sidecars() {
+        use crate::test_server;
+        use hyper::StatusCode;
+
+        let sums = format!("{SHA256_ABC}  image.iso\n");
+        let base = test_server::spawn(move |req| match req.uri().path() {
+            "/pub/image.iso.sha256" => test_server::status(StatusCode::INTERNAL_SERVER_ERROR),
+            "/pub/SHA256SUMS" => test_server::file(req, sums.as_bytes()),
+            _ => test_server::status(StatusCode::NOT_FOUND),
+        });
+        let primary = Url::parse(&format!("{base}/pub/image.iso")).unwrap();
+        let spec = ChecksumSpec::auto()
+            .fetch(&Client::new(), &primary, &["image.iso"])
+            .await
+            .unwrap();
+        assert!(spec.display().ends_with("SHA256SUMS (image.iso)"));
+        assert!(verify(&spec, b"abc").is_ok());
+    }
+
+    #[test]
+    fn skips_lines_that_are_not_checksums() {
+        let text = format!(
+            "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA256\n\n\
+             {SHA256_ABC}  image.iso\n\
+             -----BEGIN PGP SIGNATURE-----\n\
+             iQIzBAEBCAAdFiEE\n\
+             -----END PGP SIGNATURE-----\n"
+        );
+        let entries = parse_manifest(&text, Some(HashAlgorithm::Sha256)).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].file_name.as_deref(), Some("image.iso"));
+        assert!(parse_manifest("not a checksum\n", None).is_err());
+    }
+
+    #[test]
+    fn local_files_are_named_by_their_file_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let parent = dir.path().join("sha512-mirror");
+        std::fs::create_dir(&parent).unwrap();
+        let path = parent.join("image.iso.md5");
+        std::fs::write(&path, format!("{MD5_ABC}  image.iso\n")).unwrap();
+        let spec = ChecksumSpec::parse(path.to_str().unwrap())
+            .unwrap()
+            .resolve(&["image.iso"])
+            .unwrap();
+        assert_eq!(spec.algorithm(), Some(HashAlgorithm::Md5));
+        assert!(verify(&spec, b"abc").is_ok());
+    }
+
+    #[test]
+    fn reads_bsd_style_lines() {
+        let text = format!("MD5 (a.txt) = {MD5_ABC}\nSHA256 (b (1).txt) = {SHA256_ABC}\n");
+        let entries = parse_manifest(&text, None).unwrap();
+        assert_eq!(entries[0].algorithm, HashAlgorithm::Md5);
+        assert_eq!(entries[1].file_name.as_deref(), Some("b (1).txt"));
+        assert_eq!(entries[1].algorithm, HashAlgorithm::Sha256);
+        assert_eq!(
+            HashAlgorithm::from_file_name("image.iso.SHA512"),
+            Some(HashAlgorithm::Sha512)
+        );
+    }
 }
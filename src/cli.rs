@@ -1,22 +1,93 @@
 use std::convert::TryFrom;
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
-use clap::{ArgAction, Parser};
-use reqwest::Url;
-
-use crate::checksum::ChecksumSpec;
-use crate::download::{DownloadConfig, ProgressMode};
-use crate::util::{infer_output_path, parse_bandwidth_limit};
+use clap::{ArgAction, Args, Parser, Subcommand};
+use kdownload::batch::parse_input_list;
+use kdownload::checksum::HashAlgorithm;
+use kdownload::daemon::protocol::Request;
+use kdownload::daemon::{DaemonSettings, JobSpec};
+use kdownload::download::read_resume_source;
+use kdownload::metalink::Metalink;
+use kdownload::pieces::PieceHashes;
+use kdownload::util::{output_for_partmap, parse_bandwidth_limit, PARTMAP_SUFFIX};
+use kdownload::{
+    ChangePolicy, ChecksumSpec, DownloadConfig, DownloadConfigBuilder, Durability, ProgressMode,
+    SignatureSpec,
+};
+use log::warn;
 
 #[derive(Parser, Debug, Clone)]
-#[command(name = "kdownload", author, version, about = "Blazing-fast command-line downloader", long_about = None)]
+#[command(
+    name = "kdownload",
+    author,
+    version,
+    about = "Blazing-fast command-line downloader",
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
 pub struct Cli {
-    /// Primary download URL(s). Additional URLs act as mirrors.
-    #[arg(value_name = "url", required = true)]
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Primary download URL(s). Additional URLs act as mirrors. A
+    /// .kdl.partmap continues the download it records.
+    #[arg(
+        value_name = "url",
+        required_unless_present_any = ["input_file", "metalink"]
+    )]
     pub urls: Vec<String>,
 
+    /// Download every entry of a list file ("-" reads stdin)
+    #[arg(
+        short = 'i',
+        long = "input-file",
+        value_name = "path",
+        conflicts_with_all = ["urls", "mirrors", "sha256", "checksum", "checksum_url"]
+    )]
+    pub input_file: Option<PathBuf>,
+
+    /// Download the files described by a .meta4/.metalink path or URL
+    #[arg(
+        short = 'M',
+        long = "metalink",
+        value_name = "path|url",
+        conflicts_with_all = [
+            "urls",
+            "mirrors",
+            "sha256",
+            "checksum",
+            "checksum_url",
+            "input_file"
+        ]
+    )]
+    pub metalink: Option<String>,
+
+    /// Prefer metalink mirrors in these countries (e.g. de,fr)
+    #[arg(
+        long = "metalink-location",
+        value_name = "codes",
+        value_delimiter = ','
+    )]
+    pub metalink_locations: Vec<String>,
+
+    /// Files downloaded in parallel with --input-file
+    #[arg(
+        short = 'j',
+        long = "max-concurrent-downloads",
+        value_name = "int",
+        default_value_t = 4
+    )]
+    pub max_concurrent_downloads: usize,
+
+    /// Maximum connections across all mirrors and downloads (batches default to --connections)
+    #[arg(long = "max-connections", value_name = "int")]
+    pub max_connections: Option<usize>,
+
     /// Output file or directory
     #[arg(short, long, value_name = "path")]
     pub output: Option<PathBuf>,
@@ -30,12 +101,12 @@ pub struct Cli {
     )]
     pub connections: usize,
 
-    /// Initial number of segments
+    /// Initial number of segments, capped by the connection limit
     #[arg(
         short = 's',
         long = "segments",
         value_name = "int",
-        default_value_t = 64
+        default_value_t = 4
     )]
     pub segments: usize,
 
@@ -43,14 +114,69 @@ pub struct Cli {
     #[arg(short = 'm', long = "mirror", value_name = "url")]
     pub mirrors: Vec<String>,
 
-    /// Verify SHA256 checksum (hex string or file path)
-    #[arg(long = "sha256", value_name = "hex|path")]
+    /// Verify SHA256 checksum (hex string, file path or URL)
+    #[arg(
+        long = "sha256",
+        value_name = "hex|path|url",
+        conflicts_with = "checksum"
+    )]
     pub sha256: Option<String>,
 
+    /// Verify a checksum: md5|sha1|sha256|sha512|blake3 digest as algo:hex,
+    /// or a checksum file such as SHA256SUMS (path or URL)
+    #[arg(long = "checksum", value_name = "[algo:]hex|path|url")]
+    pub checksum: Option<String>,
+
+    /// Verify against a checksum file at this URL; "auto" looks for
+    /// .sha256/.sha256sum/SHA256SUMS sidecars next to the first URL
+    #[arg(
+        long = "checksum-url",
+        value_name = "url|auto",
+        conflicts_with_all = ["sha256", "checksum"]
+    )]
+    pub checksum_url: Option<String>,
+
+    /// Verify a detached signature (OpenPGP or minisign, path or URL)
+    #[arg(
+        long = "signature",
+        value_name = "path|url",
+        conflicts_with_all = ["input_file", "metalink"]
+    )]
+    pub signature: Option<String>,
+
+    /// OpenPGP keyring for --signature (binary or armored, checked with gpgv)
+    #[arg(long = "keyring", value_name = "path", requires = "signature")]
+    pub keyring: Option<PathBuf>,
+
+    /// Minisign public key for --signature (base64 key or .pub file)
+    #[arg(
+        long = "minisign-key",
+        value_name = "key|path",
+        requires = "signature",
+        conflicts_with = "keyring"
+    )]
+    pub minisign_key: Option<String>,
+
+    /// Verify pieces while downloading (bmap or piece list, path or URL)
+    #[arg(
+        long = "piece-hashes",
+        value_name = "path|url",
+        conflicts_with_all = ["input_file", "metalink"]
+    )]
+    pub piece_hashes: Option<String>,
+
     /// Resume from existing partial download
     #[arg(long = "resume", action = ArgAction::SetTrue)]
     pub resume: bool,
 
+    /// When a resumed file changed on the server: abort or restart
+    #[arg(long = "on-change", value_name = "policy", default_value = "abort")]
+    pub on_change: String,
+
+    /// When resume data is synced to disk: fast, balanced or strict
+    #[arg(long = "durability", value_name = "mode", default_value = "balanced")]
+    pub durability: String,
+
     /// Per-request timeout in seconds
     #[arg(long = "timeout", value_name = "secs")]
     pub timeout: Option<u64>,
@@ -76,81 +202,490 @@ pub struct Cli {
     pub json: bool,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Run a download queue controlled through `kdownload ctl`
+    Daemon(DaemonArgs),
+    /// Send a command to a running daemon
+    Ctl(CtlArgs),
+    /// Continue every interrupted download in a directory
+    Resume(ResumeArgs),
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct ResumeArgs {
+    /// Directory holding the partial downloads and their .kdl.partmap files
+    #[arg(value_name = "dir", default_value = ".")]
+    pub dir: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct DaemonArgs {
+    /// Control socket (default: <state-dir>/daemon.sock)
+    #[arg(long = "socket", value_name = "path")]
+    pub socket: Option<PathBuf>,
+
+    /// Directory for the persisted queue
+    #[arg(long = "state-dir", value_name = "path")]
+    pub state_dir: Option<PathBuf>,
+
+    /// Directory for downloads added without an output path
+    #[arg(short, long, value_name = "path", default_value = ".")]
+    pub output: PathBuf,
+
+    /// Jobs downloading at the same time
+    #[arg(
+        short = 'j',
+        long = "max-concurrent-downloads",
+        value_name = "int",
+        default_value_t = 4
+    )]
+    pub max_concurrent_downloads: usize,
+
+    /// Maximum connections per host
+    #[arg(
+        short = 'c',
+        long = "connections",
+        value_name = "int",
+        default_value_t = 32
+    )]
+    pub connections: usize,
+
+    /// Maximum connections across all jobs (default: --connections)
+    #[arg(long = "max-connections", value_name = "int")]
+    pub max_connections: Option<usize>,
+
+    /// Initial number of segments per job
+    #[arg(
+        short = 's',
+        long = "segments",
+        value_name = "int",
+        default_value_t = 4
+    )]
+    pub segments: usize,
+
+    /// Per-request timeout in seconds
+    #[arg(long = "timeout", value_name = "secs")]
+    pub timeout: Option<u64>,
+
+    /// Limit bandwidth across all jobs (e.g. 50M/s)
+    #[arg(long = "bandwidth-limit", value_name = "rate")]
+    pub bandwidth_limit: Option<String>,
+
+    /// Also serve aria2-compatible JSON-RPC (e.g. 127.0.0.1:6800)
+    #[arg(long = "rpc-listen", value_name = "addr")]
+    pub rpc_listen: Option<SocketAddr>,
+
+    /// Require "token:<secret>" on every JSON-RPC call
+    #[arg(long = "rpc-secret", value_name = "secret", requires = "rpc_listen")]
+    pub rpc_secret: Option<String>,
+
+    /// Browser origin allowed to call the JSON-RPC server (repeatable)
+    #[arg(
+        long = "rpc-allow-origin",
+        value_name = "origin",
+        requires = "rpc_listen"
+    )]
+    pub rpc_allow_origin: Vec<String>,
+}
+
+impl DaemonArgs {
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| self.state_dir().join(SOCKET_NAME))
+    }
+
+    pub fn state_dir(&self) -> PathBuf {
+        self.state_dir.clone().unwrap_or_else(default_state_dir)
+    }
+
+    pub fn settings(&self) -> Result<DaemonSettings> {
+        Ok(DaemonSettings {
+            state_dir: self.state_dir(),
+            output_dir: self.output.clone(),
+            max_active: self.max_concurrent_downloads.max(1),
+            max_connections: self.max_connections.unwrap_or(self.connections).max(1),
+            connections_per_host: self.connections,
+            segments: self.segments,
+            timeout: self.timeout.map(Duration::from_secs),
+            bandwidth_limit: self
+                .bandwidth_limit
+                .as_deref()
+                .map(parse_bandwidth_limit)
+                .transpose()?,
+        })
+    }
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct CtlArgs {
+    /// Control socket of the daemon
+    #[arg(long = "socket", value_name = "path")]
+    pub socket: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: CtlCommand,
+}
+
+impl CtlArgs {
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket
+            .clone()
+            .unwrap_or_else(|| default_state_dir().join(SOCKET_NAME))
+    }
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum CtlCommand {
+    /// Queue a download; additional URLs act as mirrors
+    Add {
+        #[arg(value_name = "url", required = true)]
+        urls: Vec<String>,
+        /// Output file or directory, relative to the daemon's output directory
+        #[arg(short, long, value_name = "path")]
+        output: Option<PathBuf>,
+        /// Verify a checksum ([algo:]hex or checksum file)
+        #[arg(
+            long = "checksum",
+            visible_alias = "sha256",
+            value_name = "[algo:]hex|path"
+        )]
+        checksum: Option<String>,
+        /// Higher priorities start first
+        #[arg(
+            short,
+            long,
+            value_name = "int",
+            default_value_t = 0,
+            allow_negative_numbers = true
+        )]
+        priority: i32,
+    },
+    /// Stop a job and keep its partial data
+    Pause { id: u64 },
+    /// Queue a paused or failed job again
+    Resume { id: u64 },
+    /// Stop a job and delete its partial data
+    Cancel { id: u64 },
+    /// Change the priority of a job
+    Priority {
+        id: u64,
+        #[arg(allow_negative_numbers = true)]
+        priority: i32,
+    },
+    /// Show all jobs
+    List {
+        /// Print the job list as JSON
+        #[arg(long = "json", action = ArgAction::SetTrue)]
+        json: bool,
+    },
+}
+
+impl CtlCommand {
+    pub fn request(&self) -> Request {
+        match self.clone() {
+            CtlCommand::Add {
+                urls,
+                output,
+                checksum,
+                priority,
+            } => Request::Add(JobSpec {
+                urls,
+                output,
+                checksum,
+                priority,
+            }),
+            CtlCommand::Pause { id } => Request::Pause { id },
+            CtlCommand::Resume { id } => Request::Resume { id },
+            CtlCommand::Cancel { id } => Request::Cancel { id },
+            CtlCommand::Priority { id, priority } => Request::Priority { id, priority },
+            CtlCommand::List { .. } => Request::List,
+        }
+    }
+}
+
+const SOCKET_NAME: &str = "daemon.sock";
+
+/// Metalink digests in the order they are preferred for verification.
+const METALINK_HASH_PREFERENCE: [&str; 4] = ["sha-512", "sha-256", "sha-1", "md5"];
+
+/// `$XDG_STATE_HOME/kdownload`, falling back to `~/.local/state/kdownload`.
+fn default_state_dir() -> PathBuf {
+    std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))
+        .unwrap_or_else(std::env::temp_dir)
+        .join("kdownload")
+}
+
 impl Cli {
     pub fn parse() -> Self {
         <Self as Parser>::parse()
     }
-}
 
-impl TryFrom<Cli> for DownloadConfig {
-    type Error = anyhow::Error;
+    pub fn progress_mode(&self) -> ProgressMode {
+        if self.json {
+            ProgressMode::Json
+        } else if self.quiet {
+            ProgressMode::Quiet
+        } else {
+            ProgressMode::Text
+        }
+    }
 
-    fn try_from(cli: Cli) -> Result<Self> {
-        if cli.urls.is_empty() {
-            return Err(anyhow!("at least one URL is required"));
+    /// Connection budget shared by all downloads of a batch.
+    pub fn connection_budget(&self) -> usize {
+        self.max_connections.unwrap_or(self.connections).max(1)
+    }
+
+    /// Applies the options shared by single and batch downloads.
+    fn builder<I>(&self, primary: &str, mirrors: I) -> Result<DownloadConfigBuilder>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut builder = DownloadConfig::builder(primary)
+            .mirrors(mirrors)
+            .resume(self.resume)
+            .segments(self.segments)
+            .connections(self.connections)
+            .durability(Durability::from_name(&self.durability)?)
+            .on_change(ChangePolicy::from_name(&self.on_change)?)
+            .progress(self.progress_mode());
+        if let Some(max) = self.max_connections {
+            builder = builder.max_connections(max);
+        }
+        if let Some(cap) = self.unsafe_conn {
+            builder = builder.unsafe_connection_cap(cap);
         }
+        if let Some(secs) = self.timeout {
+            builder = builder.timeout(Duration::from_secs(secs));
+        }
+        if let Some(limit) = &self.bandwidth_limit {
+            builder = builder.bandwidth_limit(parse_bandwidth_limit(limit)?);
+        }
+        Ok(builder)
+    }
 
-        let mut all_urls = vec![];
-        for url in cli.urls.iter().chain(cli.mirrors.iter()) {
-            let parsed = Url::parse(url).with_context(|| format!("invalid URL: {url}"))?;
-            if parsed.scheme() != "http" && parsed.scheme() != "https" {
-                return Err(anyhow!("unsupported URL scheme: {}", parsed.scheme()));
+    /// Adds the checksum and signature options.
+    fn verification(&self, mut builder: DownloadConfigBuilder) -> Result<DownloadConfigBuilder> {
+        if let Some(value) = &self.sha256 {
+            builder = builder.checksum(ChecksumSpec::from_input(value)?);
+        }
+        if let Some(value) = &self.checksum {
+            builder = builder.checksum(ChecksumSpec::parse(value)?);
+        }
+        match self.checksum_url.as_deref() {
+            Some("auto") => builder = builder.checksum(ChecksumSpec::auto()),
+            Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+                builder = builder.checksum(ChecksumSpec::parse(url)?)
             }
-            all_urls.push(parsed);
+            Some(other) => {
+                return Err(anyhow!(
+                    "--checksum-url expects a URL or 'auto', got {other:?}"
+                ))
+            }
+            None => {}
+        }
+        if let Some(signature) = &self.signature {
+            let spec = match (&self.keyring, &self.minisign_key) {
+                (Some(keyring), _) => SignatureSpec::openpgp(signature, keyring)?,
+                (None, Some(key)) => SignatureSpec::minisign(signature, key)?,
+                (None, None) => {
+                    return Err(anyhow!("--signature needs --keyring or --minisign-key"))
+                }
+            };
+            builder = builder.signature(spec);
         }
+        Ok(builder)
+    }
 
-        let allow_unsafe = cli.unsafe_conn.unwrap_or(64);
-        let max_per_host = if cli.unsafe_conn.is_some() {
-            cli.connections.max(1)
-        } else {
-            cli.connections.min(64).max(1)
-        };
-        if cli.unsafe_conn.is_some() && cli.connections > allow_unsafe {
+    /// The part map given in place of URLs, if any.
+    fn partmap_argument(&self) -> Option<&Path> {
+        match self.urls.as_slice() {
+            [only] if only.ends_with(PARTMAP_SUFFIX) && !only.contains("://") => {
+                Some(Path::new(only))
+            }
+            _ => None,
+        }
+    }
+
+    /// Continues the download recorded in `partmap`: its URLs, checksum and
+    /// output are reused, and mirrors, checksum and connection options given
+    /// on the command line apply on top.
+    pub fn resume_config(&self, partmap: &Path) -> Result<DownloadConfig> {
+        if self.output.is_some() {
             return Err(anyhow!(
-                "--connections exceeds unsafe limit; either lower it or raise --unsafe-conn"
+                "--output cannot be used when resuming from a part map; the download continues next to it"
             ));
         }
+        let output = output_for_partmap(partmap)
+            .ok_or_else(|| anyhow!("{:?} is not a part map", partmap))?;
+        let source = read_resume_source(partmap)?;
+        let (primary, rest) = source.urls.split_first().ok_or_else(|| {
+            anyhow!(
+                "part map {:?} does not record its URLs; resume by passing them with --resume",
+                partmap
+            )
+        })?;
 
-        let output = infer_output_path(cli.output.clone(), &all_urls)?;
-        let partmap_path = crate::util::derive_partmap_path(&output);
+        let mut builder = self
+            .builder(primary, rest.iter().chain(self.mirrors.iter()).cloned())?
+            .output(output)
+            .resume(true);
+        let overridden =
+            self.sha256.is_some() || self.checksum.is_some() || self.checksum_url.is_some();
+        match source.checksum.as_deref() {
+            Some(_) if overridden => {}
+            Some("auto") => builder = builder.checksum(ChecksumSpec::auto()),
+            Some(value) => builder = builder.checksum(ChecksumSpec::parse(value)?),
+            None => {}
+        }
+        self.verification(builder)?.build()
+    }
 
-        let timeout = cli.timeout.map(Duration::from_secs);
-        let bandwidth_limit = if let Some(limit) = cli.bandwidth_limit.clone() {
-            Some(parse_bandwidth_limit(&limit)?)
+    /// One configuration per part map in `dir`.
+    pub fn resume_configs(&self, dir: &Path) -> Result<Vec<DownloadConfig>> {
+        let mut partmaps = Vec::new();
+        for entry in fs::read_dir(dir).with_context(|| format!("failed to read {:?}", dir))? {
+            let path = entry?.path();
+            if path.is_file() && output_for_partmap(&path).is_some() {
+                partmaps.push(path);
+            }
+        }
+        partmaps.sort();
+        let progress = if partmaps.len() > 1 {
+            ProgressMode::Quiet
         } else {
-            None
+            self.progress_mode()
         };
+        partmaps
+            .iter()
+            .map(|partmap| {
+                let mut config = self.resume_config(partmap)?;
+                config.progress = progress;
+                Ok(config)
+            })
+            .collect()
+    }
 
-        let sha256 = if let Some(value) = cli.sha256.clone() {
-            Some(ChecksumSpec::from_input(&value)?)
+    /// Reads the `--input-file` list and builds one configuration per entry.
+    /// `--output` names the directory the files are placed in.
+    pub fn batch_configs(&self) -> Result<Vec<DownloadConfig>> {
+        let source = self
+            .input_file
+            .as_ref()
+            .ok_or_else(|| anyhow!("--input-file is required for batch downloads"))?;
+        let text = if source.as_os_str() == "-" {
+            io::read_to_string(io::stdin()).context("failed to read input list from stdin")?
         } else {
-            None
+            fs::read_to_string(source)
+                .with_context(|| format!("failed to read input list {:?}", source))?
         };
+        let base_dir = self.output.clone().unwrap_or_else(|| PathBuf::from("."));
 
-        let progress = if cli.json {
-            ProgressMode::Json
-        } else if cli.quiet {
+        parse_input_list(&text)?
+            .into_iter()
+            .map(|entry| {
+                let (primary, rest) = entry
+                    .urls
+                    .split_first()
+                    .ok_or_else(|| anyhow!("input list entry without URL"))?;
+                let dir = match entry.dir {
+                    Some(dir) if dir.is_relative() => base_dir.join(dir),
+                    Some(dir) => dir,
+                    None => base_dir.clone(),
+                };
+                fs::create_dir_all(&dir)
+                    .with_context(|| format!("failed to create directory {:?}", dir))?;
+                let output = match entry.out {
+                    Some(name) => dir.join(name),
+                    None => dir,
+                };
+
+                let mut builder = self
+                    .builder(primary, rest.iter().cloned())?
+                    .output(output)
+                    .progress(ProgressMode::Quiet);
+                if let Some(value) = entry.checksum {
+                    builder = builder.checksum(ChecksumSpec::parse(&value)?);
+                }
+                builder.build()
+            })
+            .collect()
+    }
+
+    /// One configuration per file of `metalink`, placed below `--output`.
+    /// The listed size is authoritative and the strongest listed digest is
+    /// verified.
+    pub fn metalink_configs(&self, metalink: &Metalink) -> Result<Vec<DownloadConfig>> {
+        let base_dir = self.output.clone().unwrap_or_else(|| PathBuf::from("."));
+        let progress = if metalink.files.len() > 1 {
             ProgressMode::Quiet
         } else {
-            ProgressMode::Text
+            self.progress_mode()
         };
 
-        Ok(DownloadConfig {
-            urls: all_urls,
-            output_path: output,
-            partmap_path,
-            resume: cli.resume,
-            initial_segments: cli.segments.max(1),
-            max_connections_per_host: max_per_host,
-            unsafe_connection_cap: allow_unsafe,
-            timeout,
-            bandwidth_limit,
-            expected_sha256: sha256,
-            progress,
-        })
+        metalink
+            .files
+            .iter()
+            .map(|file| {
+                let urls = file.ordered_urls(&self.metalink_locations);
+                let (primary, rest) = urls
+                    .split_first()
+                    .ok_or_else(|| anyhow!("metalink lists no HTTP mirrors for {:?}", file.name))?;
+                let mut builder = self
+                    .builder(primary, rest.iter().cloned())?
+                    .output(base_dir.join(&file.name))
+                    .progress(progress);
+                if let Some(size) = file.size {
+                    builder = builder.size(size);
+                }
+                if let Some(pieces) = &file.pieces {
+                    match PieceHashes::from_metalink(pieces) {
+                        Ok(hashes) => builder = builder.piece_hashes(hashes),
+                        Err(err) => warn!("{:?}: ignoring piece hashes: {err}", file.name),
+                    }
+                }
+                let strongest = METALINK_HASH_PREFERENCE
+                    .iter()
+                    .find_map(|name| Some((*name, file.hash(name)?)));
+                match strongest {
+                    Some((name, digest)) => {
+                        let algorithm = HashAlgorithm::from_name(name)?;
+                        builder = builder.checksum(ChecksumSpec::from_hex(algorithm, digest)?);
+                    }
+                    None if !file.hashes.is_empty() => warn!(
+                        "{:?}: no supported digest in metalink; skipping verification",
+                        file.name
+                    ),
+                    None => {}
+                }
+                builder.build()
+            })
+            .collect()
     }
 }
 
+impl TryFrom<Cli> for DownloadConfig {
+    type Error = anyhow::Error;
+
+    fn try_from(cli: Cli) -> Result<Self> {
+        if let Some(partmap) = cli.partmap_argument() {
+            return cli.resume_config(partmap);
+        }
+        let (primary, rest) = cli
+            .urls
+            .split_first()
+            .ok_or_else(|| anyhow!("at least one URL is required"))?;
+
+        let mut builder = cli.builder(primary, rest.iter().chain(cli.mirrors.iter()).cloned())?;
+        if let Some(path) = &cli.output {
+            builder = builder.output(path);
+        }
+        cli.verification(builder)?.build()
+    }
+}
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -171,6 +706,26 @@ mod tests {
         assert_eq!(config.progress, ProgressMode::Quiet);
     }
 
+    #[test]
+    fn partmap_argument_resumes_its_download() {
+        let cli = Cli::try_parse_from(["kdownload", "https://example.com/file.kdl.partmap"])
+            .expect("cli parse");
+        assert!(cli.partmap_argument().is_none());
+
+        let cli = Cli::try_parse_from(["kdownload", "file.iso.kdl.partmap"]).expect("cli parse");
+        assert_eq!(
+            cli.partmap_argument(),
+            Some(Path::new("file.iso.kdl.partmap"))
+        );
+        let err = DownloadConfig::try_from(cli).unwrap_err();
+        assert!(err.to_string().contains("failed to open part map"), "{err}");
+
+        let cli = Cli::try_parse_from(["kdownload", "file.iso.kdl.partmap", "-o", "other.iso"])
+            .expect("cli parse");
+        let err = DownloadConfig::try_from(cli).unwrap_err();
+        assert!(err.to_string().contains("--output"), "{err}");
+    }
+
     #[test]
     fn progress_mode_prefers_json_flag() {
         let cli =
@@ -179,4 +734,29 @@ mod tests {
         let config = DownloadConfig::try_from(cli).expect("config");
         assert_eq!(config.progress, ProgressMode::Json);
     }
+
+    #[test]
+    fn input_file_replaces_positional_urls() {
+        let cli =
+            Cli::try_parse_from(["kdownload", "-i", "list.txt", "-j", "8"]).expect("cli parse");
+        assert!(cli.urls.is_empty());
+        assert_eq!(cli.max_concurrent_downloads, 8);
+        assert_eq!(cli.connection_budget(), cli.connections);
+        assert!(
+            Cli::try_parse_from(["kdownload", "-i", "list.txt", "https://example.com/f"]).is_err()
+        );
+    }
+
+    #[test]
+    fn ctl_subcommand_does_not_need_urls() {
+        let cli =
+            Cli::try_parse_from(["kdownload", "ctl", "priority", "3", "-2"]).expect("cli parse");
+        match cli.command {
+            Some(Command::Ctl(args)) => match args.command.request() {
+                Request::Priority { id, priority } => assert_eq!((id, priority), (3, -2)),
+                other => panic!("unexpected request {other:?}"),
+            },
+            other => panic!("unexpected command {other:?}"),
+        }
+    }
 }
@@ -0,0 +1,754 @@
+//! Persistent download queue driven over a control socket.
+//!
+//! Every job is an ordinary [`DownloadManager`] run with `resume` enabled, so
+//! pausing a job (or restarting the daemon) keeps its `.kdl.partmap` and the
+//! next run continues where the previous one stopped.
+
+pub mod protocol;
+pub mod rpc;
+#[cfg(unix)]
+pub mod server;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context, Result};
+use log::{error, info, warn};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tokio::task::AbortHandle;
+
+use crate::checksum::ChecksumSpec;
+use crate::download::{
+    build_client, BandwidthLimiter, DownloadConfig, DownloadManager, HostLimits,
+};
+use crate::progress::{DownloadEvent, ProgressObserver, ProgressSnapshot};
+
+const STATE_FILE: &str = "jobs.json";
+const SPEED_WINDOW: Duration = Duration::from_secs(1);
+
+/// Settings applied to every job run by a [`Daemon`].
+#[derive(Debug, Clone)]
+pub struct DaemonSettings {
+    /// Directory holding the persisted queue.
+    pub state_dir: PathBuf,
+    /// Directory for jobs submitted without an output path.
+    pub output_dir: PathBuf,
+    /// Jobs transferring at the same time.
+    pub max_active: usize,
+    /// Connections across all active jobs.
+    pub max_connections: usize,
+    pub connections_per_host: usize,
+    pub segments: usize,
+    pub timeout: Option<Duration>,
+    pub bandwidth_limit: Option<u64>,
+}
+
+/// What a client asks the daemon to download.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct JobSpec {
+    pub urls: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub output: Option<PathBuf>,
+    /// Checksum in `--checksum` form.
+    #[serde(default, alias = "sha256", skip_serializing_if = "Option::is_none")]
+    pub checksum: Option<String>,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Active,
+    Paused,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+impl JobState {
+    pub fn is_finished(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// Public view of a job, as returned by `list`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: u64,
+    pub urls: Vec<String>,
+    pub output: PathBuf,
+    pub state: JobState,
+    pub priority: i32,
+    pub downloaded: u64,
+    pub total: Option<u64>,
+    /// Bytes per second over the last second.
+    pub speed: u64,
+    /// Connections currently transferring.
+    pub connections: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Persisted part of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct JobRecord {
+    id: u64,
+    spec: JobSpec,
+    output: PathBuf,
+    state: JobState,
+    #[serde(default)]
+    downloaded: u64,
+    #[serde(default)]
+    total: Option<u64>,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedQueue {
+    next_id: u64,
+    jobs: Vec<JobRecord>,
+}
+
+struct Job {
+    record: JobRecord,
+    progress: Arc<JobProgress>,
+    /// Bumped on every start so a stale task cannot overwrite a newer run.
+    run: u64,
+    handle: Option<AbortHandle>,
+    /// Aborted but not ended yet; its files are still in use.
+    stopping: bool,
+}
+
+impl Job {
+    fn info(&self) -> JobInfo {
+        let live = self.progress.sample();
+        let (downloaded, total) = match &live {
+            Some(snapshot) => (snapshot.downloaded, snapshot.total.or(self.record.total)),
+            None => (self.record.downloaded, self.record.total),
+        };
+        JobInfo {
+            id: self.record.id,
+            urls: self.record.spec.urls.clone(),
+            output: self.record.output.clone(),
+            state: self.record.state,
+            priority: self.record.spec.priority,
+            downloaded,
+            total,
+            speed: if self.record.state == JobState::Active {
+                self.progress.speed()
+            } else {
+                0
+            },
+            connections: if self.record.state == JobState::Active {
+                live.and_then(|s| s.segments_active).unwrap_or(0)
+            } else {
+                0
+            },
+            error: self.record.error.clone(),
+        }
+    }
+}
+
+struct QueueState {
+    jobs: BTreeMap<u64, Job>,
+    next_id: u64,
+}
+
+impl QueueState {
+    /// The unfinished job other than `except` that writes to `output`.
+    /// Completed and cancelled jobs no longer own their output once their
+    /// task has ended.
+    fn output_owner(&self, output: &std::path::Path, except: Option<u64>) -> Option<u64> {
+        self.jobs
+            .values()
+            .filter(|job| Some(job.record.id) != except)
+            .filter(|job| {
+                job.stopping
+                    || !matches!(job.record.state, JobState::Completed | JobState::Cancelled)
+            })
+            .find(|job| job.record.output == output)
+            .map(|job| job.record.id)
+    }
+}
+
+/// Download queue shared by the control socket and any other front-end.
+pub struct Daemon {
+    settings: DaemonSettings,
+    client: Client,
+    budget: Arc<Semaphore>,
+    /// Per-host connection limits shared by all jobs.
+    hosts: HostLimits,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    state: Mutex<QueueState>,
+}
+
+impl Daemon {
+    /// Loads the persisted queue from `settings.state_dir`. Jobs that were
+    /// active when the previous daemon stopped are queued again.
+    pub fn new(settings: DaemonSettings) -> Result<Arc<Self>> {
+        fs::create_dir_all(&settings.state_dir)
+            .with_context(|| format!("failed to create {:?}", settings.state_dir))?;
+        fs::create_dir_all(&settings.output_dir)
+            .with_context(|| format!("failed to create {:?}", settings.output_dir))?;
+
+        let mut state = QueueState {
+            jobs: BTreeMap::new(),
+            next_id: 1,
+        };
+        let path = settings.state_dir.join(STATE_FILE);
+        if path.exists() {
+            let data = fs::read(&path).with_context(|| format!("failed to read {:?}", path))?;
+            let persisted: PersistedQueue = serde_json::from_slice(&data)
+                .with_context(|| format!("corrupt queue state in {:?}", path))?;
+            state.next_id = persisted.next_id;
+            for mut record in persisted.jobs {
+                if record.state == JobState::Active {
+                    record.state = JobState::Queued;
+                }
+                state.jobs.insert(
+                    record.id,
+                    Job {
+                        record,
+                        progress: Arc::new(JobProgress::default()),
+                        run: 0,
+                        handle: None,
+                        stopping: false,
+                    },
+                );
+            }
+            info!("restored {} job(s) from {:?}", state.jobs.len(), path);
+        }
+
+        let client = build_client(settings.connections_per_host, settings.timeout)?;
+        let budget = Arc::new(Semaphore::new(settings.max_connections.max(1)));
+        let hosts = HostLimits::new(settings.connections_per_host);
+        let bandwidth = settings
+            .bandwidth_limit
+            .map(|limit| Arc::new(BandwidthLimiter::new(limit)));
+        let daemon = Arc::new(Self {
+            settings,
+            client,
+            budget,
+            hosts,
+            bandwidth,
+            state: Mutex::new(state),
+        });
+        daemon.schedule();
+        Ok(daemon)
+    }
+
+    /// Queues a new job and returns its id.
+    pub fn add(self: &Arc<Self>, spec: JobSpec) -> Result<u64> {
+        let config = self.job_config(&spec)?;
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(other) = state.output_owner(&config.output_path, None) {
+                return Err(anyhow!(
+                    "job {other} already downloads to {:?}",
+                    config.output_path
+                ));
+            }
+            let id = state.next_id;
+            state.next_id += 1;
+            state.jobs.insert(
+                id,
+                Job {
+                    record: JobRecord {
+                        id,
+                        output: config.output_path,
+                        spec,
+                        state: JobState::Queued,
+                        downloaded: 0,
+                        total: None,
+                        error: None,
+                    },
+                    progress: Arc::new(JobProgress::default()),
+                    run: 0,
+                    handle: None,
+                    stopping: false,
+                },
+            );
+            self.persist(&state);
+            id
+        };
+        self.schedule();
+        Ok(id)
+    }
+
+    /// Stops a queued or active job, keeping its partial data.
+    pub fn pause(self: &Arc<Self>, id: u64) -> Result<()> {
+        self.update(id, |job| match job.record.state {
+            JobState::Queued | JobState::Active => {
+                job.stop();
+                job.record.state = JobState::Paused;
+                Ok(())
+            }
+            JobState::Paused => Ok(()),
+            other => Err(anyhow!("job {id} is {other:?} and cannot be paused")),
+        })
+    }
+
+    /// Queues a paused or failed job again. It starts once the task of an
+    /// earlier run has ended.
+    pub fn resume(self: &Arc<Self>, id: u64) -> Result<()> {
+        self.update_state(id, |state| {
+            let job = &state.jobs[&id];
+            if let Some(other) = state.output_owner(&job.record.output, Some(id)) {
+                return Err(anyhow!(
+                    "job {other} already downloads to {:?}",
+                    job.record.output
+                ));
+            }
+            let job = state.jobs.get_mut(&id).expect("job exists");
+            match job.record.state {
+                JobState::Paused | JobState::Failed => {
+                    job.record.state = JobState::Queued;
+                    job.record.error = None;
+                    Ok(())
+                }
+                JobState::Queued | JobState::Active => Ok(()),
+                other => Err(anyhow!("job {id} is {other:?} and cannot be resumed")),
+            }
+        })
+    }
+
+    /// Stops a job for good and removes its partial output once its task
+    /// has ended.
+    pub fn cancel(self: &Arc<Self>, id: u64) -> Result<()> {
+        self.update(id, |job| {
+            if matches!(job.record.state, JobState::Completed | JobState::Cancelled) {
+                return Err(anyhow!("job {id} already finished"));
+            }
+            job.stop();
+            job.record.state = JobState::Cancelled;
+            if !job.stopping {
+                remove_partial(&job.record.output);
+            }
+            Ok(())
+        })
+    }
+
+    /// Changes a job's priority; higher priorities start first.
+    pub fn set_priority(self: &Arc<Self>, id: u64, priority: i32) -> Result<()> {
+        self.update(id, |job| {
+            job.record.spec.priority = priority;
+            Ok(())
+        })
+    }
+
+    pub fn list(&self) -> Vec<JobInfo> {
+        let state = self.state.lock().unwrap();
+        state.jobs.values().map(Job::info).collect()
+    }
+
+    pub fn job(&self, id: u64) -> Option<JobInfo> {
+        let state = self.state.lock().unwrap();
+        state.jobs.get(&id).map(Job::info)
+    }
+
+    fn update<F>(self: &Arc<Self>, id: u64, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Job) -> Result<()>,
+    {
+        self.update_state(id, |state| f(state.jobs.get_mut(&id).expect("job exists")))
+    }
+
+    fn update_state<F>(self: &Arc<Self>, id: u64, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut QueueState) -> Result<()>,
+    {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.jobs.contains_key(&id) {
+                return Err(anyhow!("no job with id {id}"));
+            }
+            f(&mut state)?;
+            self.persist(&state);
+        }
+        self.schedule();
+        Ok(())
+    }
+
+    fn job_config(&self, spec: &JobSpec) -> Result<DownloadConfig> {
+        let (primary, mirrors) = spec
+            .urls
+            .split_first()
+            .ok_or_else(|| anyhow!("at least one URL is required"))?;
+        let output = match &spec.output {
+            Some(path) if path.is_relative() => self.settings.output_dir.join(path),
+            Some(path) => path.clone(),
+            None => self.settings.output_dir.clone(),
+        };
+        let mut builder = DownloadConfig::builder(primary.as_str())
+            .mirrors(mirrors.iter().cloned())
+            .output(output)
+            .resume(true)
+            .segments(self.settings.segments)
+            .connections(self.settings.connections_per_host);
+        if let Some(value) = &spec.checksum {
+            builder = builder.checksum(ChecksumSpec::parse(value)?);
+        }
+        builder.build()
+    }
+
+    /// Starts queued jobs, highest priority first, until `max_active` run.
+    /// Jobs still stopping hold their slot.
+    fn schedule(self: &Arc<Self>) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            let active = state
+                .jobs
+                .values()
+                .filter(|job| job.record.state == JobState::Active || job.stopping)
+                .count();
+            if active >= self.settings.max_active.max(1) {
+                break;
+            }
+            let next = state
+                .jobs
+                .values()
+                .filter(|job| job.record.state == JobState::Queued && !job.stopping)
+                .max_by_key(|job| (job.record.spec.priority, std::cmp::Reverse(job.record.id)))
+                .map(|job| job.record.id);
+            let Some(id) = next else { break };
+
+            let job = state.jobs.get_mut(&id).expect("job exists");
+            let mut config = match self.job_config(&job.record.spec) {
+                Ok(config) => config,
+                Err(err) => {
+                    job.record.state = JobState::Failed;
+                    job.record.error = Some(err.to_string());
+                    continue;
+                }
+            };
+            config.output_path = job.record.output.clone();
+            config.partmap_path = crate::util::derive_partmap_path(&config.output_path);
+
+            job.run += 1;
+            job.record.state = JobState::Active;
+            job.progress = Arc::new(JobProgress::default());
+            let run = job.run;
+            let progress = job.progress.clone();
+
+            let mut manager = DownloadManager::with_client(config, self.client.clone())
+                .with_connection_budget(self.budget.clone())
+                .with_host_limits(&self.hosts)
+                .with_observer(progress);
+            if let Some(limiter) = &self.bandwidth {
+                manager = manager.with_bandwidth_limiter(limiter.clone());
+            }
+            let daemon = self.clone();
+            info!("starting job {id}");
+            let task = tokio::spawn(async move { manager.run().await.map(|_| ()) });
+            job.handle = Some(task.abort_handle());
+            tokio::spawn(async move {
+                let result = match task.await {
+                    Ok(result) => result,
+                    Err(err) => Err(anyhow!("job task ended: {err}")),
+                };
+                daemon.on_job_finished(id, run, result);
+            });
+        }
+        self.persist(&state);
+    }
+
+    /// Runs once the task of `run` has ended, whether it finished or was
+    /// aborted by [`Daemon::pause`] or [`Daemon::cancel`].
+    fn on_job_finished(self: &Arc<Self>, id: u64, run: u64, result: Result<()>) {
+        {
+            let mut state = self.state.lock().unwrap();
+            let Some(job) = state.jobs.get_mut(&id) else {
+                return;
+            };
+            if job.run != run {
+                return;
+            }
+            job.handle = None;
+            if std::mem::take(&mut job.stopping) {
+                if job.record.state == JobState::Cancelled {
+                    remove_partial(&job.record.output);
+                }
+                drop(state);
+                self.schedule();
+                return;
+            }
+            if job.record.state != JobState::Active {
+                return;
+            }
+            job.remember_progress();
+            match result {
+                Ok(()) => {
+                    info!("job {id} completed");
+                    job.record.state = JobState::Completed;
+                }
+                Err(err) => {
+                    warn!("job {id} failed: {err}");
+                    job.record.state = JobState::Failed;
+                    job.record.error = Some(err.to_string());
+                }
+            }
+            self.persist(&state);
+        }
+        self.schedule();
+    }
+
+    fn persist(&self, state: &QueueState) {
+        let persisted = PersistedQueue {
+            next_id: state.next_id,
+            jobs: state
+                .jobs
+                .values()
+                .map(|job| {
+                    let mut record = job.record.clone();
+                    if let Some(snapshot) = job.progress.sample() {
+                        record.downloaded = snapshot.downloaded;
+                        record.total = snapshot.total.or(record.total);
+                    }
+                    record
+                })
+                .collect(),
+        };
+        if let Err(err) = write_atomically(&self.settings.state_dir.join(STATE_FILE), &persisted) {
+            error!("failed to persist queue: {err}");
+        }
+    }
+}
+
+impl Job {
+    /// Aborts the running task; it counts as stopping until it has ended.
+    fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            handle.abort();
+            self.stopping = true;
+        }
+        self.remember_progress();
+    }
+
+    fn remember_progress(&mut self) {
+        if let Some(snapshot) = self.progress.sample() {
+            self.record.downloaded = snapshot.downloaded;
+            self.record.total = snapshot.total.or(self.record.total);
+        }
+    }
+}
+
+fn write_atomically<T: Serialize>(path: &PathBuf, value: &T) -> Result<()> {
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)
+        .with_context(|| format!("failed to write {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace {:?}", path))?;
+    Ok(())
+}
+
+fn remove_partial(output: &std::path::Path) {
+    let partmap = crate::util::derive_partmap_path(output);
+    if partmap.exists() {
+        let _ = fs::remove_file(&partmap);
+        let _ = fs::remove_file(output);
+    }
+}
+
+/// Observer that keeps the latest progress of a running job.
+#[derive(Default)]
+struct JobProgress {
+    inner: Mutex<JobProgressInner>,
+}
+
+#[derive(Default)]
+struct JobProgressInner {
+    last: Option<ProgressSnapshot>,
+    sample: Option<(Instant, u64)>,
+    speed: u64,
+}
+
+impl JobProgress {
+    fn sample(&self) -> Option<ProgressSnapshot> {
+        self.inner.lock().unwrap().last.clone()
+    }
+
+    fn speed(&self) -> u64 {
+        self.inner.lock().unwrap().speed
+    }
+}
+
+impl ProgressObserver for JobProgress {
+    fn on_event(&self, event: &DownloadEvent) {
+        let mut inner = self.inner.lock().unwrap();
+        match event {
+            DownloadEvent::MetadataProbed { total_bytes, .. } => {
+                let mut snapshot = inner.last.clone().unwrap_or_default();
+                snapshot.total = *total_bytes;
+                inner.last = Some(snapshot);
+            }
+            DownloadEvent::Progress(snapshot) => {
+                let now = Instant::now();
+                match inner.sample {
+                    Some((at, bytes)) if now.duration_since(at) >= SPEED_WINDOW => {
+                        let delta = snapshot.downloaded.saturating_sub(bytes);
+                        inner.speed = (delta as f64 / now.duration_since(at).as_secs_f64()) as u64;
+                        inner.sample = Some((now, snapshot.downloaded));
+                    }
+                    Some(_) => {}
+                    None => inner.sample = Some((now, snapshot.downloaded)),
+                }
+                inner.last = Some(snapshot.clone());
+            }
+            DownloadEvent::Finished { snapshot, .. } if snapshot.total.is_some() => {
+                inner.last = Some(snapshot.clone());
+                inner.speed = 0;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server;
+
+    fn settings(dir: &std::path::Path) -> DaemonSettings {
+        DaemonSettings {
+            state_dir: dir.join("state"),
+            output_dir: dir.join("out"),
+            max_active: 1,
+            max_connections: 4,
+            connections_per_host: 4,
+            segments: 4,
+            timeout: None,
+            bandwidth_limit: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_survives_restart() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let settings = settings(dir);
+        // The test never yields, so paused jobs never get to connect.
+        let daemon = Daemon::new(settings.clone()).expect("daemon");
+        let first = daemon
+            .add(JobSpec {
+                urls: vec!["http://127.0.0.1:9/a.bin".into()],
+                ..JobSpec::default()
+            })
+            .expect("add");
+        daemon.pause(first).expect("pause");
+        let second = daemon
+            .add(JobSpec {
+                urls: vec!["http://127.0.0.1:9/b.bin".into()],
+                priority: 3,
+                ..JobSpec::default()
+            })
+            .expect("add");
+        daemon.pause(second).expect("pause");
+        daemon.set_priority(first, 7).expect("priority");
+        drop(daemon);
+
+        let restored = Daemon::new(settings).expect("restore");
+        let jobs = restored.list();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].state, JobState::Paused);
+        assert_eq!(jobs[0].priority, 7);
+        assert_eq!(jobs[1].output, dir.join("out").join("b.bin"));
+        assert!(restored.resume(first).is_ok());
+        assert!(restored.cancel(99).is_err());
+    }
+
+    #[tokio::test]
+    async fn jobs_cannot_share_an_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let daemon = Daemon::new(settings(dir.path())).expect("daemon");
+        let spec = JobSpec {
+            urls: vec!["http://127.0.0.1:9/a.bin".into()],
+            ..JobSpec::default()
+        };
+        let first = daemon.add(spec.clone()).expect("add");
+        daemon.pause(first).expect("pause");
+        let same = JobSpec {
+            urls: vec!["http://127.0.0.1:9/other.bin".into()],
+            output: Some("a.bin".into()),
+            ..JobSpec::default()
+        };
+        let err = daemon.add(same).unwrap_err();
+        assert!(err.to_string().contains(&format!("job {first}")), "{err}");
+
+        daemon.cancel(first).expect("cancel");
+        until(|| daemon.add(spec.clone()).is_ok()).await;
+    }
+
+    /// Polls `done` until it holds, for at most five seconds.
+    async fn until(mut done: impl FnMut() -> bool) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("condition never held");
+    }
+
+    #[tokio::test]
+    async fn stopped_jobs_release_their_files_only_once_ended() {
+        let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let served = data.clone();
+        let base = test_server::spawn(move |req| test_server::file(req, &served));
+        let temp = tempfile::tempdir().unwrap();
+        let daemon = Daemon::new(settings(temp.path())).expect("daemon");
+        let spec = JobSpec {
+            urls: vec![format!("{base}/a.bin")],
+            ..JobSpec::default()
+        };
+
+        // The first run has not ended yet, so the job waits in the queue.
+        let id = daemon.add(spec.clone()).expect("add");
+        daemon.pause(id).expect("pause");
+        daemon.resume(id).expect("resume");
+        assert_eq!(daemon.job(id).unwrap().state, JobState::Queued);
+        until(|| daemon.job(id).unwrap().state == JobState::Completed).await;
+        let output = daemon.job(id).unwrap().output;
+        assert_eq!(fs::read(&output).unwrap(), data);
+
+        // Partial files stay until the aborted task has ended.
+        let id = daemon.add(spec).expect("add");
+        let output = daemon.job(id).unwrap().output;
+        let partmap = crate::util::derive_partmap_path(&output);
+        fs::write(&output, b"partial").unwrap();
+        fs::write(&partmap, b"").unwrap();
+        daemon.cancel(id).expect("cancel");
+        assert!(output.exists() && partmap.exists());
+        until(|| !output.exists() && !partmap.exists()).await;
+    }
+
+    #[tokio::test]
+    async fn failed_jobs_can_be_cancelled() {
+        let temp = tempfile::tempdir().unwrap();
+        let daemon = Daemon::new(settings(temp.path())).expect("daemon");
+        let id = daemon
+            .add(JobSpec {
+                urls: vec!["http://127.0.0.1:9/a.bin".into()],
+                ..JobSpec::default()
+            })
+            .expect("add");
+        until(|| daemon.job(id).unwrap().state == JobState::Failed).await;
+
+        let output = daemon.job(id).unwrap().output;
+        let partmap = crate::util::derive_partmap_path(&output);
+        fs::write(&output, b"partial").unwrap();
+        fs::write(&partmap, b"").unwrap();
+        daemon.cancel(id).expect("cancel");
+        assert_eq!(daemon.job(id).unwrap().state, JobState::Cancelled);
+        assert!(!output.exists() && !partmap.exists());
+    }
+}
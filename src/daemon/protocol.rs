@@ -0,0 +1,96 @@
+//! Control protocol: one JSON request per line, answered by one JSON response
+//! per line.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::{Daemon, JobInfo, JobSpec};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Add(JobSpec),
+    Pause { id: u64 },
+    Resume { id: u64 },
+    Cancel { id: u64 },
+    Priority { id: u64, priority: i32 },
+    List,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub jobs: Option<Vec<JobInfo>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Response {
+    fn ok() -> Self {
+        Self {
+            ok: true,
+            ..Self::default()
+        }
+    }
+
+    pub fn error(message: impl Into<String>) -> Self {
+        Self {
+            ok: false,
+            error: Some(message.into()),
+            ..Self::default()
+        }
+    }
+}
+
+/// Applies one request to the queue.
+pub fn handle(daemon: &Arc<Daemon>, request: Request) -> Response {
+    let result = match request {
+        Request::Add(spec) => daemon.add(spec).map(|id| Response {
+            id: Some(id),
+            ..Response::ok()
+        }),
+        Request::Pause { id } => daemon.pause(id).map(|_| Response::ok()),
+        Request::Resume { id } => daemon.resume(id).map(|_| Response::ok()),
+        Request::Cancel { id } => daemon.cancel(id).map(|_| Response::ok()),
+        Request::Priority { id, priority } => {
+            daemon.set_priority(id, priority).map(|_| Response::ok())
+        }
+        Request::List => Ok(Response {
+            jobs: Some(daemon.list()),
+            ..Response::ok()
+        }),
+    };
+    result.unwrap_or_else(|err| Response::error(format!("{err:#}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_are_tagged_by_command() {
+        let request: Request = serde_json::from_str(
+            r#"{"command":"add","urls":["https://a.example/x"],"priority":2}"#,
+        )
+        .expect("parse add");
+        match request {
+            Request::Add(spec) => {
+                assert_eq!(spec.urls, vec!["https://a.example/x"]);
+                assert_eq!(spec.priority, 2);
+                assert!(spec.output.is_none());
+            }
+            other => panic!("unexpected request {other:?}"),
+        }
+
+        let json = serde_json::to_string(&Request::Priority {
+            id: 4,
+            priority: -1,
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"command":"priority","id":4,"priority":-1}"#);
+    }
+}
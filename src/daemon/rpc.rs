@@ -0,0 +1,708 @@
+//! aria2-compatible JSON-RPC front-end for the daemon queue.
+//!
+//! Requests are accepted as HTTP `POST /jsonrpc` bodies and as text messages
+//! on a WebSocket opened against the same path. Job ids are exposed as
+//! 16-digit hex GIDs and numbers are encoded as strings, as aria2 does.
+//!
+//! Browsers may only reach the server from origins listed in
+//! [`RpcSettings::allowed_origins`]; requests carrying any other `Origin`
+//! are refused, so a web page cannot queue downloads behind the user's back.
+
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::path::{Component, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{info, warn};
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::WebSocketStream;
+
+use crate::checksum::HashAlgorithm;
+
+use super::{Daemon, JobInfo, JobSpec, JobState};
+
+const RPC_PATH: &str = "/jsonrpc";
+
+/// JSON-RPC error code aria2 uses for every failed call.
+const ARIA2_ERROR: i64 = 1;
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+
+/// Access control for the RPC server.
+#[derive(Debug, Clone, Default)]
+pub struct RpcSettings {
+    /// When set, every call must pass `token:<secret>` as first parameter.
+    pub secret: Option<String>,
+    /// Browser origins (e.g. `http://localhost:8080`) allowed to call the
+    /// server. Requests without an `Origin` header are always accepted.
+    pub allowed_origins: Vec<String>,
+}
+
+/// Serves the RPC interface on `listener` until the process exits.
+pub async fn serve(
+    daemon: Arc<Daemon>,
+    listener: TcpListener,
+    settings: RpcSettings,
+) -> Result<()> {
+    let addr = listener.local_addr()?;
+    let rpc = Arc::new(Rpc { daemon, settings });
+    let make_service = make_service_fn(move |_| {
+        let rpc = rpc.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let rpc = rpc.clone();
+                async move { Ok::<_, Infallible>(rpc.serve_http(req).await) }
+            }))
+        }
+    });
+    info!("JSON-RPC listening on http://{addr}{RPC_PATH}");
+    Server::from_tcp(listener)
+        .with_context(|| format!("failed to listen on {addr}"))?
+        .serve(make_service)
+        .await
+        .context("JSON-RPC server failed")
+}
+
+struct Rpc {
+    daemon: Arc<Daemon>,
+    settings: RpcSettings,
+}
+
+impl Rpc {
+    async fn serve_http(self: Arc<Self>, mut req: Request<Body>) -> Response<Body> {
+        if req.uri().path() != RPC_PATH {
+            return plain(StatusCode::NOT_FOUND, "not found");
+        }
+        let origin = match self.allowed_origin(&req) {
+            Ok(origin) => origin,
+            Err(origin) => {
+                warn!("refused JSON-RPC request from origin {origin:?}");
+                return plain(StatusCode::FORBIDDEN, "origin not allowed");
+            }
+        };
+        if is_websocket_upgrade(&req) {
+            return self.upgrade(&mut req);
+        }
+        match *req.method() {
+            Method::OPTIONS => with_cors(Response::new(Body::empty()), origin),
+            Method::POST => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(err) => return plain(StatusCode::BAD_REQUEST, &err.to_string()),
+                };
+                let reply = self.handle_message(&body);
+                let mut response = Response::new(Body::from(reply.to_string()));
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static("application/json-rpc"),
+                );
+                with_cors(response, origin)
+            }
+            _ => plain(StatusCode::METHOD_NOT_ALLOWED, "use POST or a WebSocket"),
+        }
+    }
+
+    /// The request's `Origin` if it may be echoed back, `Ok(None)` when
+    /// there is none, or the refused origin.
+    fn allowed_origin(&self, req: &Request<Body>) -> Result<Option<HeaderValue>, String> {
+        let Some(origin) = req.headers().get(header::ORIGIN) else {
+            return Ok(None);
+        };
+        let text = origin.to_str().unwrap_or_default();
+        if self
+            .settings
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(text))
+        {
+            Ok(Some(origin.clone()))
+        } else {
+            Err(text.to_string())
+        }
+    }
+
+    fn upgrade(self: Arc<Self>, req: &mut Request<Body>) -> Response<Body> {
+        let Some(key) = req.headers().get(header::SEC_WEBSOCKET_KEY) else {
+            return plain(StatusCode::BAD_REQUEST, "missing Sec-WebSocket-Key");
+        };
+        let accept = derive_accept_key(key.as_bytes());
+        let upgrade = hyper::upgrade::on(req);
+        tokio::spawn(async move {
+            match upgrade.await {
+                Ok(upgraded) => {
+                    let socket =
+                        WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+                    if let Err(err) = self.serve_websocket(socket).await {
+                        warn!("JSON-RPC WebSocket closed: {err}");
+                    }
+                }
+                Err(err) => warn!("WebSocket upgrade failed: {err}"),
+            }
+        });
+
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+        let headers = response.headers_mut();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        headers.insert(
+            header::SEC_WEBSOCKET_ACCEPT,
+            HeaderValue::from_str(&accept).expect("accept key is base64"),
+        );
+        response
+    }
+
+    async fn serve_websocket(
+        &self,
+        mut socket: WebSocketStream<hyper::upgrade::Upgraded>,
+    ) -> Result<()> {
+        while let Some(message) = socket.next().await {
+            let reply = match message? {
+                Message::Text(text) => self.handle_message(text.as_bytes()),
+                Message::Binary(data) => self.handle_message(&data),
+                Message::Close(_) => break,
+                _ => continue,
+            };
+            socket.send(Message::Text(reply.to_string())).await?;
+        }
+        Ok(())
+    }
+
+    /// Handles a single call or a batch.
+    fn handle_message(&self, body: &[u8]) -> Value {
+        match serde_json::from_slice::<Value>(body) {
+            Ok(Value::Array(calls)) => Value::Array(
+                calls
+                    .into_iter()
+                    .map(|call| self.handle_call(call))
+                    .collect(),
+            ),
+            Ok(call) => self.handle_call(call),
+            Err(err) => error_reply(Value::Null, PARSE_ERROR, &err.to_string()),
+        }
+    }
+
+    fn handle_call(&self, call: Value) -> Value {
+        let id = call.get("id").cloned().unwrap_or(Value::Null);
+        let Some(method) = call.get("method").and_then(Value::as_str) else {
+            return error_reply(id, INVALID_REQUEST, "missing method");
+        };
+        let params = match call.get("params") {
+            Some(Value::Array(params)) => params.clone(),
+            None => Vec::new(),
+            Some(_) => return error_reply(id, INVALID_REQUEST, "params must be an array"),
+        };
+        let params = match self.check_token(params) {
+            Ok(params) => params,
+            Err(err) => return error_reply(id, ARIA2_ERROR, &err.to_string()),
+        };
+        match self.dispatch(method, &params) {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(RpcError::UnknownMethod) => {
+                error_reply(id, METHOD_NOT_FOUND, &format!("method not found: {method}"))
+            }
+            Err(RpcError::Failed(err)) => error_reply(id, ARIA2_ERROR, &format!("{err:#}")),
+        }
+    }
+
+    fn check_token(&self, mut params: Vec<Value>) -> Result<Vec<Value>> {
+        let token = params
+            .first()
+            .and_then(Value::as_str)
+            .and_then(|first| first.strip_prefix("token:"))
+            .map(str::to_string);
+        if token.is_some() {
+            params.remove(0);
+        }
+        match &self.settings.secret {
+            Some(secret) if !secret_matches(secret, token.as_deref()) => {
+                Err(anyhow!("Unauthorized"))
+            }
+            _ => Ok(params),
+        }
+    }
+
+    fn dispatch(&self, method: &str, params: &[Value]) -> Result<Value, RpcError> {
+        let daemon = &self.daemon;
+        let result = match method {
+            "aria2.addUri" => self.add_uri(params),
+            "aria2.tellStatus" => {
+                let id = gid_param(params)?;
+                let job = daemon.job(id).ok_or_else(|| no_such_gid(id))?;
+                Ok(filter_keys(status(&job), params.get(1)))
+            }
+            "aria2.tellActive" => {
+                Ok(self.tell(|state| state == JobState::Active, None, params.first()))
+            }
+            "aria2.tellWaiting" => Ok(self.tell(
+                |state| matches!(state, JobState::Queued | JobState::Paused),
+                Some(page_param(params)?),
+                params.get(2),
+            )),
+            "aria2.tellStopped" => Ok(self.tell(
+                JobState::is_finished,
+                Some(page_param(params)?),
+                params.get(2),
+            )),
+            "aria2.pause" | "aria2.forcePause" => {
+                let id = gid_param(params)?;
+                daemon.pause(id).map(|_| gid(id))
+            }
+            "aria2.unpause" => {
+                let id = gid_param(params)?;
+                daemon.resume(id).map(|_| gid(id))
+            }
+            "aria2.remove" | "aria2.forceRemove" => {
+                let id = gid_param(params)?;
+                daemon.cancel(id).map(|_| gid(id))
+            }
+            "aria2.getGlobalStat" => Ok(self.global_stat()),
+            "aria2.getVersion" => Ok(json!({
+                "version": env!("CARGO_PKG_VERSION"),
+                "enabledFeatures": ["HTTP", "HTTPS"],
+            })),
+            _ => return Err(RpcError::UnknownMethod),
+        };
+        result.map_err(RpcError::Failed)
+    }
+
+    /// `aria2.addUri([uris], {options}, position)`. Supported options are
+    /// `dir`, `out` and `checksum=sha-256=<hex>`; `dir` and `out` are taken
+    /// relative to the daemon's output directory.
+    fn add_uri(&self, params: &[Value]) -> Result<Value> {
+        let urls: Vec<String> = params
+            .first()
+            .and_then(Value::as_array)
+            .ok_or_else(|| anyhow!("addUri expects an array of URIs"))?
+            .iter()
+            .map(|uri| {
+                uri.as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| anyhow!("URIs must be strings"))
+            })
+            .collect::<Result<_>>()?;
+
+        let options = params.get(1).and_then(Value::as_object);
+        let option = |key: &str| options.and_then(|o| o.get(key)).and_then(Value::as_str);
+        let output = match (option("dir"), option("out")) {
+            (Some(dir), Some(out)) => Some(PathBuf::from(dir).join(out)),
+            (Some(dir), None) => Some(PathBuf::from(format!("{dir}{}", std::path::MAIN_SEPARATOR))),
+            (None, Some(out)) => Some(PathBuf::from(out)),
+            (None, None) => None,
+        };
+        if let Some(path) = &output {
+            let inside = path
+                .components()
+                .all(|part| matches!(part, Component::Normal(_) | Component::CurDir));
+            if !inside {
+                return Err(anyhow!(
+                    "dir and out must be relative paths inside the download directory"
+                ));
+            }
+        }
+        // aria2 only takes `<type>=<digest>` here, never a checksum file.
+        let checksum = match option("checksum") {
+            Some(value) => match value.split_once('=') {
+                Some((name, _)) if HashAlgorithm::from_name(name).is_ok() => {
+                    Some(value.to_string())
+                }
+                _ => return Err(anyhow!("unsupported checksum {value:?}")),
+            },
+            None => None,
+        };
+
+        let id = self.daemon.add(JobSpec {
+            urls,
+            output,
+            checksum,
+            priority: 0,
+        })?;
+        Ok(gid(id))
+    }
+
+    fn tell(
+        &self,
+        filter: impl Fn(JobState) -> bool,
+        page: Option<(usize, usize)>,
+        keys: Option<&Value>,
+    ) -> Value {
+        let (offset, num) = page.unwrap_or((0, usize::MAX));
+        Value::Array(
+            self.daemon
+                .list()
+                .iter()
+                .filter(|job| filter(job.state))
+                .skip(offset)
+                .take(num)
+                .map(|job| filter_keys(status(job), keys))
+                .collect(),
+        )
+    }
+
+    fn global_stat(&self) -> Value {
+        let jobs = self.daemon.list();
+        let count = |f: fn(JobState) -> bool| jobs.iter().filter(|job| f(job.state)).count();
+        let speed: u64 = jobs.iter().map(|job| job.speed).sum();
+        let stopped = count(JobState::is_finished);
+        json!({
+            "downloadSpeed": speed.to_string(),
+            "uploadSpeed": "0",
+            "numActive": count(|state| state == JobState::Active).to_string(),
+            "numWaiting": count(|state| matches!(state, JobState::Queued | JobState::Paused)).to_string(),
+            "numStopped": stopped.to_string(),
+            "numStoppedTotal": stopped.to_string(),
+        })
+    }
+}
+
+enum RpcError {
+    UnknownMethod,
+    Failed(anyhow::Error),
+}
+
+impl From<anyhow::Error> for RpcError {
+    fn from(err: anyhow::Error) -> Self {
+        RpcError::Failed(err)
+    }
+}
+
+/// Compares `token` with `secret` in time that does not depend on where
+/// they differ: both are hashed and the digests compared in full.
+fn secret_matches(secret: &str, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return false;
+    };
+    let (expected, given) = (Sha256::digest(secret), Sha256::digest(token));
+    expected
+        .iter()
+        .zip(given.iter())
+        .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+        == 0
+}
+
+fn gid(id: u64) -> Value {
+    Value::String(format!("{id:016x}"))
+}
+
+fn gid_param(params: &[Value]) -> Result<u64> {
+    let gid = params
+        .first()
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("GID is required"))?;
+    u64::from_str_radix(gid, 16).map_err(|_| anyhow!("invalid GID {gid}"))
+}
+
+/// `offset` and `num` of `tellWaiting` and `tellStopped`.
+fn page_param(params: &[Value]) -> Result<(usize, usize)> {
+    let number = |index: usize| {
+        params
+            .get(index)
+            .and_then(Value::as_u64)
+            .map(|value| value as usize)
+            .ok_or_else(|| anyhow!("offset and num are required"))
+    };
+    Ok((number(0)?, number(1)?))
+}
+
+fn no_such_gid(id: u64) -> anyhow::Error {
+    anyhow!("GID {id:016x} is not found")
+}
+
+/// aria2's `tellStatus` structure for one job.
+fn status(job: &JobInfo) -> Value {
+    let status = match job.state {
+        JobState::Queued => "waiting",
+        JobState::Active => "active",
+        JobState::Paused => "paused",
+        JobState::Completed => "complete",
+        JobState::Failed => "error",
+        JobState::Cancelled => "removed",
+    };
+    let total = job.total.unwrap_or(0).to_string();
+    let completed = job.downloaded.to_string();
+    let dir = job
+        .output
+        .parent()
+        .map(|dir| dir.display().to_string())
+        .unwrap_or_default();
+    let uris: Vec<Value> = job
+        .urls
+        .iter()
+        .map(|uri| json!({ "uri": uri, "status": "used" }))
+        .collect();
+
+    let mut value = json!({
+        "gid": gid(job.id),
+        "status": status,
+        "totalLength": total,
+        "completedLength": completed,
+        "uploadLength": "0",
+        "downloadSpeed": job.speed.to_string(),
+        "uploadSpeed": "0",
+        "connections": job.connections.to_string(),
+        "dir": dir,
+        "files": [{
+            "index": "1",
+            "path": job.output.display().to_string(),
+            "length": total,
+            "completedLength": completed,
+            "selected": "true",
+            "uris": uris,
+        }],
+    });
+    if let Some(err) = &job.error {
+        value["errorCode"] = json!("1");
+        value["errorMessage"] = json!(err);
+    }
+    value
+}
+
+/// Keeps only the requested keys, like aria2's optional `keys` parameter.
+fn filter_keys(status: Value, keys: Option<&Value>) -> Value {
+    let Some(keys) = keys
+        .and_then(Value::as_array)
+        .filter(|keys| !keys.is_empty())
+    else {
+        return status;
+    };
+    let Value::Object(fields) = status else {
+        return status;
+    };
+    let wanted: Map<String, Value> = fields
+        .into_iter()
+        .filter(|(key, _)| keys.iter().any(|k| k.as_str() == Some(key)))
+        .collect();
+    Value::Object(wanted)
+}
+
+fn error_reply(id: Value, code: i64, message: &str) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "error": { "code": code, "message": message },
+    })
+}
+
+fn is_websocket_upgrade(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+}
+
+/// Lets an allowed browser front-end read the response.
+fn with_cors(mut response: Response<Body>, origin: Option<HeaderValue>) -> Response<Body> {
+    let headers = response.headers_mut();
+    headers.insert(header::VARY, HeaderValue::from_static("Origin"));
+    let Some(origin) = origin else {
+        return response;
+    };
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Content-Type"),
+    );
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("POST, OPTIONS"),
+    );
+    response
+}
+
+fn plain(status: StatusCode, message: &str) -> Response<Body> {
+    let mut response = Response::new(Body::from(message.to_string()));
+    *response.status_mut() = status;
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::daemon::DaemonSettings;
+
+    #[test]
+    fn secrets_must_match_exactly() {
+        assert!(secret_matches("s3cret", Some("s3cret")));
+        assert!(!secret_matches("s3cret", Some("s3cre")));
+        assert!(!secret_matches("s3cret", Some("s3cret ")));
+        assert!(!secret_matches("s3cret", None));
+    }
+
+    #[tokio::test]
+    async fn answers_over_http_and_websocket() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let daemon = Daemon::new(DaemonSettings {
+            state_dir: dir.join("state"),
+            output_dir: dir.join("out"),
+            max_active: 1,
+            max_connections: 4,
+            connections_per_host: 4,
+            segments: 4,
+            timeout: None,
+            bandwidth_limit: None,
+        })
+        .expect("daemon");
+        // Paused before the runtime yields, so it never tries to connect.
+        let held = daemon
+            .add(JobSpec {
+                urls: vec!["http://127.0.0.1:9/held.bin".into()],
+                ..JobSpec::default()
+            })
+            .expect("add");
+        daemon.pause(held).expect("pause");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().unwrap();
+        let settings = RpcSettings {
+            secret: Some("s3cret".into()),
+            allowed_origins: vec!["http://localhost:8080".into()],
+        };
+        tokio::spawn(serve(daemon, listener, settings));
+
+        let client = reqwest::Client::new();
+        let call = |body: Value| {
+            let client = client.clone();
+            async move {
+                client
+                    .post(format!("http://{addr}/jsonrpc"))
+                    .body(body.to_string())
+                    .send()
+                    .await
+                    .expect("post")
+                    .bytes()
+                    .await
+                    .map(|body| serde_json::from_slice::<Value>(&body).expect("json"))
+                    .expect("body")
+            }
+        };
+
+        let added = call(json!({
+            "jsonrpc": "2.0", "id": "a", "method": "aria2.addUri",
+            "params": ["token:s3cret", ["http://127.0.0.1:9/file.bin"], {"out": "x.bin"}],
+        }))
+        .await;
+        assert_eq!(added["result"], json!("0000000000000002"));
+
+        let status = call(json!({
+            "jsonrpc": "2.0", "id": 2, "method": "aria2.tellStatus",
+            "params": ["token:s3cret", "0000000000000002", ["files"]],
+        }))
+        .await;
+        assert!(status["result"]["files"][0]["path"]
+            .as_str()
+            .unwrap()
+            .ends_with("x.bin"));
+
+        let denied =
+            call(json!({"jsonrpc": "2.0", "id": 3, "method": "aria2.getGlobalStat"})).await;
+        assert_eq!(denied["error"]["message"], "Unauthorized");
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/jsonrpc"))
+            .await
+            .expect("websocket");
+        let request = json!({
+            "jsonrpc": "2.0", "id": 4, "method": "aria2.tellStatus",
+            "params": ["token:s3cret", "0000000000000001", ["status"]],
+        });
+        socket
+            .send(Message::Text(request.to_string()))
+            .await
+            .unwrap();
+        let reply = match socket.next().await.expect("reply").unwrap() {
+            Message::Text(text) => serde_json::from_str::<Value>(&text).unwrap(),
+            other => panic!("unexpected message {other:?}"),
+        };
+        assert_eq!(reply["result"]["status"], "paused");
+        assert!(reply["result"].get("gid").is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_origins_and_paths_outside_the_output() {
+        let temp = tempfile::tempdir().unwrap();
+        let daemon = Daemon::new(DaemonSettings {
+            state_dir: temp.path().join("state"),
+            output_dir: temp.path().join("out"),
+            max_active: 1,
+            max_connections: 4,
+            connections_per_host: 4,
+            segments: 4,
+            timeout: None,
+            bandwidth_limit: None,
+        })
+        .expect("daemon");
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let addr = listener.local_addr().unwrap();
+        let settings = RpcSettings {
+            secret: None,
+            allowed_origins: vec!["http://localhost:8080".into()],
+        };
+        tokio::spawn(serve(daemon, listener, settings));
+
+        let client = reqwest::Client::new();
+        let version = json!({"jsonrpc": "2.0", "id": 1, "method": "aria2.getVersion"});
+        let post = |origin: &'static str| {
+            client
+                .post(format!("http://{addr}/jsonrpc"))
+                .header(header::ORIGIN, origin)
+                .body(version.to_string())
+                .send()
+        };
+        let allowed = post("http://localhost:8080").await.expect("post");
+        assert_eq!(allowed.status(), StatusCode::OK);
+        assert_eq!(
+            allowed.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "http://localhost:8080"
+        );
+        let foreign = post("https://evil.example").await.expect("post");
+        assert_eq!(foreign.status(), StatusCode::FORBIDDEN);
+
+        let request = tokio_tungstenite::tungstenite::http::Request::builder()
+            .uri(format!("ws://{addr}/jsonrpc"))
+            .header("Host", addr.to_string())
+            .header("Origin", "https://evil.example")
+            .header("Connection", "Upgrade")
+            .header("Upgrade", "websocket")
+            .header("Sec-WebSocket-Version", "13")
+            .header("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==")
+            .body(())
+            .unwrap();
+        assert!(tokio_tungstenite::connect_async(request).await.is_err());
+
+        for options in [
+            json!({"dir": "/etc"}),
+            json!({"out": "../escape.bin"}),
+            json!({"dir": "sub", "out": "/tmp/x"}),
+        ] {
+            let reply = client
+                .post(format!("http://{addr}/jsonrpc"))
+                .body(
+                    json!({
+                        "jsonrpc": "2.0", "id": 2, "method": "aria2.addUri",
+                        "params": [["http://127.0.0.1:9/file.bin"], options],
+                    })
+                    .to_string(),
+                )
+                .send()
+                .await
+                .expect("post")
+                .bytes()
+                .await
+                .map(|body| serde_json::from_slice::<Value>(&body).expect("json"))
+                .expect("body");
+            assert!(reply["error"]["message"]
+                .as_str()
+                .unwrap()
+                .contains("inside the download directory"));
+        }
+    }
+}
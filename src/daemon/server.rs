@@ -0,0 +1,81 @@
+//! Unix socket front-end for the daemon and the matching client.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+use super::protocol::{handle, Request, Response};
+use super::Daemon;
+
+/// Accepts control connections on `socket` until the process exits.
+pub async fn serve(daemon: Arc<Daemon>, socket: &Path) -> Result<()> {
+    if socket.exists() {
+        if UnixStream::connect(socket).await.is_ok() {
+            return Err(anyhow!(
+                "another daemon is already listening on {:?}",
+                socket
+            ));
+        }
+        // Left behind by a daemon that did not shut down cleanly.
+        std::fs::remove_file(socket)
+            .with_context(|| format!("failed to remove stale socket {:?}", socket))?;
+    }
+    crate::util::ensure_parent_dir(socket)?;
+    let listener =
+        UnixListener::bind(socket).with_context(|| format!("failed to bind {:?}", socket))?;
+    info!("listening on {:?}", socket);
+
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let daemon = daemon.clone();
+        tokio::spawn(async move {
+            if let Err(err) = handle_connection(daemon, stream).await {
+                warn!("control connection failed: {err}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(daemon: Arc<Daemon>, stream: UnixStream) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => handle(&daemon, request),
+            Err(err) => Response::error(format!("invalid request: {err}")),
+        };
+        let mut bytes = serde_json::to_vec(&response)?;
+        bytes.push(b'\n');
+        write.write_all(&bytes).await?;
+    }
+    Ok(())
+}
+
+/// Sends one request to the daemon listening on `socket`.
+pub async fn send(socket: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(socket)
+        .await
+        .with_context(|| format!("failed to connect to daemon at {:?}", socket))?;
+    let (read, mut write) = stream.into_split();
+    let mut bytes = serde_json::to_vec(request)?;
+    bytes.push(b'\n');
+    write.write_all(&bytes).await?;
+
+    let line = BufReader::new(read)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| anyhow!("daemon closed the connection"))?;
+    let response: Response = serde_json::from_str(&line).context("invalid daemon response")?;
+    match response.error {
+        Some(err) if !response.ok => Err(anyhow!(err)),
+        _ => Ok(response),
+    }
+}
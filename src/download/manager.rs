@@ -1,39 +1,51 @@
+use crate::checksum::ChecksumSpec;
 use crate::download::bandwidth::BandwidthLimiter;
-use crate::download::mirror::MirrorPool;
-use crate::download::partmap::PartMapHandle;
-use crate::download::DownloadConfig;
-use crate::progress::{ProgressFinish, ProgressReporter};
+use crate::download::mirror::{HostLimits, MirrorFailure, MirrorPool};
+use crate::download::partmap::{
+    read_file_size, read_resume_source, PartMapHandle, PartSegment, ResumeSource, Validator,
+};
+use crate::download::piece_tracker::{overlapping, PieceTracker};
+use crate::download::prefix_hash::PrefixHasher;
+use crate::download::sink::{FileSink, OutputSink};
+use crate::download::{checksum_names, ChangePolicy, DownloadConfig, DownloadOutcome};
+use crate::pieces::PieceHashes;
+use crate::progress::{
+    observer_for_mode, DownloadEvent, ProgressFinish, ProgressObserver, ProgressReporter,
+    ProgressSnapshot,
+};
 use crate::scheduler::{Scheduler, SegmentStats, SegmentTask};
-use crate::util::{ensure_parent_dir, format_bytes};
+use crate::util::format_bytes;
 
 use anyhow::{anyhow, Context, Result};
+use futures_util::future::join_all;
 use futures_util::StreamExt;
 use log::{debug, info, warn};
 use reqwest::{header, Client, StatusCode, Url};
-use std::fs::{File, OpenOptions};
-use std::io::{self, Seek, SeekFrom, Write};
-#[cfg(unix)]
-use std::os::unix::fs::FileExt;
-#[cfg(windows)]
-use std::os::windows::fs::FileExt as WindowsFileExt;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex as StdMutex};
 use std::time::{Duration, Instant};
 use tokio::fs as async_fs;
-use tokio::task::JoinSet;
-use tokio::time::sleep;
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
 
-#[cfg(target_os = "linux")]
-use nix::errno::Errno;
-#[cfg(target_os = "linux")]
-use nix::fcntl::{fallocate, FallocateFlags};
-#[cfg(target_os = "linux")]
-use std::os::unix::io::AsRawFd;
-
-const MIN_CHUNK_SIZE: u64 = 4 << 20; // 4 MiB
 const MAX_RETRIES: usize = 5;
+/// Smallest piece a segment is split into for an idle connection, and the
+/// smallest initial segment.
+const MIN_SPLIT_SIZE: u64 = 512 << 10; // 512 KiB
+/// How often running segments are sampled to adjust parallelism.
+const REBALANCE_INTERVAL: Duration = Duration::from_millis(500);
+/// How long mirrors get to answer the metadata probe.
+const PROBE_DEADLINE: Duration = Duration::from_secs(10);
+/// Failed verifications of one piece before the download is abandoned.
+const MAX_PIECE_FAILURES: u32 = 3;
 const WRITE_BUFFER_SIZE: usize = 1024 * 1024; // 1 MiB write buffer
+/// Written bytes after which a running segment records its progress.
+const CHECKPOINT_BYTES: u64 = 8 << 20; // 8 MiB
+/// Longest a running segment goes without recording its progress.
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 struct BufferPool {
@@ -49,7 +61,8 @@ impl BufferPool {
 
     fn get(&self) -> Vec<u8> {
         let mut pool = self.pool.lock().unwrap();
-        pool.pop().unwrap_or_else(|| Vec::with_capacity(WRITE_BUFFER_SIZE))
+        pool.pop()
+            .unwrap_or_else(|| Vec::with_capacity(WRITE_BUFFER_SIZE))
     }
 
     fn recycle(&self, mut buf: Vec<u8>) {
@@ -66,83 +79,399 @@ pub struct DownloadManager {
     client: Client,
     mirrors: MirrorPool,
     bandwidth: Option<Arc<BandwidthLimiter>>,
+    connection_budget: Option<Arc<Semaphore>>,
+    sink: Arc<dyn OutputSink>,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    last_snapshot: StdMutex<Option<ProgressSnapshot>>,
 }
 
+#[derive(Clone)]
 struct FileMetadata {
     content_length: Option<u64>,
     supports_ranges: bool,
     filename: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    /// ETag and Last-Modified of every mirror that answered the probe.
+    validators: Vec<Validator>,
 }
 
 enum SegmentOutcome {
-    Completed(SegmentStats),
+    /// Carries the mirror that delivered the last bytes, if any were needed.
+    Completed(SegmentStats, Option<Url>),
     Failed(anyhow::Error),
+    /// An endgame request for the tail of segment `id` finished.
+    Raced(usize, Result<RaceResult>),
+}
+
+/// Bytes fetched by an endgame request, starting at `start`.
+struct RaceResult {
+    start: u64,
+    data: Vec<u8>,
+    url: Url,
+    duration: Duration,
 }
 
 impl DownloadManager {
     pub fn new(config: DownloadConfig) -> Result<Self> {
-        let mirrors = MirrorPool::new(config.urls.clone());
-        let mut builder = Client::builder()
-            .user_agent("kdownload/1.4")
-            .redirect(reqwest::redirect::Policy::limited(10))
-            .pool_max_idle_per_host(config.max_connections_per_host)
-            .pool_idle_timeout(Some(std::time::Duration::from_secs(90)))
-            .tcp_nodelay(true)
-            .http2_adaptive_window(true)
-            .http2_keep_alive_interval(Some(std::time::Duration::from_secs(10)))
-            .http2_keep_alive_timeout(std::time::Duration::from_secs(20));
-        if let Some(timeout) = config.timeout {
-            builder = builder.timeout(timeout);
-        }
-        let client = builder.build().context("failed to build HTTP client")?;
+        let client = build_client(config.max_connections_per_host, config.timeout)?;
+        Ok(Self::with_client(config, client))
+    }
+
+    /// Creates a manager that issues its requests through an existing client,
+    /// sharing its connection pool with other downloads.
+    pub fn with_client(config: DownloadConfig, client: Client) -> Self {
+        let mirrors = MirrorPool::new(config.urls.clone(), config.connections_per_host());
         let bandwidth = config
             .bandwidth_limit
             .map(|limit| Arc::new(BandwidthLimiter::new(limit)));
-        Ok(Self {
+        let observer = observer_for_mode(config.progress);
+        let sink: Arc<dyn OutputSink> = Arc::new(FileSink::new(config.output_path.clone()));
+        Self {
             config,
             client,
             mirrors,
             bandwidth,
-        })
+            connection_budget: None,
+            sink,
+            observer,
+            last_snapshot: StdMutex::new(None),
+        }
+    }
+
+    /// Shares `limiter` with other downloads instead of applying
+    /// [`DownloadConfig::bandwidth_limit`] to this one alone.
+    pub fn with_bandwidth_limiter(mut self, limiter: Arc<BandwidthLimiter>) -> Self {
+        self.bandwidth = Some(limiter);
+        self
+    }
+
+    /// Draws connections to each host from `limits`, shared with other
+    /// downloads, instead of allowing [`DownloadConfig::connections_per_host`]
+    /// to this one alone.
+    pub fn with_host_limits(mut self, limits: &HostLimits) -> Self {
+        self.mirrors = MirrorPool::with_host_limits(self.config.urls.clone(), limits);
+        self
+    }
+
+    /// Makes every connection hold a permit from `budget` while it transfers,
+    /// capping connections across all downloads sharing it.
+    pub fn with_connection_budget(mut self, budget: Arc<Semaphore>) -> Self {
+        self.connection_budget = Some(budget);
+        self
+    }
+
+    /// Replaces the observer selected by [`DownloadConfig::progress`].
+    pub fn with_observer(mut self, observer: Arc<dyn ProgressObserver>) -> Self {
+        self.observer = Some(observer);
+        self
+    }
+
+    /// Writes into `sink` instead of [`DownloadConfig::output_path`].
+    pub fn with_sink(mut self, sink: Arc<dyn OutputSink>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Downloads the configured file, verifying it when a checksum was given.
+    pub async fn run(self) -> Result<DownloadOutcome> {
+        let started = Instant::now();
+        self.emit(DownloadEvent::Started {
+            urls: self.mirrors.all(),
+            output: self.config.output_path.clone(),
+        });
+
+        let result = self.run_inner(started).await;
+        let finish = if result.is_ok() {
+            ProgressFinish::Success
+        } else {
+            ProgressFinish::Failure
+        };
+        let snapshot = self
+            .last_snapshot
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_default();
+        self.emit(DownloadEvent::Finished {
+            result: finish,
+            snapshot,
+            mirror_failures: self.mirrors.failures(),
+        });
+        result
     }
 
-    pub async fn run(self) -> Result<()> {
-        ensure_parent_dir(&self.config.output_path)?;
+    async fn run_inner(&self, started: Instant) -> Result<DownloadOutcome> {
+        if !self.sink.is_readable() {
+            let requested = [
+                (self.config.checksum.is_some(), "a checksum"),
+                (self.config.signature.is_some(), "a signature"),
+            ];
+            if let Some((_, what)) = requested.iter().find(|(requested, _)| *requested) {
+                return Err(anyhow!(
+                    "{what} was requested but the output cannot be read back to verify it"
+                ));
+            }
+        }
         let metadata = self.probe_metadata().await?;
-        let file_path = self.config.output_path.clone();
-        if file_path.exists() && !self.config.resume {
+        let checksum = match &self.config.checksum {
+            Some(spec) => {
+                let names = checksum_names(&self.config.output_path, &self.config.urls);
+                Some(
+                    spec.clone()
+                        .fetch(&self.client, &self.config.urls[0], &names)
+                        .await?,
+                )
+            }
+            None => None,
+        };
+        // Fetched up front so a missing signature fails before the transfer.
+        let signature = match &self.config.signature {
+            Some(spec) => Some((spec, spec.fetch(&self.client).await?)),
+            None => None,
+        };
+        let new_prefix_hash = || {
+            checksum
+                .as_ref()
+                .and_then(ChecksumSpec::algorithm)
+                .filter(|_| self.sink.is_readable())
+                .map(|algorithm| Arc::new(PrefixHasher::new(algorithm)))
+        };
+        let mut prefix_hash = new_prefix_hash();
+        let mut existing = self.sink.open()?;
+        if existing > 0 && !self.config.resume {
             return Err(anyhow!(
                 "output file {:?} already exists; use --resume to continue",
-                file_path
+                self.config.output_path
             ));
         }
 
-        if metadata.supports_ranges && metadata.content_length.is_some() {
-            self.download_segments(metadata).await?;
-        } else {
-            warn!("server does not support ranged requests; falling back to single connection");
-            self.download_streaming(metadata).await?;
+        let mut metadata = metadata;
+        let mut restarted = false;
+        let bytes = loop {
+            if !metadata.supports_ranges || metadata.content_length.is_none() {
+                warn!("server does not support ranged requests; falling back to single connection");
+                let bytes = self
+                    .download_streaming(metadata, existing, prefix_hash.clone())
+                    .await?;
+                if let Some(hashes) = &self.config.piece_hashes {
+                    // Without ranged requests a bad piece cannot be refetched alone.
+                    self.verify_all_pieces(hashes.clone(), bytes).await?;
+                }
+                break bytes;
+            }
+            let err = match self
+                .download_segments(metadata.clone(), existing, prefix_hash.clone())
+                .await
+            {
+                Ok(bytes) => break bytes,
+                Err(err) => err,
+            };
+            let Some(changed) = err.downcast_ref::<FileChanged>() else {
+                return Err(err);
+            };
+            self.on_changed(&changed.to_string())?;
+            if restarted {
+                return Err(anyhow!("{changed}; it changed again after starting over"));
+            }
+            restarted = true;
+            if self.config.partmap_path.exists() {
+                async_fs::remove_file(&self.config.partmap_path)
+                    .await
+                    .with_context(|| {
+                        format!("failed to remove part map {:?}", self.config.partmap_path)
+                    })?;
+            }
+            self.sink.set_len(0)?;
+            self.mirrors.enable_all();
+            metadata = self.probe_metadata().await?;
+            prefix_hash = new_prefix_hash();
+            existing = 0;
+        };
+        if let Some(expected) = self.config.expected_size {
+            if bytes != expected {
+                return Err(anyhow!(
+                    "received {bytes} bytes but {expected} were expected"
+                ));
+            }
         }
 
-        if let Some(spec) = &self.config.expected_sha256 {
-            info!("verifying SHA256 checksum ({})", spec.display());
-            spec.verify_file(&self.config.output_path).await?;
+        let digest = match &checksum {
+            Some(spec) => {
+                let algorithm = spec.algorithm().map_or("checksum", |alg| alg.name());
+                info!("verifying {algorithm} checksum ({})", spec.display());
+                self.emit(DownloadEvent::Verifying { algorithm });
+                if let Some(hasher) = prefix_hash {
+                    let sink = self.sink.clone();
+                    let computed =
+                        tokio::task::spawn_blocking(move || hasher.finish(sink.as_ref(), bytes))
+                            .await??;
+                    Some(spec.check(computed)?)
+                } else {
+                    Some(spec.verify_sink(self.sink.clone()).await?)
+                }
+            }
+            None => None,
+        };
+
+        if let Some((spec, signature)) = signature {
+            info!("verifying {} signature ({})", spec.kind(), spec.display());
+            self.emit(DownloadEvent::Verifying {
+                algorithm: spec.kind(),
+            });
+            if let Err(err) = spec.verify(signature, self.sink.clone()).await {
+                return Err(match self.quarantine() {
+                    Some(path) => anyhow!("{err:#}; output moved to {}", path.display()),
+                    None => err,
+                });
+            }
         }
 
-        Ok(())
+        Ok(DownloadOutcome {
+            bytes,
+            duration: started.elapsed(),
+            path: self.sink.path().map(|path| path.to_path_buf()),
+            digest,
+            mirror_failures: self.mirrors.failures(),
+        })
     }
 
+    /// Moves output that failed signature verification out of the way,
+    /// deleting it if it cannot be renamed.
+    fn quarantine(&self) -> Option<PathBuf> {
+        let path = self.sink.path()?;
+        let mut name = path.file_name()?.to_os_string();
+        name.push(".unverified");
+        let target = path.with_file_name(name);
+        match std::fs::rename(path, &target) {
+            Ok(()) => Some(target),
+            Err(err) => {
+                warn!(
+                    "failed to quarantine {}: {err}; removing it",
+                    path.display()
+                );
+                std::fs::remove_file(path).ok();
+                None
+            }
+        }
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
+    }
+
+    /// Probes every mirror at once and takes the metadata most of them agree
+    /// on. Mirrors reporting another size are dropped; differing validators
+    /// are only reported, since independent servers often compute their own.
     async fn probe_metadata(&self) -> Result<FileMetadata> {
-        for url in self.mirrors.all() {
-            match self.try_head(&url).await {
-                Ok(meta) => return Ok(meta),
+        let probes = join_all(self.mirrors.all().into_iter().map(|url| async move {
+            let started = Instant::now();
+            let result = match timeout(PROBE_DEADLINE, self.try_head(&url)).await {
+                Ok(result) => result,
+                Err(_) => Err(anyhow!("no answer within {PROBE_DEADLINE:?}")),
+            };
+            (url, started.elapsed(), result)
+        }))
+        .await;
+
+        let mut answers = Vec::new();
+        for (url, latency, result) in probes {
+            match result {
+                Ok(meta) if !self.size_matches(&meta) => {
+                    warn!(
+                        "{} reports {} bytes but {} are expected; skipping mirror",
+                        url,
+                        meta.content_length.unwrap_or_default(),
+                        self.config.expected_size.unwrap_or_default()
+                    );
+                    self.mirrors.record_failure(
+                        &url,
+                        format!(
+                            "reports {} bytes, expected {}",
+                            meta.content_length.unwrap_or_default(),
+                            self.config.expected_size.unwrap_or_default()
+                        ),
+                    );
+                    self.mirrors.disable(&url);
+                }
+                Ok(meta) => {
+                    // Seeds the mirror's score before any segment has finished.
+                    self.mirrors.record_latency(&url, latency);
+                    answers.push((url, meta));
+                }
                 Err(err) => {
                     debug!("HEAD request failed for {}: {err}", url);
-                    continue;
+                    self.mirrors
+                        .record_failure(&url, format!("metadata probe failed: {err}"));
+                }
+            }
+        }
+
+        let lengths: Vec<Option<u64>> = answers
+            .iter()
+            .map(|(_, meta)| meta.content_length)
+            .collect();
+        let Some(chosen) = majority(&lengths) else {
+            return Err(anyhow!("failed to retrieve metadata from all mirrors"));
+        };
+        let (chosen_url, reference) = &answers[chosen];
+        for (url, meta) in &answers {
+            if meta.content_length.is_some() && meta.content_length != reference.content_length {
+                let reason = format!(
+                    "reports {} bytes but most mirrors report {}",
+                    meta.content_length.unwrap_or_default(),
+                    reference.content_length.unwrap_or_default()
+                );
+                warn!("{url} {reason}; skipping mirror");
+                self.mirrors.record_failure(url, reason);
+                self.mirrors.disable(url);
+                continue;
+            }
+            for (name, value, expected) in [
+                ("ETag", &meta.etag, &reference.etag),
+                (
+                    "Last-Modified",
+                    &meta.last_modified,
+                    &reference.last_modified,
+                ),
+            ] {
+                if let (Some(value), Some(expected)) = (value, expected) {
+                    if value != expected {
+                        warn!("{url} reports {name} {value} but {chosen_url} reports {expected}");
+                    }
                 }
             }
         }
-        Err(anyhow!("failed to retrieve metadata from all mirrors"))
+
+        let validators = answers
+            .iter()
+            .filter(|(_, meta)| meta.content_length == reference.content_length)
+            .map(|(url, meta)| Validator {
+                url: url.to_string(),
+                etag: meta.etag.clone(),
+                last_modified: meta.last_modified.clone(),
+            })
+            .collect();
+        let (url, mut meta) = answers.swap_remove(chosen);
+        meta.validators = validators;
+        if meta.content_length.is_none() {
+            meta.content_length = self.config.expected_size;
+        }
+        self.emit(DownloadEvent::MetadataProbed {
+            url,
+            total_bytes: meta.content_length,
+            supports_ranges: meta.supports_ranges,
+        });
+        Ok(meta)
+    }
+
+    fn size_matches(&self, meta: &FileMetadata) -> bool {
+        match (self.config.expected_size, meta.content_length) {
+            (Some(expected), Some(actual)) => expected == actual,
+            _ => true,
+        }
     }
 
     async fn try_head(&self, url: &Url) -> Result<FileMetadata> {
@@ -156,11 +485,16 @@ impl DownloadManager {
                 .map(|v| v.to_ascii_lowercase().contains("bytes"))
                 .unwrap_or(false);
             let filename = filename_from_headers(&response);
+            let etag = header_text(&response, header::ETAG);
+            let last_modified = header_text(&response, header::LAST_MODIFIED);
             if length.is_some() {
                 return Ok(FileMetadata {
                     content_length: length,
                     supports_ranges,
                     filename,
+                    etag,
+                    last_modified,
+                    validators: Vec::new(),
                 });
             }
 
@@ -176,6 +510,9 @@ impl DownloadManager {
                 content_length: length,
                 supports_ranges,
                 filename,
+                etag,
+                last_modified,
+                validators: Vec::new(),
             })
         } else if matches!(
             response.status(),
@@ -196,23 +533,35 @@ impl DownloadManager {
             .await?;
 
         if response.status() == StatusCode::PARTIAL_CONTENT {
-            let total = parse_content_range(response.headers().get(header::CONTENT_RANGE))
-                .ok_or_else(|| anyhow!("missing Content-Range header"))?;
+            // A `*` total leaves the size unknown; such files are streamed.
+            let total = parse_byte_range(response.headers().get(header::CONTENT_RANGE))
+                .ok_or_else(|| anyhow!("missing Content-Range header"))?
+                .total;
             let filename = filename_from_headers(&response);
+            let etag = header_text(&response, header::ETAG);
+            let last_modified = header_text(&response, header::LAST_MODIFIED);
             let _ = response.bytes().await?; // consume body
             Ok(FileMetadata {
-                content_length: Some(total),
+                content_length: total,
                 supports_ranges: true,
                 filename,
+                etag,
+                last_modified,
+                validators: Vec::new(),
             })
         } else if response.status().is_success() {
             let filename = filename_from_headers(&response);
+            let etag = header_text(&response, header::ETAG);
+            let last_modified = header_text(&response, header::LAST_MODIFIED);
             let length = response.content_length();
             let _ = response.bytes().await?;
             Ok(FileMetadata {
                 content_length: length,
                 supports_ranges: false,
                 filename,
+                etag,
+                last_modified,
+                validators: Vec::new(),
             })
         } else {
             Err(anyhow!(
@@ -223,21 +572,69 @@ impl DownloadManager {
         }
     }
 
-    async fn download_segments(&self, metadata: FileMetadata) -> Result<()> {
+    async fn download_segments(
+        &self,
+        metadata: FileMetadata,
+        existing: u64,
+        prefix_hash: Option<Arc<PrefixHasher>>,
+    ) -> Result<u64> {
         let total_size = metadata
             .content_length
             .ok_or_else(|| anyhow!("content length is required for segmented download"))?;
-        let chunk_size = compute_chunk_size(total_size, self.config.initial_segments);
+        // A few large segments to start with; the scheduler ramps parallelism
+        // up from there and segments are split for every new connection, so
+        // the layout follows the connections actually in use.
+        let initial_parallelism = self
+            .config
+            .initial_segments
+            .min(self.config.max_parallelism())
+            .max(1);
+        let chunk_size = compute_chunk_size(total_size, initial_parallelism);
 
-        let file = prepare_output_file(&self.config.output_path, total_size, self.config.resume)?;
-        let file = Arc::new(file);
+        if !self.config.resume || existing < total_size {
+            self.sink.set_len(total_size)?;
+        }
 
-        let partmap =
-            PartMapHandle::load_or_create(self.config.partmap_path.clone(), total_size, chunk_size)
-                .await?;
+        let partmap = if !self.sink.is_persistent() {
+            PartMapHandle::ephemeral(total_size, chunk_size)
+        } else {
+            if !self.config.resume && self.config.partmap_path.exists() {
+                async_fs::remove_file(&self.config.partmap_path).await.ok();
+            }
+            if self.config.resume && self.config.partmap_path.exists() {
+                self.check_unchanged(&metadata.validators, Some(total_size))
+                    .await?;
+            }
+            PartMapHandle::load_or_create(
+                self.config.partmap_path.clone(),
+                total_size,
+                chunk_size,
+                self.resume_source(&metadata)?,
+            )
+            .await?
+            .with_durability(self.config.durability, self.sink.clone())
+        };
         let partmap = Arc::new(partmap);
 
+        let mut pieces = match &self.config.piece_hashes {
+            Some(hashes) => {
+                hashes.check_size(total_size)?;
+                Some(PieceTracker::new(hashes.clone(), total_size))
+            }
+            None => None,
+        };
+        if let Some(tracker) = pieces.as_mut() {
+            // Data from an earlier run is checked before anything new arrives.
+            self.check_pieces(tracker, &partmap).await?;
+        }
+
         let segments = partmap.segments().await;
+        if let Some(hasher) = &prefix_hash {
+            for segment in &segments {
+                let restored = segment.downloaded.min(segment.len());
+                hasher.mark_written(segment.start, segment.start + restored);
+            }
+        }
         let total_completed: u64 = segments
             .iter()
             .map(|segment| segment.downloaded.min(segment.len()))
@@ -257,8 +654,8 @@ impl DownloadManager {
         if pending.is_empty() {
             info!("all segments already downloaded; finalizing");
             partmap.finalize().await?;
-            file.sync_all()?;
-            return Ok(());
+            self.sink.sync()?;
+            return Ok(total_size);
         }
 
         pending.sort_by_key(|s| s.start);
@@ -272,219 +669,689 @@ impl DownloadManager {
             );
         }
 
-        let initial_parallelism = self
-            .config
-            .initial_segments
-            .min(self.config.max_parallelism())
-            .max(1);
         let scheduler = Arc::new(Scheduler::new(
             pending,
             initial_parallelism,
             self.config.max_parallelism(),
         ));
 
-        let mut progress_display = ProgressReporter::spawn(
-            self.config.progress,
-            Some(total_size),
-            total_completed,
-            progress.clone(),
-            Some(scheduler.clone()),
-        );
+        let mut progress_display = self.observer.clone().map(|observer| {
+            ProgressReporter::spawn(
+                observer,
+                Some(total_size),
+                total_completed,
+                progress.clone(),
+                Some(scheduler.clone()),
+            )
+        });
 
-        let client = self.client.clone();
-        let mirrors = self.mirrors.clone();
-        let bandwidth = self.bandwidth.clone();
-        let buffer_pool = BufferPool::new();
+        let ctx = SegmentContext {
+            client: self.client.clone(),
+            mirrors: self.mirrors.clone(),
+            sink: self.sink.clone(),
+            partmap: partmap.clone(),
+            bandwidth: self.bandwidth.clone(),
+            connection_budget: self.connection_budget.clone(),
+            progress: progress.clone(),
+            pool: BufferPool::new(),
+            observer: self.observer.clone(),
+            avoid: Arc::new(StdMutex::new(HashMap::new())),
+            prefix_hash,
+            total_size,
+            in_flight: Arc::new(StdMutex::new(HashMap::new())),
+            validators: Arc::new(
+                metadata
+                    .validators
+                    .iter()
+                    .filter_map(|validator| Some((validator.url.parse().ok()?, validator.clone())))
+                    .collect(),
+            ),
+            on_change: self.config.on_change,
+        };
         let mut join_set: JoinSet<SegmentOutcome> = JoinSet::new();
+        // Endgame requests by the segment whose tail they fetch.
+        let mut races: HashMap<usize, AbortHandle> = HashMap::new();
+        let mut rebalance = interval(REBALANCE_INTERVAL);
+        rebalance.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut last_sample = (progress.load(Ordering::Relaxed), Instant::now());
 
         while scheduler.has_remaining() {
-            while let Some(segment) = scheduler.next_segment() {
-                let client = client.clone();
-                let mirrors = mirrors.clone();
-                let file = file.clone();
-                let partmap = partmap.clone();
-                let bandwidth = bandwidth.clone();
-                let progress = progress.clone();
-                let pool = buffer_pool.clone();
-                join_set.spawn(async move {
-                    match download_segment_with_retry(
-                        client,
-                        mirrors,
-                        file,
-                        partmap,
-                        bandwidth,
-                        progress,
-                        segment.clone(),
-                        pool,
-                    )
-                    .await
-                    {
-                        Ok(stats) => SegmentOutcome::Completed(stats),
-                        Err(err) => SegmentOutcome::Failed(err),
+            loop {
+                while let Some(segment) = scheduler.next_segment() {
+                    let ctx = ctx.clone();
+                    join_set.spawn(async move {
+                        match download_segment_with_retry(ctx, segment).await {
+                            Ok((stats, url)) => SegmentOutcome::Completed(stats, url),
+                            Err(err) => SegmentOutcome::Failed(err),
+                        }
+                    });
+                }
+                match self.split_straggler(&scheduler, &ctx).await {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => {
+                        self.finalize_progress(&mut progress_display).await;
+                        return Err(err);
                     }
-                });
+                }
             }
-
-            match join_set.join_next().await {
-                Some(Ok(SegmentOutcome::Completed(stats))) => {
+            self.start_races(&scheduler, &ctx, &mut races, &mut join_set);
+
+            let next = tokio::select! {
+                next = join_set.join_next() => next,
+                _ = rebalance.tick() => {
+                    let downloaded = progress.load(Ordering::Relaxed);
+                    let active = scheduler.snapshot().active;
+                    let elapsed = last_sample.1.elapsed().as_secs_f64();
+                    if active > 0 && elapsed > 0.0 {
+                        let speed = downloaded.saturating_sub(last_sample.0) as f64 / elapsed;
+                        scheduler.record_throughput(speed / active as f64);
+                    }
+                    last_sample = (downloaded, Instant::now());
+                    continue;
+                }
+            };
+            match next {
+                Some(Ok(SegmentOutcome::Completed(stats, url))) => {
                     let segment_id = stats.id;
+                    if let Some(race) = races.remove(&segment_id) {
+                        race.abort();
+                        scheduler.on_race_finished();
+                    }
                     let segment_bytes = stats.bytes;
                     let segment_duration = stats.duration;
+                    self.emit(DownloadEvent::SegmentCompleted {
+                        id: segment_id,
+                        bytes: segment_bytes,
+                        duration: segment_duration,
+                    });
+                    if let Some(url) = &url {
+                        self.mirrors.record_segment(url, &stats);
+                    }
                     scheduler.on_segment_complete(stats);
                     debug!(
                         "segment {segment_id} completed: {} in {:?}",
                         format_bytes(segment_bytes),
                         segment_duration
                     );
+                    if let Some(tracker) = pieces.as_mut() {
+                        let reset = match self.check_pieces(tracker, &partmap).await {
+                            Ok(reset) => reset,
+                            Err(err) => {
+                                self.finalize_progress(&mut progress_display).await;
+                                return Err(err);
+                            }
+                        };
+                        for segment in reset {
+                            progress.fetch_sub(segment.downloaded, Ordering::Relaxed);
+                            if let Some(hasher) = &ctx.prefix_hash {
+                                hasher.invalidate(segment.start, segment.end + 1);
+                            }
+                            if segment.id == segment_id {
+                                if let Some(url) = &url {
+                                    ctx.avoid.lock().unwrap().insert(segment.id, url.clone());
+                                }
+                            }
+                            scheduler.requeue(SegmentTask {
+                                id: segment.id,
+                                start: segment.start,
+                                end: segment.end,
+                                downloaded: 0,
+                            });
+                        }
+                    }
+                    // The server may have closed the response early.
+                    if let Some(segment) = partmap.segment(segment_id).await {
+                        if segment.remaining() > 0 && !scheduler.is_queued(segment_id) {
+                            warn!(
+                                "segment {segment_id} ended {} short; fetching the rest",
+                                format_bytes(segment.remaining())
+                            );
+                            scheduler.requeue(SegmentTask {
+                                id: segment.id,
+                                start: segment.start,
+                                end: segment.end,
+                                downloaded: segment.downloaded,
+                            });
+                        }
+                    }
+                }
+                Some(Ok(SegmentOutcome::Raced(id, result))) => {
+                    if races.remove(&id).is_some() {
+                        scheduler.on_race_finished();
+                    }
+                    let claimed = match result {
+                        Ok(race) => self.claim_race(&ctx, id, race).await,
+                        Err(err) => {
+                            debug!("endgame request for segment {id} failed: {err}");
+                            Ok(())
+                        }
+                    };
+                    if let Err(err) = claimed {
+                        self.finalize_progress(&mut progress_display).await;
+                        return Err(err);
+                    }
                 }
                 Some(Ok(SegmentOutcome::Failed(err))) => {
-                    Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+                    // Nothing may write to the output once the caller has it.
+                    join_set.shutdown().await;
+                    self.finalize_progress(&mut progress_display).await;
                     return Err(err);
                 }
+                Some(Err(join_err)) if join_err.is_cancelled() => {}
                 Some(Err(join_err)) => {
-                    Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+                    self.finalize_progress(&mut progress_display).await;
                     return Err(anyhow!("segment task panic: {}", join_err));
                 }
                 None => break,
             }
         }
+        for race in races.into_values() {
+            race.abort();
+        }
 
         while let Some(res) = join_set.join_next().await {
             match res {
-                Ok(SegmentOutcome::Completed(stats)) => {
+                Ok(SegmentOutcome::Completed(stats, url)) => {
+                    self.emit(DownloadEvent::SegmentCompleted {
+                        id: stats.id,
+                        bytes: stats.bytes,
+                        duration: stats.duration,
+                    });
+                    if let Some(url) = &url {
+                        self.mirrors.record_segment(url, &stats);
+                    }
                     scheduler.on_segment_complete(stats);
                 }
+                Ok(SegmentOutcome::Raced(..)) => {}
                 Ok(SegmentOutcome::Failed(err)) => {
-                    Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+                    self.finalize_progress(&mut progress_display).await;
                     return Err(err);
                 }
+                Err(join_err) if join_err.is_cancelled() => {}
                 Err(join_err) => {
-                    Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+                    self.finalize_progress(&mut progress_display).await;
                     return Err(anyhow!("segment task panic: {}", join_err));
                 }
             }
         }
 
+        if let Some(tracker) = &pieces {
+            if tracker.unverified() > 0 {
+                self.finalize_progress(&mut progress_display).await;
+                return Err(anyhow!(
+                    "{} pieces could not be verified",
+                    tracker.unverified()
+                ));
+            }
+        }
+        if let Err(err) = ensure_complete(&partmap.segments().await) {
+            self.finalize_progress(&mut progress_display).await;
+            return Err(err);
+        }
         if let Err(err) = partmap.finalize().await {
-            Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+            self.finalize_progress(&mut progress_display).await;
             return Err(err);
         }
-        if let Err(err) = file.sync_all() {
-            Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+        if let Err(err) = self.sink.sync() {
+            self.finalize_progress(&mut progress_display).await;
             return Err(err.into());
         }
 
-        Self::finalize_progress(&mut progress_display, ProgressFinish::Success).await;
+        self.finalize_progress(&mut progress_display).await;
         if self.config.partmap_path.exists() {
             async_fs::remove_file(&self.config.partmap_path).await.ok();
         }
-        Ok(())
+        Ok(total_size)
     }
 
-    async fn download_streaming(&self, metadata: FileMetadata) -> Result<()> {
-        if self.config.partmap_path.exists() {
-            async_fs::remove_file(&self.config.partmap_path).await.ok();
+    /// Compares the size and what the mirrors report now with what the part
+    /// map recorded, and aborts or starts over per
+    /// [`DownloadConfig::on_change`] if the file was replaced. `size` is
+    /// `None` for a stream of unknown length.
+    async fn check_unchanged(&self, validators: &[Validator], size: Option<u64>) -> Result<()> {
+        let path = &self.config.partmap_path;
+        // Streamed downloads record 0; their size is not known to compare.
+        let recorded_size = Some(read_file_size(path)?).filter(|&size| size > 0);
+        let resized = match (recorded_size, size) {
+            (Some(before), Some(now)) if before != now => {
+                Some(format!("size changed from {before} to {now} bytes"))
+            }
+            _ => None,
+        };
+        let recorded = read_resume_source(path)?.validators;
+        let Some(reason) = resized.or_else(|| {
+            validators.iter().find_map(|current| {
+                let before = recorded.iter().find(|before| before.url == current.url)?;
+                Some(format!("{}: {}", current.url, before.changed(current)?))
+            })
+        }) else {
+            return Ok(());
+        };
+        self.on_changed(&reason)?;
+        async_fs::remove_file(&self.config.partmap_path)
+            .await
+            .with_context(|| format!("failed to remove part map {:?}", self.config.partmap_path))
+    }
+
+    /// Fails for [`ChangePolicy::Abort`]; for [`ChangePolicy::Restart`] only
+    /// warns, and the caller starts over.
+    fn on_changed(&self, reason: &str) -> Result<()> {
+        match self.config.on_change {
+            ChangePolicy::Abort => Err(anyhow!(
+                "{:?} changed on the server since the download started ({reason}); \
+                 use --on-change restart to download it again",
+                self.config.output_path
+            )),
+            ChangePolicy::Restart => {
+                warn!(
+                    "{:?} changed on the server since the download started ({reason}); starting over",
+                    self.config.output_path
+                );
+                Ok(())
+            }
         }
+    }
 
-        let mut file = OpenOptions::new()
-            .create(true)
-            .write(true)
-            .read(true)
-            .open(&self.config.output_path)
-            .with_context(|| format!("failed to open {:?}", self.config.output_path))?;
+    /// What the part map records so the download can be resumed by path.
+    fn resume_source(&self, metadata: &FileMetadata) -> Result<ResumeSource> {
+        Ok(ResumeSource {
+            urls: self.config.urls.iter().map(Url::to_string).collect(),
+            output: std::path::absolute(&self.config.output_path)?,
+            checksum: self.config.checksum.as_ref().map(ChecksumSpec::to_input),
+            validators: metadata.validators.clone(),
+        })
+    }
 
-        let mut start_offset = 0u64;
-        let can_resume = self.config.resume && metadata.supports_ranges;
+    async fn verify_all_pieces(&self, hashes: Arc<PieceHashes>, total: u64) -> Result<()> {
+        hashes.check_size(total)?;
+        let sink = self.sink.clone();
+        let bad = tokio::task::spawn_blocking(move || -> Result<Option<usize>> {
+            for index in 0..hashes.pieces.len() {
+                if !hashes.verify(index, sink.as_ref(), total)? {
+                    return Ok(Some(index));
+                }
+            }
+            Ok(None)
+        })
+        .await??;
+        match bad {
+            Some(index) => Err(anyhow!("piece {index} failed verification")),
+            None => Ok(()),
+        }
+    }
+
+    /// Gives half of the in-flight segment with the most bytes left to an
+    /// idle connection. Returns whether a segment was split.
+    async fn split_straggler(&self, scheduler: &Scheduler, ctx: &SegmentContext) -> Result<bool> {
+        if scheduler.idle_slots() == 0 {
+            return Ok(false);
+        }
+        let largest = ctx
+            .in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, entry)| (*id, entry.clone()))
+            .max_by_key(|(_, entry)| entry.progress().1);
+        let Some((id, entry)) = largest else {
+            return Ok(false);
+        };
+        let Some(at) = entry.split_half() else {
+            return Ok(false);
+        };
+        let tail = ctx.partmap.split(id, at).await?;
+        debug!(
+            "split segment {id} at {at}; segment {} takes {}",
+            tail.id,
+            format_bytes(tail.len())
+        );
+        self.emit(DownloadEvent::SegmentSplit {
+            id,
+            new_id: tail.id,
+            at,
+        });
+        scheduler.requeue(SegmentTask {
+            id: tail.id,
+            start: tail.start,
+            end: tail.end,
+            downloaded: 0,
+        });
+        Ok(true)
+    }
 
-        if can_resume {
-            if let Ok(meta) = file.metadata() {
-                start_offset = meta.len();
+    /// Endgame: once no segment is worth splitting, idle connections fetch
+    /// the tails of in-flight segments again from other mirrors. Whichever
+    /// request finishes first wins; see [`DownloadManager::claim_race`].
+    fn start_races(
+        &self,
+        scheduler: &Scheduler,
+        ctx: &SegmentContext,
+        races: &mut HashMap<usize, AbortHandle>,
+        join_set: &mut JoinSet<SegmentOutcome>,
+    ) {
+        if scheduler.idle_slots() == 0 {
+            return;
+        }
+        let mut candidates: Vec<(usize, Arc<InFlight>)> = ctx
+            .in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(id, _)| !races.contains_key(id))
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect();
+        candidates.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.progress().1));
+        for (id, entry) in candidates {
+            if scheduler.idle_slots() == 0 {
+                break;
             }
-            if start_offset > 0 {
-                info!("resuming from byte {start_offset}");
+            let (start, remaining) = entry.progress();
+            if remaining == 0 {
+                continue;
             }
-        } else {
-            if self.config.resume {
-                warn!("server does not allow resume; restarting download");
+            let exclude = HashSet::from([entry.url.clone()]);
+            let Some(url) = ctx.mirrors.next_usable(&exclude, None) else {
+                return;
+            };
+            let end = start + remaining - 1;
+            debug!("endgame: racing bytes {start}-{end} of segment {id} on {url}");
+            let race_ctx = ctx.clone();
+            let handle = join_set.spawn(async move {
+                SegmentOutcome::Raced(id, fetch_race(&race_ctx, url, start, end).await)
+            });
+            races.insert(id, handle);
+            scheduler.on_race_started();
+        }
+    }
+
+    /// Uses the bytes of a finished endgame request if the original request
+    /// has not delivered them yet, stopping it and recording the range as a
+    /// segment of its own.
+    async fn claim_race(&self, ctx: &SegmentContext, id: usize, race: RaceResult) -> Result<()> {
+        let entry = ctx.in_flight.lock().unwrap().get(&id).cloned();
+        let race_end = race.start + race.data.len() as u64 - 1;
+        let Some(at) = entry.and_then(|entry| entry.take_over(race.start, race_end)) else {
+            debug!("segment {id} finished before its endgame request");
+            return Ok(());
+        };
+        let tail = ctx.partmap.split(id, at).await?;
+        let offset = (at - race.start) as usize;
+        let len = tail.len() as usize;
+        let sink = ctx.sink.clone();
+        let prefix_hash = ctx.prefix_hash.clone();
+        tokio::task::spawn_blocking(move || {
+            write_and_hash(
+                sink.as_ref(),
+                prefix_hash.as_deref(),
+                &race.data[offset..offset + len],
+                at,
+            )
+        })
+        .await??;
+        ctx.partmap
+            .record_progress(tail.id, tail.len(), true)
+            .await?;
+        ctx.progress.fetch_add(tail.len(), Ordering::Relaxed);
+        info!(
+            "endgame: {} delivered the last {} of segment {id} first",
+            race.url,
+            format_bytes(tail.len())
+        );
+        let stats = SegmentStats {
+            id: tail.id,
+            bytes: tail.len(),
+            duration: race.duration,
+        };
+        self.mirrors.record_segment(&race.url, &stats);
+        self.emit(DownloadEvent::SegmentSplit {
+            id,
+            new_id: tail.id,
+            at,
+        });
+        self.emit(DownloadEvent::SegmentCompleted {
+            id: tail.id,
+            bytes: stats.bytes,
+            duration: stats.duration,
+        });
+        Ok(())
+    }
+
+    /// Verifies every piece whose data is complete. Segments holding a
+    /// corrupt piece are reset in the part map and returned with the byte
+    /// counts they had, so the caller can fetch them again.
+    async fn check_pieces(
+        &self,
+        tracker: &mut PieceTracker,
+        partmap: &PartMapHandle,
+    ) -> Result<Vec<PartSegment>> {
+        let segments = partmap.segments().await;
+        let mut reset: Vec<PartSegment> = Vec::new();
+        for index in tracker.ready(&segments) {
+            let (start, end) = tracker.range(index);
+            if reset
+                .iter()
+                .any(|segment| segment.start <= end && segment.end >= start)
+            {
+                continue;
+            }
+            let hashes = tracker.hashes();
+            let sink = self.sink.clone();
+            let total = tracker.total();
+            let valid =
+                tokio::task::spawn_blocking(move || hashes.verify(index, sink.as_ref(), total))
+                    .await??;
+            if valid {
+                tracker.mark_verified(index);
+                continue;
+            }
+
+            let failures = tracker.record_failure(index);
+            self.emit(DownloadEvent::PieceFailed { index, start, end });
+            if failures >= MAX_PIECE_FAILURES {
+                return Err(anyhow!(
+                    "piece {index} (bytes {start}-{end}) failed verification {failures} times"
+                ));
+            }
+            warn!("piece {index} (bytes {start}-{end}) is corrupt; downloading it again");
+            for segment in overlapping(&segments, start, end) {
+                if reset.iter().any(|done| done.id == segment.id) {
+                    continue;
+                }
+                partmap.record_progress(segment.id, 0, true).await?;
+                tracker.invalidate(segment.start, segment.end);
+                reset.push(segment.clone());
             }
-            file.set_len(0)?;
         }
+        Ok(reset)
+    }
 
-        file.seek(SeekFrom::Start(start_offset))?;
+    async fn download_streaming(
+        &self,
+        mut metadata: FileMetadata,
+        existing: u64,
+        prefix_hash: Option<Arc<PrefixHasher>>,
+    ) -> Result<u64> {
+        let partmap_path = &self.config.partmap_path;
+        let persistent = self.sink.is_persistent();
+        // A streamed download's part map records a 0-byte file: it keeps the
+        // source and validators, and the output's length is the progress.
+        let mut resumable = self.config.resume
+            && metadata.supports_ranges
+            && existing > 0
+            && persistent
+            && partmap_path.exists()
+            && read_file_size(partmap_path)? == 0;
+        if resumable {
+            self.check_unchanged(&metadata.validators, None).await?;
+            resumable = partmap_path.exists();
+        } else if self.config.resume && existing > 0 {
+            if metadata.supports_ranges {
+                warn!("no part map of a streamed download to resume from; restarting download");
+            } else {
+                warn!("server does not allow resume; restarting download");
+            }
+        }
+        if persistent && !resumable && partmap_path.exists() {
+            async_fs::remove_file(partmap_path).await.ok();
+        }
 
-        let mut request = self.client.get(self.mirrors.primary());
-        if can_resume && start_offset > 0 {
-            request = request.header(header::RANGE, format!("bytes={}-", start_offset));
+        let mut start_offset = if resumable { existing } else { 0 };
+        let url = self.mirrors.next();
+        let mut request = self.client.get(url.clone());
+        if start_offset > 0 {
+            info!("resuming from byte {start_offset}");
+            request = request.header(header::RANGE, format!("bytes={start_offset}-"));
+            let validator = metadata
+                .validators
+                .iter()
+                .find(|validator| validator.url == url.as_str());
+            if let Some(value) = validator.and_then(Validator::if_range) {
+                request = request.header(header::IF_RANGE, value);
+            }
         }
 
+        let _permit = acquire_connection(&self.connection_budget).await?;
         let response = request.send().await?;
         if !response.status().is_success() {
             return Err(anyhow!("download failed with status {}", response.status()));
         }
+        if start_offset > 0 {
+            if response.status() == StatusCode::PARTIAL_CONTENT {
+                let range = parse_byte_range(response.headers().get(header::CONTENT_RANGE));
+                if range.map(|range| range.first) != Some(start_offset) {
+                    return Err(anyhow!(
+                        "{url} did not resume at byte {start_offset}; retry without --resume"
+                    ));
+                }
+            } else {
+                // `If-Range` did not match: the whole new file follows.
+                self.on_changed(&format!(
+                    "{url} answered {} to a resumed request",
+                    response.status()
+                ))?;
+                if let Some(validator) = metadata
+                    .validators
+                    .iter_mut()
+                    .find(|validator| validator.url == url.as_str())
+                {
+                    validator.etag = header_text(&response, header::ETAG);
+                    validator.last_modified = header_text(&response, header::LAST_MODIFIED);
+                }
+                start_offset = 0;
+            }
+        }
+        if start_offset == 0 {
+            self.sink.set_len(0)?;
+        } else if let Some(hasher) = &prefix_hash {
+            hasher.mark_written(0, start_offset);
+        }
+        if persistent {
+            PartMapHandle::load_or_create(
+                partmap_path.clone(),
+                0,
+                1,
+                self.resume_source(&metadata)?,
+            )
+            .await?;
+        }
 
         let bandwidth = self.bandwidth.clone();
         let progress = Arc::new(AtomicU64::new(start_offset));
-        let mut progress_display = ProgressReporter::spawn(
-            self.config.progress,
-            metadata.content_length,
-            start_offset,
-            progress.clone(),
-            None,
-        );
+        let mut progress_display = self.observer.clone().map(|observer| {
+            ProgressReporter::spawn(
+                observer,
+                metadata.content_length,
+                start_offset,
+                progress.clone(),
+                None,
+            )
+        });
 
         let mut stream = response.bytes_stream();
-        let result: Result<()> = async {
+        let result: Result<u64> = async {
+            let mut position = start_offset;
             while let Some(chunk) = stream.next().await {
                 let chunk = chunk?;
                 if let Some(limiter) = &bandwidth {
                     limiter.consume(chunk.len()).await;
                 }
-                file.write_all(chunk.as_ref())?;
+                write_and_hash(
+                    self.sink.as_ref(),
+                    prefix_hash.as_deref(),
+                    chunk.as_ref(),
+                    position,
+                )?;
+                position += chunk.len() as u64;
                 progress.fetch_add(chunk.len() as u64, Ordering::Relaxed);
             }
-            file.sync_all()?;
-            Ok(())
+            self.sink.sync()?;
+            let received = progress.load(Ordering::Relaxed);
+            if let Some(total) = metadata.content_length {
+                if received < total {
+                    return Err(anyhow!(
+                        "connection closed after {received} of {total} bytes; retry with --resume"
+                    ));
+                }
+            }
+            Ok(received)
         }
         .await;
 
         match result {
-            Ok(()) => {
-                Self::finalize_progress(&mut progress_display, ProgressFinish::Success).await;
-                Ok(())
+            Ok(bytes) => {
+                self.finalize_progress(&mut progress_display).await;
+                if persistent && partmap_path.exists() {
+                    async_fs::remove_file(partmap_path).await.ok();
+                }
+                Ok(bytes)
             }
             Err(err) => {
-                Self::finalize_progress(&mut progress_display, ProgressFinish::Failure).await;
+                self.finalize_progress(&mut progress_display).await;
                 Err(err)
             }
         }
     }
 
-    async fn finalize_progress(progress: &mut Option<ProgressReporter>, finish: ProgressFinish) {
+    async fn finalize_progress(&self, progress: &mut Option<ProgressReporter>) {
         if let Some(reporter) = progress.take() {
-            reporter.finish(finish).await;
+            if let Some(snapshot) = reporter.finish().await {
+                *self.last_snapshot.lock().unwrap() = Some(snapshot);
+            }
         }
     }
 }
 
-#[cfg(unix)]
-fn write_all_at(file: &File, buf: &[u8], position: u64) -> io::Result<()> {
-    file.write_all_at(buf, position)
+/// Builds the HTTP client used for all requests of a download.
+pub fn build_client(max_connections_per_host: usize, timeout: Option<Duration>) -> Result<Client> {
+    let mut builder = Client::builder()
+        .user_agent("kdownload/1.4")
+        .redirect(reqwest::redirect::Policy::limited(10))
+        .pool_max_idle_per_host(max_connections_per_host)
+        .pool_idle_timeout(Some(std::time::Duration::from_secs(90)))
+        .tcp_nodelay(true)
+        .http2_adaptive_window(true)
+        .http2_keep_alive_interval(Some(std::time::Duration::from_secs(10)))
+        .http2_keep_alive_timeout(std::time::Duration::from_secs(20));
+    if let Some(timeout) = timeout {
+        builder = builder.timeout(timeout);
+    }
+    builder.build().context("failed to build HTTP client")
 }
 
-#[cfg(windows)]
-fn write_all_at(file: &File, mut buf: &[u8], mut position: u64) -> io::Result<()> {
-    while !buf.is_empty() {
-        let written = file.seek_write(buf, position)?;
-        if written == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::WriteZero,
-                "failed to write segment data",
-            ));
-        }
-        buf = &buf[written..];
-        position += written as u64;
+async fn acquire_connection(
+    budget: &Option<Arc<Semaphore>>,
+) -> Result<Option<OwnedSemaphorePermit>> {
+    match budget {
+        Some(budget) => Ok(Some(
+            budget
+                .clone()
+                .acquire_owned()
+                .await
+                .context("connection budget closed")?,
+        )),
+        None => Ok(None),
     }
-    Ok(())
 }
 
 fn parse_content_length(value: Option<&header::HeaderValue>) -> Option<u64> {
@@ -493,13 +1360,86 @@ fn parse_content_length(value: Option<&header::HeaderValue>) -> Option<u64> {
         .and_then(|s| s.parse().ok())
 }
 
-fn parse_content_range(value: Option<&header::HeaderValue>) -> Option<u64> {
-    let raw = value?.to_str().ok()?;
-    let parts: Vec<&str> = raw.split('/').collect();
-    if parts.len() != 2 {
-        return None;
+/// `Content-Range: bytes <first>-<last>/<total>`; `total` is `None` for `*`.
+#[derive(Debug, PartialEq, Eq)]
+struct ByteRange {
+    first: u64,
+    last: u64,
+    total: Option<u64>,
+}
+
+fn parse_byte_range(value: Option<&header::HeaderValue>) -> Option<ByteRange> {
+    let raw = value?.to_str().ok()?.trim();
+    let (range, total) = raw.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.trim().split_once('-')?;
+    let total = match total.trim() {
+        "*" => None,
+        total => Some(total.parse().ok()?),
+    };
+    Some(ByteRange {
+        first: first.trim().parse().ok()?,
+        last: last.trim().parse().ok()?,
+        total,
+    })
+}
+
+/// A response that does not answer the request, e.g. from a mirror holding
+/// another version of the file. The mirror is not used again.
+#[derive(Debug, thiserror::Error)]
+#[error("mirror {url}: {reason}")]
+struct MirrorMismatch {
+    url: Url,
+    reason: String,
+}
+
+/// A mirror now serves another version of the file: its validators differ
+/// from the probe's. Handled per [`DownloadConfig::on_change`].
+#[derive(Debug, thiserror::Error)]
+#[error("{url}: file changed on the server: {reason}")]
+struct FileChanged {
+    url: Url,
+    reason: String,
+}
+
+/// Checks that a response to `bytes=position-end` of a `total`-byte file
+/// carries exactly that range, or a prefix of it, and returns the body length.
+fn validate_range(
+    response: &reqwest::Response,
+    position: u64,
+    end: u64,
+    total: u64,
+) -> std::result::Result<u64, String> {
+    let headers = response.headers();
+    if response.status() != StatusCode::PARTIAL_CONTENT {
+        // A full response is only usable when the whole file was requested.
+        let length = parse_content_length(headers.get(header::CONTENT_LENGTH));
+        if position != 0 || end + 1 != total {
+            return Err(format!(
+                "ignored the range {position}-{end} and answered {}",
+                response.status()
+            ));
+        }
+        if length.is_some_and(|length| length != total) {
+            return Err(format!(
+                "serves {} bytes, expected {total}",
+                length.unwrap_or_default()
+            ));
+        }
+        return Ok(total);
+    }
+
+    let range = parse_byte_range(headers.get(header::CONTENT_RANGE))
+        .ok_or_else(|| "206 response without a valid Content-Range".to_string())?;
+    if range.first != position || range.last < range.first || range.last > end {
+        return Err(format!(
+            "sent bytes {}-{} for the range {position}-{end}",
+            range.first, range.last
+        ));
     }
-    parts[1].parse().ok()
+    if let Some(actual) = range.total.filter(|&actual| actual != total) {
+        return Err(format!("serves {actual} bytes, expected {total}"));
+    }
+    Ok(range.last - range.first + 1)
 }
 
 fn filename_from_headers(response: &reqwest::Response) -> Option<String> {
@@ -510,6 +1450,39 @@ fn filename_from_headers(response: &reqwest::Response) -> Option<String> {
         .and_then(parse_content_disposition)
 }
 
+fn header_text(response: &reqwest::Response, name: header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Index of the first probe reporting the most common length; mirrors
+/// listed first win ties. Probes without a length only count when no probe
+/// has one.
+fn majority(lengths: &[Option<u64>]) -> Option<usize> {
+    let votes = |length: u64| {
+        lengths
+            .iter()
+            .filter(|&&other| other == Some(length))
+            .count()
+    };
+    let mut best: Option<(usize, usize)> = None;
+    for (idx, length) in lengths.iter().enumerate() {
+        let Some(length) = *length else { continue };
+        let count = votes(length);
+        if best.is_none_or(|(_, best_count)| count > best_count) {
+            best = Some((idx, count));
+        }
+    }
+    match best {
+        Some((idx, _)) => Some(idx),
+        None if lengths.is_empty() => None,
+        None => Some(0),
+    }
+}
+
 fn parse_content_disposition(value: &str) -> Option<String> {
     for part in value.split(';') {
         let part = part.trim();
@@ -528,107 +1501,421 @@ fn compute_chunk_size(total: u64, initial_segments: usize) -> u64 {
         return 1;
     }
     let segments = initial_segments.max(1) as u64;
-    let base = (total + segments - 1) / segments;
-    base.max(MIN_CHUNK_SIZE).min(total)
+    let base = total.div_ceil(segments);
+    base.max(MIN_SPLIT_SIZE).min(total)
+}
+
+/// Shared state handed to every segment task.
+#[derive(Clone)]
+struct SegmentContext {
+    client: Client,
+    mirrors: MirrorPool,
+    sink: Arc<dyn OutputSink>,
+    partmap: Arc<PartMapHandle>,
+    bandwidth: Option<Arc<BandwidthLimiter>>,
+    connection_budget: Option<Arc<Semaphore>>,
+    progress: Arc<AtomicU64>,
+    pool: BufferPool,
+    observer: Option<Arc<dyn ProgressObserver>>,
+    /// Mirror that served corrupt data for a segment, tried last on refetch.
+    avoid: Arc<StdMutex<HashMap<usize, Url>>>,
+    prefix_hash: Option<Arc<PrefixHasher>>,
+    /// Probed size; responses describing another size are rejected.
+    total_size: u64,
+    /// Requests currently transferring, by segment id.
+    in_flight: Arc<StdMutex<HashMap<usize, Arc<InFlight>>>>,
+    /// What each mirror reported about the file when probed.
+    validators: Arc<HashMap<Url, Validator>>,
+    on_change: ChangePolicy,
+}
+
+/// A segment request in progress. The coordinator may lower `end` to hand
+/// the rest of the range to another connection.
+struct InFlight {
+    segment_start: u64,
+    url: Url,
+    range: StdMutex<InFlightRange>,
+    /// Signalled when an endgame request delivered the rest of the range.
+    taken_over: Notify,
 }
 
-fn prepare_output_file(path: &PathBuf, size: u64, resume: bool) -> Result<File> {
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        .read(true)
-        .open(path)
-        .with_context(|| format!("failed to open {:?}", path))?;
+struct InFlightRange {
+    /// Next offset the request will deliver.
+    received: u64,
+    /// Last offset the request may still deliver.
+    end: u64,
+}
+
+impl InFlight {
+    /// Accounts for `len` bytes arriving and returns how many of them are
+    /// still wanted.
+    fn accept(&self, len: usize) -> usize {
+        let mut range = self.range.lock().unwrap();
+        let wanted = (range.end + 1).saturating_sub(range.received);
+        let accepted = wanted.min(len as u64);
+        range.received += accepted;
+        accepted as usize
+    }
+
+    fn end(&self) -> u64 {
+        self.range.lock().unwrap().end
+    }
 
-    if !resume || file.metadata()?.len() < size {
-        preallocate(&file, size)?;
+    /// Next offset and bytes still to come.
+    fn progress(&self) -> (u64, u64) {
+        let range = self.range.lock().unwrap();
+        (
+            range.received,
+            (range.end + 1).saturating_sub(range.received),
+        )
+    }
+
+    /// Halves what is left, returning where the second half starts.
+    fn split_half(&self) -> Option<u64> {
+        let mut range = self.range.lock().unwrap();
+        let remaining = (range.end + 1).saturating_sub(range.received);
+        if remaining < 2 * MIN_SPLIT_SIZE {
+            return None;
+        }
+        let at = range.received + remaining / 2;
+        range.end = at - 1;
+        Some(at)
+    }
+
+    /// Stops the request where it is if `start..=end` covers everything it
+    /// has left, returning the first offset it will not deliver.
+    fn take_over(&self, start: u64, end: u64) -> Option<u64> {
+        let mut range = self.range.lock().unwrap();
+        let at = range.received;
+        if at < start || at > range.end || end < range.end || at <= self.segment_start {
+            return None;
+        }
+        range.end = at - 1;
+        self.taken_over.notify_one();
+        Some(at)
     }
-    Ok(file)
 }
 
-fn preallocate(file: &File, size: u64) -> Result<()> {
-    #[cfg(target_os = "linux")]
-    {
-        if size > 0 {
-            if let Err(err) = fallocate(
-                file.as_raw_fd(),
-                FallocateFlags::FALLOC_FL_KEEP_SIZE,
-                0,
-                size as i64,
-            ) {
-                if err != Errno::ENOTSUP && err != Errno::EINVAL {
-                    return Err(anyhow!("fallocate failed: {err}"));
-                }
-            }
+/// Keeps a request listed in [`SegmentContext::in_flight`] while it runs.
+struct InFlightGuard<'a> {
+    ctx: &'a SegmentContext,
+    id: usize,
+    entry: Arc<InFlight>,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn register(ctx: &'a SegmentContext, id: usize, entry: InFlight) -> Self {
+        let entry = Arc::new(entry);
+        ctx.in_flight.lock().unwrap().insert(id, entry.clone());
+        Self { ctx, id, entry }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        let mut in_flight = self.ctx.in_flight.lock().unwrap();
+        if in_flight
+            .get(&self.id)
+            .is_some_and(|entry| Arc::ptr_eq(entry, &self.entry))
+        {
+            in_flight.remove(&self.id);
         }
-        file.set_len(size)?;
-        return Ok(());
     }
+}
 
-    #[cfg(not(target_os = "linux"))]
-    {
-        file.set_len(size)?;
-        Ok(())
+impl SegmentContext {
+    /// GET for bytes `start..=end` of `url`. `If-Range` makes a mirror whose
+    /// copy changed since the probe answer with the whole new file instead.
+    fn range_request(&self, url: &Url, start: u64, end: u64) -> reqwest::RequestBuilder {
+        let mut builder = self
+            .client
+            .get(url.clone())
+            .header(header::RANGE, format!("bytes={start}-{end}"));
+        if let Some(value) = self.validators.get(url).and_then(Validator::if_range) {
+            builder = builder.header(header::IF_RANGE, value);
+        }
+        builder
+    }
+
+    /// Checks that `response` describes the file the probe saw at `url`.
+    fn check_validator(&self, url: &Url, response: &reqwest::Response) -> Result<(), FileChanged> {
+        let Some(probed) = self.validators.get(url) else {
+            return Ok(());
+        };
+        let current = Validator {
+            url: url.to_string(),
+            etag: header_text(response, header::ETAG),
+            last_modified: header_text(response, header::LAST_MODIFIED),
+        };
+        match probed.changed(&current) {
+            Some(reason) => Err(FileChanged {
+                url: url.clone(),
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    fn emit(&self, event: DownloadEvent) {
+        if let Some(observer) = &self.observer {
+            observer.on_event(&event);
+        }
     }
 }
 
 async fn download_segment_with_retry(
-    client: Client,
-    mirrors: MirrorPool,
-    file: Arc<File>,
-    partmap: Arc<PartMapHandle>,
-    bandwidth: Option<Arc<BandwidthLimiter>>,
-    progress: Arc<AtomicU64>,
+    ctx: SegmentContext,
     segment: SegmentTask,
-    pool: BufferPool,
-) -> Result<SegmentStats> {
+) -> Result<(SegmentStats, Option<Url>)> {
     if segment.remaining_range().is_none() {
-        return Ok(SegmentStats {
+        let stats = SegmentStats {
             id: segment.id,
             bytes: 0,
             duration: Duration::from_secs(0),
-        });
+        };
+        return Ok((stats, None));
     }
 
     let mut attempt = 0usize;
+    let mut previous_url: Option<Url> = None;
+    // Failed attempts per mirror for this segment; a mirror that reaches
+    // `MAX_RETRIES` is not tried for it again.
+    let mut failures: HashMap<Url, usize> = HashMap::new();
+    let mut failed: HashSet<Url> = HashSet::new();
+    let mut backoff = Backoff::default();
+    let avoid = ctx.avoid.lock().unwrap().remove(&segment.id);
     loop {
         attempt += 1;
-        match download_segment_once(
-            client.clone(),
-            mirrors.clone(),
-            file.clone(),
-            partmap.clone(),
-            bandwidth.clone(),
-            progress.clone(),
-            segment.clone(),
-            pool.clone(),
-        )
-        .await
-        {
-            Ok(stats) => return Ok(stats),
-            Err(err) if attempt < MAX_RETRIES => {
-                warn!(
-                    "segment {} failed on attempt {}: {err}; retrying",
-                    segment.id, attempt
-                );
-                sleep(Duration::from_secs(1 << attempt.min(4))).await;
+        let preferred_not = previous_url.as_ref().or(avoid.as_ref());
+        let Some(url) = ctx.mirrors.next_usable(&failed, preferred_not) else {
+            return Err(anyhow!(
+                "segment {} failed on every mirror: {}",
+                segment.id,
+                describe_failures(&ctx.mirrors.failures())
+            ));
+        };
+        if let Some(previous) = previous_url.take() {
+            if previous != url {
+                ctx.emit(DownloadEvent::MirrorSwitched {
+                    segment: segment.id,
+                    from: previous,
+                    to: url.clone(),
+                });
+            }
+        }
+        let err = match download_segment_once(&ctx, &segment, &url).await {
+            Ok(stats) => return Ok((stats, Some(url))),
+            Err(err) => err,
+        };
+        ctx.mirrors.record_failure(&url, err.to_string());
+        if err.is::<FileChanged>() {
+            warn!("{err}; no longer using this mirror");
+            ctx.mirrors.disable(&url);
+            failed.insert(url.clone());
+            ctx.emit(DownloadEvent::MirrorFailed {
+                segment: segment.id,
+                url: url.clone(),
+                error: err.to_string(),
+            });
+            // A restart waits until no mirror has the version the download
+            // started with.
+            if ctx.on_change == ChangePolicy::Abort || ctx.mirrors.usable_count(&failed) == 0 {
+                return Err(err);
+            }
+            previous_url = Some(url);
+            continue;
+        }
+        let count = failures.entry(url.clone()).or_default();
+        *count += 1;
+        let mismatch = err.is::<MirrorMismatch>();
+        if mismatch {
+            warn!("{err}; no longer using this mirror");
+            ctx.mirrors.disable(&url);
+        } else {
+            warn!(
+                "segment {} failed on attempt {} via {url}: {err}",
+                segment.id, attempt
+            );
+        }
+        ctx.emit(DownloadEvent::SegmentRetried {
+            id: segment.id,
+            attempt,
+            error: err.to_string(),
+        });
+        if mismatch || *count >= MAX_RETRIES {
+            failed.insert(url.clone());
+            ctx.emit(DownloadEvent::MirrorFailed {
+                segment: segment.id,
+                url: url.clone(),
+                error: err.to_string(),
+            });
+        }
+        if !mismatch {
+            if let Some(delay) = backoff.on_failure(&ctx.mirrors, &failed, &url) {
+                sleep(delay).await;
             }
-            Err(err) => return Err(err),
+        }
+        previous_url = Some(url);
+    }
+}
+
+/// Mirrors that failed a segment since its last pause. Retries fail over
+/// at once while an untried mirror is left, and back off once every mirror
+/// still in play has failed.
+#[derive(Default)]
+struct Backoff {
+    round: HashSet<Url>,
+    pauses: u32,
+}
+
+impl Backoff {
+    /// Records that `url` failed; returns how long to wait before the next
+    /// attempt if that completed a round. `failed` are mirrors given up on.
+    fn on_failure(
+        &mut self,
+        mirrors: &MirrorPool,
+        failed: &HashSet<Url>,
+        url: &Url,
+    ) -> Option<Duration> {
+        self.round.insert(url.clone());
+        let tried: HashSet<Url> = failed.union(&self.round).cloned().collect();
+        if mirrors.usable_count(failed) == 0 || mirrors.usable_count(&tried) > 0 {
+            return None;
+        }
+        self.round.clear();
+        self.pauses += 1;
+        Some(Duration::from_secs(1 << self.pauses.min(4)))
+    }
+}
+
+/// One `url (N failures): last error` entry per mirror.
+fn describe_failures(failures: &[MirrorFailure]) -> String {
+    failures
+        .iter()
+        .map(|failure| {
+            format!(
+                "{} ({} failures): {}",
+                failure.url, failure.failures, failure.error
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+/// Refuses to finish a download while any segment still misses bytes.
+fn ensure_complete(segments: &[PartSegment]) -> Result<()> {
+    match segments.iter().find(|segment| segment.remaining() > 0) {
+        Some(hole) => Err(anyhow!(
+            "segment {} is incomplete: {} missing at offset {}",
+            hole.id,
+            format_bytes(hole.remaining()),
+            hole.start + hole.downloaded
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Downloads `start..=end` from `url` into memory for an endgame race.
+async fn fetch_race(ctx: &SegmentContext, url: Url, start: u64, end: u64) -> Result<RaceResult> {
+    let _host_permit = ctx.mirrors.acquire(&url).await?;
+    let _permit = acquire_connection(&ctx.connection_budget).await?;
+    let started = Instant::now();
+    let response = ctx.range_request(&url, start, end).send().await?;
+    ctx.mirrors.record_latency(&url, started.elapsed());
+    if !response.status().is_success() {
+        return Err(anyhow!("unexpected status {}", response.status()));
+    }
+    ctx.check_validator(&url, &response)?;
+    let expected_len = validate_range(&response, start, end, ctx.total_size)
+        .map_err(|reason| anyhow!("mirror {url}: {reason}"))?;
+    let mut data = Vec::with_capacity(expected_len as usize);
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        if let Some(limiter) = &ctx.bandwidth {
+            limiter.consume(chunk.len()).await;
+        }
+        data.extend_from_slice(&chunk);
+        if data.len() as u64 > expected_len {
+            return Err(anyhow!("mirror {url} sent more than requested"));
+        }
+    }
+    if (data.len() as u64) < expected_len {
+        return Err(anyhow!(
+            "response ended after {} of {expected_len} bytes",
+            data.len()
+        ));
+    }
+    Ok(RaceResult {
+        start,
+        data,
+        url,
+        duration: started.elapsed(),
+    })
+}
+
+/// Writes `buf` at `position` and feeds it to the incremental checksum.
+fn write_and_hash(
+    sink: &dyn OutputSink,
+    prefix_hash: Option<&PrefixHasher>,
+    buf: &[u8],
+    position: u64,
+) -> std::io::Result<()> {
+    sink.write_all_at(buf, position)?;
+    match prefix_hash {
+        Some(hasher) => hasher.on_write(buf, position, sink),
+        None => Ok(()),
+    }
+}
+
+/// Writes `buffer` at `position` off the async runtime and returns it to the
+/// pool.
+async fn flush_buffer(ctx: &SegmentContext, buffer: Vec<u8>, position: u64) -> Result<()> {
+    if buffer.is_empty() {
+        ctx.pool.recycle(buffer);
+        return Ok(());
+    }
+    let sink = ctx.sink.clone();
+    let pool = ctx.pool.clone();
+    let prefix_hash = ctx.prefix_hash.clone();
+    tokio::task::spawn_blocking(move || {
+        let res = write_and_hash(sink.as_ref(), prefix_hash.as_deref(), &buffer, position);
+        pool.recycle(buffer);
+        res
+    })
+    .await??;
+    Ok(())
+}
+
+/// Last progress a running segment recorded in the part map.
+struct Checkpoint {
+    downloaded: u64,
+    at: Instant,
+}
+
+impl Checkpoint {
+    fn new(downloaded: u64) -> Self {
+        Self {
+            downloaded,
+            at: Instant::now(),
         }
     }
+
+    /// Whether `written` bytes are worth recording.
+    fn due(&self, written: u64) -> bool {
+        written > self.downloaded
+            && (written - self.downloaded >= CHECKPOINT_BYTES
+                || self.at.elapsed() >= CHECKPOINT_INTERVAL)
+    }
 }
 
 async fn download_segment_once(
-    client: Client,
-    mirrors: MirrorPool,
-    file: Arc<File>,
-    partmap: Arc<PartMapHandle>,
-    bandwidth: Option<Arc<BandwidthLimiter>>,
-    progress: Arc<AtomicU64>,
-    segment: SegmentTask,
-    pool: BufferPool,
+    ctx: &SegmentContext,
+    segment: &SegmentTask,
+    url: &Url,
 ) -> Result<SegmentStats> {
-    let segment_state = partmap
+    let segment_state = ctx
+        .partmap
         .segment(segment.id)
         .await
         .ok_or_else(|| anyhow!("segment {} missing in part map", segment.id))?;
@@ -644,80 +1931,621 @@ async fn download_segment_once(
     let position = segment_state.start + segment_state.downloaded;
     let end = segment_state.end;
 
-    let mut builder = client.get(mirrors.next());
-    builder = builder.header(header::RANGE, format!("bytes={}-{}", position, end));
+    ctx.emit(DownloadEvent::SegmentStarted {
+        id: segment.id,
+        url: url.clone(),
+        start: position,
+        end,
+    });
+
+    let builder = ctx.range_request(url, position, end);
 
+    let _host_permit = ctx.mirrors.acquire(url).await?;
+    let _permit = acquire_connection(&ctx.connection_budget).await?;
     let start_time = Instant::now();
     let response = builder.send().await?;
-    if !(response.status() == StatusCode::PARTIAL_CONTENT
-        || (position == 0 && response.status().is_success()))
-    {
+    ctx.mirrors.record_latency(url, start_time.elapsed());
+    if !response.status().is_success() {
         return Err(anyhow!(
             "unexpected status {} for segment {}",
             response.status(),
             segment.id
         ));
     }
+    ctx.check_validator(url, &response)?;
+    let expected_len =
+        validate_range(&response, position, end, ctx.total_size).map_err(|reason| {
+            MirrorMismatch {
+                url: url.clone(),
+                reason,
+            }
+        })?;
 
     let mut downloaded = segment_state.downloaded;
     let mut total_downloaded = 0u64;
-    let mut write_buffer = pool.get();
+    let mut write_buffer = ctx.pool.get();
     let mut buffer_position = position;
+    let mut checkpoint = Checkpoint::new(downloaded);
+    let guard = InFlightGuard::register(
+        ctx,
+        segment.id,
+        InFlight {
+            segment_start: segment_state.start,
+            url: url.clone(),
+            range: StdMutex::new(InFlightRange {
+                received: position,
+                end,
+            }),
+            taken_over: Notify::new(),
+        },
+    );
 
     let mut stream = response.bytes_stream();
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk?;
-        if let Some(limiter) = &bandwidth {
+    loop {
+        let chunk = tokio::select! {
+            chunk = stream.next() => chunk,
+            _ = guard.entry.taken_over.notified() => break,
+        };
+        let Some(chunk) = chunk else { break };
+        let mut chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(err) => {
+                // Journal what arrived, so the retry continues after it
+                // instead of fetching and hashing it again.
+                let received = buffer_position + write_buffer.len() as u64;
+                flush_buffer(ctx, write_buffer, buffer_position).await?;
+                ctx.partmap
+                    .record_progress(segment.id, received - segment_state.start, false)
+                    .await?;
+                return Err(err.into());
+            }
+        };
+        if total_downloaded + chunk.len() as u64 > expected_len {
+            return Err(MirrorMismatch {
+                url: url.clone(),
+                reason: format!("sent more than the {expected_len} bytes of its Content-Range"),
+            }
+            .into());
+        }
+        // The segment may have been split; drop what now belongs to another.
+        let accepted = guard.entry.accept(chunk.len());
+        let cut_short = accepted < chunk.len();
+        chunk.truncate(accepted);
+        if let Some(limiter) = &ctx.bandwidth {
             limiter.consume(chunk.len()).await;
         }
 
         write_buffer.extend_from_slice(&chunk);
 
         if write_buffer.len() >= WRITE_BUFFER_SIZE {
-            let file_clone = file.clone();
-            let buf_to_write = write_buffer;
-            let pos_to_write = buffer_position;
-            let pool_clone = pool.clone();
-            let len = buf_to_write.len() as u64;
-            
-            tokio::task::spawn_blocking(move || {
-                let res = write_all_at(&file_clone, &buf_to_write, pos_to_write);
-                pool_clone.recycle(buf_to_write);
-                res
-            }).await??;
-            
+            let len = write_buffer.len() as u64;
+            flush_buffer(ctx, write_buffer, buffer_position).await?;
             buffer_position += len;
-            write_buffer = pool.get();
+            write_buffer = ctx.pool.get();
+
+            // Only bytes handed to the sink are journaled, never the buffer.
+            let written = buffer_position - segment_state.start;
+            if checkpoint.due(written) {
+                ctx.partmap
+                    .record_progress(segment.id, written, false)
+                    .await?;
+                checkpoint = Checkpoint::new(written);
+            }
         }
 
         downloaded += chunk.len() as u64;
         total_downloaded += chunk.len() as u64;
-        progress.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        ctx.progress
+            .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        if cut_short {
+            break;
+        }
     }
 
-    if !write_buffer.is_empty() {
-        let file_clone = file.clone();
-        let buf_to_write = write_buffer;
-        let pos_to_write = buffer_position;
-        let pool_clone = pool.clone();
-        
-        tokio::task::spawn_blocking(move || {
-            let res = write_all_at(&file_clone, &buf_to_write, pos_to_write);
-            pool_clone.recycle(buf_to_write);
-            res
-        }).await??;
-    } else {
-        pool.recycle(write_buffer);
-    }
+    flush_buffer(ctx, write_buffer, buffer_position).await?;
 
-    let completed = downloaded >= segment.len();
-    partmap
+    let completed = segment_state.start + downloaded > guard.entry.end();
+    drop(guard);
+    ctx.partmap
         .record_progress(segment.id, downloaded, completed)
         .await?;
+    if !completed && total_downloaded == 0 {
+        // Without progress a requeue would loop; let the retry logic decide.
+        return Err(anyhow!(
+            "segment {} response ended without data at offset {}",
+            segment.id,
+            position
+        ));
+    }
 
     Ok(SegmentStats {
         id: segment.id,
         bytes: total_downloaded,
         duration: start_time.elapsed(),
     })
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::ProgressMode;
+    use crate::test_server;
+    use hyper::{Body, Method, Request, Response};
+    use std::sync::atomic::AtomicUsize;
+
+    /// Bytes that differ at every offset, so misplaced data is caught.
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    fn quiet(url: &str, output: &std::path::Path) -> crate::download::DownloadConfigBuilder {
+        DownloadConfig::builder(url)
+            .output(output)
+            .segments(2)
+            .connections(2)
+            .progress(ProgressMode::Quiet)
+    }
+
+    fn range(value: &str) -> Option<ByteRange> {
+        parse_byte_range(Some(&header::HeaderValue::from_str(value).unwrap()))
+    }
+
+    #[test]
+    fn parses_content_range() {
+        assert_eq!(
+            range("bytes 100-199/1000"),
+            Some(ByteRange {
+                first: 100,
+                last: 199,
+                total: Some(1000)
+            })
+        );
+        assert_eq!(range("bytes 0-9/*").unwrap().total, None);
+        assert_eq!(range("bytes */1000"), None);
+        assert_eq!(range("items 0-9/10"), None);
+    }
+
+    fn answer(status: u16, headers: &[(&str, &str)]) -> reqwest::Response {
+        let mut builder = Response::builder().status(status);
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        reqwest::Response::from(builder.body(Vec::new()).unwrap())
+    }
+
+    #[test]
+    fn ranges_must_match_the_request() {
+        let partial = |range: &str| answer(206, &[("content-range", range)]);
+        assert_eq!(
+            validate_range(&partial("bytes 100-199/1000"), 100, 199, 1000),
+            Ok(100)
+        );
+        // A shorter range is fine; the rest is requeued.
+        assert_eq!(
+            validate_range(&partial("bytes 100-149/1000"), 100, 199, 1000),
+            Ok(50)
+        );
+
+        let err = validate_range(&partial("bytes 0-99/1000"), 100, 199, 1000).unwrap_err();
+        assert!(
+            err.contains("sent bytes 0-99 for the range 100-199"),
+            "{err}"
+        );
+        let err = validate_range(&partial("bytes 100-299/1000"), 100, 199, 1000).unwrap_err();
+        assert!(err.contains("sent bytes 100-299"), "{err}");
+        let err = validate_range(&partial("bytes 100-199/2000"), 100, 199, 1000).unwrap_err();
+        assert!(err.contains("serves 2000 bytes, expected 1000"), "{err}");
+        let err = validate_range(&answer(206, &[]), 100, 199, 1000).unwrap_err();
+        assert!(err.contains("without a valid Content-Range"), "{err}");
+    }
+
+    #[test]
+    fn full_answers_only_fit_whole_file_requests() {
+        let full = |length: &str| answer(200, &[("content-length", length)]);
+        let err = validate_range(&full("1000"), 100, 199, 1000).unwrap_err();
+        assert!(err.contains("ignored the range 100-199"), "{err}");
+        assert_eq!(validate_range(&full("1000"), 0, 999, 1000), Ok(1000));
+        let err = validate_range(&full("900"), 0, 999, 1000).unwrap_err();
+        assert!(err.contains("serves 900 bytes"), "{err}");
+    }
+
+    #[test]
+    fn checkpoints_follow_written_bytes_or_time() {
+        let checkpoint = Checkpoint::new(1 << 20);
+        assert!(!checkpoint.due(2 << 20));
+        assert!(checkpoint.due((1 << 20) + CHECKPOINT_BYTES));
+
+        let stale = Checkpoint {
+            downloaded: 0,
+            at: Instant::now() - CHECKPOINT_INTERVAL,
+        };
+        assert!(stale.due(1));
+        assert!(!stale.due(0));
+    }
+
+    #[tokio::test]
+    async fn refuses_verification_it_cannot_perform() {
+        let config = DownloadConfig::builder("http://127.0.0.1:9/file.bin")
+            .output("unused.bin")
+            .checksum(ChecksumSpec::parse(&format!("sha256:{}", "0".repeat(64))).unwrap())
+            .build()
+            .unwrap();
+        let err = DownloadManager::new(config)
+            .unwrap()
+            .with_sink(Arc::new(crate::download::NullSink))
+            .run()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("cannot be read back"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn segments_cut_short_are_requeued() {
+        const LIMIT: u64 = 64 * 1024;
+        let data = Arc::new(sample(300_000));
+        let served = data.clone();
+        let gets = Arc::new(AtomicUsize::new(0));
+        let counted = gets.clone();
+        let base = test_server::spawn(move |req| {
+            let total = served.len() as u64;
+            match test_server::requested_range(req, total) {
+                Some((first, last)) if req.method() == Method::GET => {
+                    counted.fetch_add(1, Ordering::Relaxed);
+                    // Promises the whole range but closes after LIMIT bytes.
+                    let cut = last.min(first + LIMIT - 1);
+                    let mut response =
+                        Response::new(Body::from(served[first as usize..=cut as usize].to_vec()));
+                    *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                    response.headers_mut().insert(
+                        header::CONTENT_RANGE,
+                        format!("bytes {first}-{last}/{total}").parse().unwrap(),
+                    );
+                    response
+                }
+                _ => test_server::file(req, &served),
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let config = quiet(&format!("{base}/file.bin"), &output).build().unwrap();
+        let outcome = DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(outcome.bytes, data.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+        assert!(gets.load(Ordering::Relaxed) >= data.len() / LIMIT as usize);
+    }
+
+    #[tokio::test]
+    async fn broken_responses_keep_what_they_delivered() {
+        const DELIVERED: usize = 100_000;
+        let data = Arc::new(sample(300_000));
+        let digest = crate::checksum::HashAlgorithm::Sha256
+            .hash_reader(&data[..])
+            .unwrap();
+        let served = data.clone();
+        let starts = Arc::new(StdMutex::new(Vec::new()));
+        let seen = starts.clone();
+        let base = test_server::spawn(move |req| {
+            let response = test_server::file(req, &served);
+            let Some((first, _)) = test_server::requested_range(req, served.len() as u64) else {
+                return response;
+            };
+            let mut seen = seen.lock().unwrap();
+            seen.push(first);
+            if seen.len() > 1 {
+                return response;
+            }
+            // The first response breaks off after DELIVERED bytes.
+            let (mut sender, body) = Body::channel();
+            let head = served[..DELIVERED].to_vec();
+            tokio::spawn(async move {
+                sender.send_data(head.into()).await.ok();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                sender.abort();
+            });
+            Response::from_parts(response.into_parts().0, body)
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let config = quiet(&format!("{base}/a/file.bin"), &output)
+            .mirrors([format!("{base}/b/file.bin")])
+            .segments(1)
+            .connections(1)
+            .checksum(ChecksumSpec::parse(&format!("sha256:{}", hex::encode(digest))).unwrap())
+            .build()
+            .unwrap();
+        DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+        // The retry asks for the rest, not for the bytes already written.
+        assert_eq!(*starts.lock().unwrap(), [0, DELIVERED as u64]);
+    }
+
+    /// [`test_server::file`] with an `ETag`.
+    fn tagged(req: &Request<Body>, data: &[u8], etag: &str) -> Response<Body> {
+        let mut response = test_server::file(req, data);
+        response
+            .headers_mut()
+            .insert(header::ETAG, etag.parse().unwrap());
+        response
+    }
+
+    #[tokio::test]
+    async fn files_changing_mid_download_follow_the_policy() {
+        let old = Arc::new(sample(200_000));
+        let new: Arc<Vec<u8>> = Arc::new(old.iter().rev().copied().collect());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let (before, after, counted) = (old.clone(), new.clone(), requests.clone());
+        // Only the probe sees the old file.
+        let base = test_server::spawn(move |req| {
+            if counted.fetch_add(1, Ordering::Relaxed) == 0 {
+                tagged(req, &before, "\"v1\"")
+            } else {
+                tagged(req, &after, "\"v2\"")
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let url = format!("{base}/file.bin");
+
+        let config = quiet(&url, &output).build().unwrap();
+        let err = DownloadManager::new(config)
+            .unwrap()
+            .run()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("changed on the server"), "{err}");
+        assert!(err.contains("--on-change restart"), "{err}");
+
+        std::fs::remove_file(&output).unwrap();
+        requests.store(0, Ordering::Relaxed);
+        let config = quiet(&url, &output)
+            .on_change(ChangePolicy::Restart)
+            .build()
+            .unwrap();
+        let outcome = DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(outcome.bytes, new.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), *new);
+    }
+
+    #[tokio::test]
+    async fn resized_files_follow_the_policy() {
+        let data = Arc::new(sample(120_000));
+        let served = data.clone();
+        let base = test_server::spawn(move |req| test_server::file(req, &served));
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let url = format!("{base}/file.bin");
+        let interrupted = |config: &DownloadConfig| {
+            let source = ResumeSource {
+                urls: vec![url.clone()],
+                output: std::path::absolute(&config.output_path).unwrap(),
+                checksum: None,
+                validators: Vec::new(),
+            };
+            std::fs::write(&config.output_path, vec![7u8; 100_000]).unwrap();
+            PartMapHandle::load_or_create(config.partmap_path.clone(), 100_000, 50_000, source)
+        };
+
+        let config = quiet(&url, &output).resume(true).build().unwrap();
+        interrupted(&config).await.unwrap();
+        let err = DownloadManager::new(config)
+            .unwrap()
+            .run()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("size changed from 100000 to 120000 bytes"),
+            "{err}"
+        );
+
+        let config = quiet(&url, &output)
+            .resume(true)
+            .on_change(ChangePolicy::Restart)
+            .build()
+            .unwrap();
+        interrupted(&config).await.unwrap();
+        DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+    }
+
+    /// Serves `data` under `etag` without revealing its size, so it can only
+    /// be streamed. Ranges are honoured unless `If-Range` names another tag.
+    fn unsized_file(req: &Request<Body>, data: &[u8], etag: &str) -> Response<Body> {
+        if req.method() == Method::HEAD {
+            return test_server::status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        let fresh = req
+            .headers()
+            .get(header::IF_RANGE)
+            .is_none_or(|value| value == etag);
+        let range = test_server::requested_range(req, data.len() as u64).filter(|_| fresh);
+        let mut response = match range {
+            Some((first, last)) => {
+                let mut response =
+                    Response::new(Body::from(data[first as usize..=last as usize].to_vec()));
+                *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+                response.headers_mut().insert(
+                    header::CONTENT_RANGE,
+                    format!("bytes {first}-{last}/*").parse().unwrap(),
+                );
+                response
+            }
+            None => Response::new(Body::from(data.to_vec())),
+        };
+        response
+            .headers_mut()
+            .insert(header::ETAG, etag.parse().unwrap());
+        response
+    }
+
+    /// Leaves `prefix` in the output and a part map recording `etag`, as a
+    /// streamed download interrupted after `prefix.len()` bytes would.
+    async fn interrupted_stream(config: &DownloadConfig, prefix: &[u8], etag: &str) {
+        std::fs::write(&config.output_path, prefix).unwrap();
+        let url = config.urls[0].to_string();
+        let source = ResumeSource {
+            urls: vec![url.clone()],
+            output: std::path::absolute(&config.output_path).unwrap(),
+            checksum: None,
+            validators: vec![Validator {
+                url,
+                etag: Some(etag.to_string()),
+                last_modified: None,
+            }],
+        };
+        PartMapHandle::load_or_create(config.partmap_path.clone(), 0, 1, source)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn streams_resume_only_the_same_file() {
+        let data = Arc::new(sample(50_000));
+        let served = data.clone();
+        let if_ranges = Arc::new(StdMutex::new(Vec::new()));
+        let seen = if_ranges.clone();
+        let base = test_server::spawn(move |req| {
+            if let Some(value) = req.headers().get(header::IF_RANGE) {
+                seen.lock()
+                    .unwrap()
+                    .push(value.to_str().unwrap().to_string());
+            }
+            unsized_file(req, &served, "\"v1\"")
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let config = quiet(&format!("{base}/file.bin"), &output)
+            .resume(true)
+            .build()
+            .unwrap();
+        interrupted_stream(&config, &data[..20_000], "\"v1\"").await;
+        let partmap = config.partmap_path.clone();
+
+        let outcome = DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(outcome.bytes, data.len() as u64);
+        assert_eq!(std::fs::read(&output).unwrap(), *data);
+        assert_eq!(*if_ranges.lock().unwrap(), ["\"v1\""]);
+        assert!(!partmap.exists());
+    }
+
+    #[tokio::test]
+    async fn streams_of_a_changed_file_are_not_spliced() {
+        let old = sample(50_000);
+        let new: Arc<Vec<u8>> = Arc::new(old.iter().rev().copied().collect());
+        let served = new.clone();
+        let base = test_server::spawn(move |req| unsized_file(req, &served, "\"v2\""));
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("file.bin");
+        let url = format!("{base}/file.bin");
+
+        let config = quiet(&url, &output).resume(true).build().unwrap();
+        interrupted_stream(&config, &old[..20_000], "\"v1\"").await;
+        let err = DownloadManager::new(config)
+            .unwrap()
+            .run()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("changed on the server"), "{err}");
+        assert_eq!(std::fs::read(&output).unwrap(), old[..20_000]);
+
+        let config = quiet(&url, &output)
+            .resume(true)
+            .on_change(ChangePolicy::Restart)
+            .build()
+            .unwrap();
+        DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), *new);
+
+        // Replaced between the probe and the request: `If-Range` catches it.
+        let (before, after) = (Arc::new(old.clone()), new.clone());
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counted = requests.clone();
+        let base = test_server::spawn(move |req| {
+            // The HEAD and the range probe still see the old file.
+            if counted.fetch_add(1, Ordering::Relaxed) < 2 {
+                unsized_file(req, &before, "\"v1\"")
+            } else {
+                unsized_file(req, &after, "\"v2\"")
+            }
+        });
+        let url = format!("{base}/file.bin");
+        let config = quiet(&url, &output).resume(true).build().unwrap();
+        interrupted_stream(&config, &old[..20_000], "\"v1\"").await;
+        let err = DownloadManager::new(config)
+            .unwrap()
+            .run()
+            .await
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("answered 200 OK to a resumed request"),
+            "{err}"
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), old[..20_000]);
+
+        let config = quiet(&url, &output)
+            .resume(true)
+            .on_change(ChangePolicy::Restart)
+            .build()
+            .unwrap();
+        interrupted_stream(&config, &old[..20_000], "\"v1\"").await;
+        requests.store(0, Ordering::Relaxed);
+        DownloadManager::new(config).unwrap().run().await.unwrap();
+        assert_eq!(std::fs::read(&output).unwrap(), *new);
+    }
+
+    #[test]
+    fn holes_keep_a_download_from_finishing() {
+        let segment = |id, start, end, downloaded| PartSegment {
+            id,
+            start,
+            end,
+            downloaded,
+        };
+        assert!(ensure_complete(&[segment(0, 0, 99, 100), segment(1, 100, 199, 100)]).is_ok());
+        let err = ensure_complete(&[segment(0, 0, 99, 100), segment(1, 100, 199, 40)])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("segment 1 is incomplete"), "{err}");
+        assert!(err.contains("offset 140"), "{err}");
+    }
+
+    #[test]
+    fn backs_off_once_every_mirror_failed() {
+        let a = Url::parse("http://a.example/file").unwrap();
+        let b = Url::parse("http://b.example/file").unwrap();
+        let mirrors = MirrorPool::new(vec![a.clone(), b.clone()], 4);
+        let mut failed = HashSet::new();
+        let mut backoff = Backoff::default();
+        assert_eq!(backoff.on_failure(&mirrors, &failed, &a), None);
+        assert_eq!(
+            backoff.on_failure(&mirrors, &failed, &b),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(backoff.on_failure(&mirrors, &failed, &b), None);
+        assert_eq!(
+            backoff.on_failure(&mirrors, &failed, &a),
+            Some(Duration::from_secs(4))
+        );
+
+        // With one mirror given up on, every failure of the other pauses.
+        failed.insert(a.clone());
+        assert_eq!(
+            backoff.on_failure(&mirrors, &failed, &b),
+            Some(Duration::from_secs(8))
+        );
+        failed.insert(b.clone());
+        assert_eq!(backoff.on_failure(&mirrors, &failed, &b), None);
+    }
+
+    #[test]
+    fn majority_picks_the_most_common_length() {
+        assert_eq!(majority(&[Some(10), Some(20), Some(20)]), Some(1));
+        assert_eq!(majority(&[Some(10), Some(20)]), Some(0));
+        assert_eq!(majority(&[None, Some(20)]), Some(1));
+        assert_eq!(majority(&[None, None]), Some(0));
+        assert_eq!(majority(&[]), None);
+    }
+}
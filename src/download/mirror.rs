@@ -1,34 +1,450 @@
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
+use anyhow::{Context, Result};
+use log::warn;
 use reqwest::Url;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+use crate::scheduler::SegmentStats;
+
+/// Weight of a new sample in the moving averages.
+const EWMA_ALPHA: f64 = 0.3;
+/// Failures in a row after which a mirror is benched.
+const MAX_CONSECUTIVE_FAILURES: u32 = 3;
+const MIRROR_COOLDOWN: Duration = Duration::from_secs(30);
+/// Floor for the success factor, so a recovered mirror is still sampled.
+const MIN_SUCCESS_FACTOR: f64 = 0.05;
+
+/// Failed requests against one mirror, as listed in
+/// [`DownloadOutcome::mirror_failures`](crate::DownloadOutcome::mirror_failures).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MirrorFailure {
+    pub url: Url,
+    pub failures: usize,
+    /// The most recent error.
+    pub error: String,
+}
+
+/// Connection limits per host. Downloads given the same `HostLimits` share
+/// each host's budget instead of getting a full one apiece.
+#[derive(Clone)]
+pub struct HostLimits {
+    per_host: usize,
+    hosts: Arc<Mutex<HashMap<String, Arc<Semaphore>>>>,
+}
+
+impl HostLimits {
+    /// Allows `per_host` connections to each host.
+    pub fn new(per_host: usize) -> Self {
+        Self {
+            per_host: per_host.max(1),
+            hosts: Arc::default(),
+        }
+    }
+
+    fn permits(&self, url: &Url) -> Arc<Semaphore> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .entry(host_key(url))
+            .or_insert_with(|| Arc::new(Semaphore::new(self.per_host)))
+            .clone()
+    }
+}
+
+/// What the pool has learned about one mirror.
+#[derive(Debug, Default)]
+struct MirrorHealth {
+    /// Moving average of segment throughput in bytes per second.
+    throughput: Option<f64>,
+    /// Moving average of the time until response headers arrive.
+    latency: Option<Duration>,
+    /// Moving average of request outcomes, 1.0 for failures.
+    error_rate: f64,
+    consecutive_failures: u32,
+    benched_until: Option<Instant>,
+    /// Share from the configured order (1, 1/2, 1/3, ...), standing in for
+    /// throughput until the mirror has finished a segment.
+    preference: f64,
+    /// Running weight for smooth weighted round-robin.
+    current: f64,
+}
+
+impl MirrorHealth {
+    fn record_outcome(&mut self, failed: bool) {
+        let sample = if failed { 1.0 } else { 0.0 };
+        self.error_rate += EWMA_ALPHA * (sample - self.error_rate);
+    }
+
+    fn benched(&self, now: Instant) -> bool {
+        self.benched_until.is_some_and(|until| until > now)
+    }
+
+    /// Relative share of new segments; `default_throughput` stands in for
+    /// mirrors that have not finished a segment yet.
+    fn score(&self, default_throughput: f64) -> f64 {
+        let throughput = self
+            .throughput
+            .unwrap_or(default_throughput * self.preference);
+        let latency = self.latency.map_or(0.0, |latency| latency.as_secs_f64());
+        let success = (1.0 - self.error_rate).max(MIN_SUCCESS_FACTOR);
+        (throughput * success / (1.0 + latency)).max(f64::MIN_POSITIVE)
+    }
+}
+
+/// Mirrors of one file, most preferred first. New requests go to mirrors in
+/// proportion to their measured throughput, latency and error rate, and by
+/// their order until measured; mirrors that keep failing are benched for a
+/// while. Connections are limited per host, so mirrors on
+/// one host share a budget.
 #[derive(Clone)]
 pub struct MirrorPool {
     urls: Arc<Vec<Url>>,
-    cursor: Arc<AtomicUsize>,
+    disabled: Arc<Vec<AtomicBool>>,
+    /// Connection permits of each mirror's host, shared by mirrors on it.
+    hosts: Arc<Vec<Arc<Semaphore>>>,
+    health: Arc<Mutex<Vec<MirrorHealth>>>,
+    failures: Arc<Mutex<Vec<MirrorFailure>>>,
 }
 
 impl MirrorPool {
-    pub fn new(urls: Vec<Url>) -> Self {
+    /// Pool over `urls`, allowing `per_host` connections to each host.
+    pub fn new(urls: Vec<Url>, per_host: usize) -> Self {
+        Self::with_host_limits(urls, &HostLimits::new(per_host))
+    }
+
+    /// Pool over `urls` drawing connections from `limits`.
+    pub fn with_host_limits(urls: Vec<Url>, limits: &HostLimits) -> Self {
         assert!(!urls.is_empty(), "at least one URL required");
+        let hosts = urls.iter().map(|url| limits.permits(url)).collect();
         Self {
-            cursor: Arc::new(AtomicUsize::new(0)),
+            hosts: Arc::new(hosts),
+            disabled: Arc::new(urls.iter().map(|_| AtomicBool::new(false)).collect()),
+            health: Arc::new(Mutex::new(
+                (0..urls.len())
+                    .map(|rank| MirrorHealth {
+                        preference: 1.0 / (rank + 1) as f64,
+                        ..MirrorHealth::default()
+                    })
+                    .collect(),
+            )),
             urls: Arc::new(urls),
+            failures: Arc::default(),
         }
     }
 
+    /// Next mirror by score, skipping disabled mirrors unless every mirror
+    /// is disabled.
     pub fn next(&self) -> Url {
-        let idx = self.cursor.fetch_add(1, Ordering::Relaxed);
-        let urls = self.urls.as_ref();
-        urls[idx % urls.len()].clone()
+        self.next_usable(&HashSet::new(), None)
+            .unwrap_or_else(|| self.urls[0].clone())
+    }
+
+    /// Next enabled mirror that is not in `exclude`, by score. Mirrors not
+    /// benched, with a free connection on their host, and other than
+    /// `previous` are preferred, in that order. `None` once every mirror is
+    /// excluded or disabled.
+    pub fn next_usable(&self, exclude: &HashSet<Url>, previous: Option<&Url>) -> Option<Url> {
+        let now = Instant::now();
+        let mut health = self.health.lock().unwrap();
+        let usable: Vec<usize> = (0..self.urls.len())
+            .filter(|&idx| {
+                !self.disabled[idx].load(Ordering::Relaxed) && !exclude.contains(&self.urls[idx])
+            })
+            .collect();
+        let mut candidates = usable;
+        prefer(&mut candidates, |idx| !health[idx].benched(now));
+        prefer(&mut candidates, |idx| {
+            self.hosts[idx].available_permits() > 0
+        });
+        prefer(&mut candidates, |idx| Some(&self.urls[idx]) != previous);
+        let idx = pick_weighted(&mut health, &candidates)?;
+        Some(self.urls[idx].clone())
+    }
+
+    /// Enabled mirrors not in `exclude`.
+    pub fn usable_count(&self, exclude: &HashSet<Url>) -> usize {
+        self.urls
+            .iter()
+            .zip(self.disabled.iter())
+            .filter(|(url, disabled)| !disabled.load(Ordering::Relaxed) && !exclude.contains(*url))
+            .count()
     }
 
-    pub fn primary(&self) -> Url {
-        self.urls[0].clone()
+    /// Waits for a free connection on the host of `url`. The connection
+    /// counts against the host until the permit is dropped.
+    pub async fn acquire(&self, url: &Url) -> Result<Option<OwnedSemaphorePermit>> {
+        let Some(idx) = self.urls.iter().position(|candidate| candidate == url) else {
+            return Ok(None);
+        };
+        let permit = self.hosts[idx]
+            .clone()
+            .acquire_owned()
+            .await
+            .context("host connection limit closed")?;
+        Ok(Some(permit))
     }
 
     pub fn all(&self) -> Vec<Url> {
         self.urls.as_ref().clone()
     }
+
+    /// Records the time `url` took to answer with response headers.
+    pub fn record_latency(&self, url: &Url, latency: Duration) {
+        self.update(url, |health| {
+            health.latency = Some(match health.latency {
+                Some(average) => average.mul_f64(1.0 - EWMA_ALPHA) + latency.mul_f64(EWMA_ALPHA),
+                None => latency,
+            });
+        });
+    }
+
+    /// Feeds a finished segment into the score of the mirror that served it.
+    pub fn record_segment(&self, url: &Url, stats: &SegmentStats) {
+        if stats.bytes == 0 || stats.duration.is_zero() {
+            return;
+        }
+        let throughput = stats.throughput();
+        self.update(url, |health| {
+            health.record_outcome(false);
+            health.consecutive_failures = 0;
+            health.throughput = Some(match health.throughput {
+                Some(average) => average + EWMA_ALPHA * (throughput - average),
+                None => throughput,
+            });
+        });
+    }
+
+    /// Counts a failed request against `url` for its score and the final
+    /// report, benching the mirror after repeated failures.
+    pub fn record_failure(&self, url: &Url, error: impl ToString) {
+        let error = error.to_string();
+        let can_bench = self.urls.len() > 1;
+        self.update(url, |health| {
+            health.record_outcome(true);
+            health.consecutive_failures += 1;
+            if can_bench && health.consecutive_failures >= MAX_CONSECUTIVE_FAILURES {
+                warn!(
+                    "{url} failed {} times in a row; benching it for {:?}",
+                    health.consecutive_failures, MIRROR_COOLDOWN
+                );
+                health.consecutive_failures = 0;
+                health.benched_until = Some(Instant::now() + MIRROR_COOLDOWN);
+            }
+        });
+        let mut failures = self.failures.lock().unwrap();
+        match failures.iter_mut().find(|failure| &failure.url == url) {
+            Some(failure) => {
+                failure.failures += 1;
+                failure.error = error;
+            }
+            None => failures.push(MirrorFailure {
+                url: url.clone(),
+                failures: 1,
+                error,
+            }),
+        }
+    }
+
+    /// Every mirror that failed at least once, in order of first failure.
+    pub fn failures(&self) -> Vec<MirrorFailure> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    /// Stops handing out `url`, e.g. because it serves a different file.
+    pub fn disable(&self, url: &Url) {
+        for (candidate, disabled) in self.urls.iter().zip(self.disabled.iter()) {
+            if candidate == url {
+                disabled.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Makes every mirror usable again, e.g. when a download starts over.
+    pub fn enable_all(&self) {
+        for disabled in self.disabled.iter() {
+            disabled.store(false, Ordering::Relaxed);
+        }
+    }
+
+    fn update(&self, url: &Url, apply: impl FnOnce(&mut MirrorHealth)) {
+        if let Some(idx) = self.urls.iter().position(|candidate| candidate == url) {
+            apply(&mut self.health.lock().unwrap()[idx]);
+        }
+    }
+}
+
+/// Distinct hosts among `urls`; each gets its own connection limit.
+pub(crate) fn host_count(urls: &[Url]) -> usize {
+    let hosts: HashSet<String> = urls.iter().map(host_key).collect();
+    hosts.len()
+}
+
+fn host_key(url: &Url) -> String {
+    format!(
+        "{}:{}",
+        url.host_str().unwrap_or_default(),
+        url.port_or_known_default().unwrap_or_default()
+    )
+}
+
+/// Keeps the candidates matching `keep`, unless none do.
+fn prefer(candidates: &mut Vec<usize>, keep: impl Fn(usize) -> bool) {
+    if candidates.iter().any(|&idx| keep(idx)) {
+        candidates.retain(|&idx| keep(idx));
+    }
+}
+
+/// Smooth weighted round-robin over `candidates`: deterministic, and every
+/// mirror gets its share of picks spread evenly over time.
+fn pick_weighted(health: &mut [MirrorHealth], candidates: &[usize]) -> Option<usize> {
+    // Unmeasured mirrors are assumed as fast as the best one, so they get
+    // sampled early.
+    let default_throughput = candidates
+        .iter()
+        .filter_map(|&idx| health[idx].throughput)
+        .fold(1.0, f64::max);
+    let scores: Vec<f64> = candidates
+        .iter()
+        .map(|&idx| health[idx].score(default_throughput))
+        .collect();
+    let total: f64 = scores.iter().sum();
+    let mut best: Option<usize> = None;
+    for (&idx, score) in candidates.iter().zip(&scores) {
+        health[idx].current += score;
+        if best.is_none_or(|best| health[idx].current > health[best].current) {
+            best = Some(idx);
+        }
+    }
+    let best = best?;
+    health[best].current -= total;
+    Some(best)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::timeout;
+
+    fn pool(count: usize) -> MirrorPool {
+        MirrorPool::new(
+            (0..count)
+                .map(|i| Url::parse(&format!("http://mirror{i}/file")).unwrap())
+                .collect(),
+            4,
+        )
+    }
+
+    fn stats(bytes: u64, millis: u64) -> SegmentStats {
+        SegmentStats {
+            id: 0,
+            bytes,
+            duration: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn next_usable_skips_failed_and_disabled_mirrors() {
+        let pool = pool(3);
+        let urls = pool.all();
+        pool.disable(&urls[0]);
+        let mut exclude = HashSet::new();
+        assert_eq!(
+            pool.next_usable(&exclude, Some(&urls[1])),
+            Some(urls[2].clone())
+        );
+        exclude.insert(urls[2].clone());
+        // Only the previous mirror is left, so it is retried.
+        assert_eq!(
+            pool.next_usable(&exclude, Some(&urls[1])),
+            Some(urls[1].clone())
+        );
+        assert_eq!(pool.usable_count(&exclude), 1);
+        exclude.insert(urls[1].clone());
+        assert_eq!(pool.next_usable(&exclude, None), None);
+    }
+
+    #[test]
+    fn failures_are_aggregated_per_mirror() {
+        let pool = pool(2);
+        let urls = pool.all();
+        pool.record_failure(&urls[1], "timeout");
+        pool.record_failure(&urls[1], "reset");
+        let failures = pool.failures();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            (failures[0].failures, failures[0].error.as_str()),
+            (2, "reset")
+        );
+    }
+
+    #[test]
+    fn faster_mirrors_get_more_segments() {
+        let pool = pool(2);
+        let urls = pool.all();
+        pool.record_segment(&urls[0], &stats(30 << 20, 1000));
+        pool.record_segment(&urls[1], &stats(10 << 20, 1000));
+        let picks = (0..40).filter(|_| pool.next() == urls[0]).count();
+        assert_eq!(picks, 30);
+    }
+
+    #[test]
+    fn configured_order_leads_until_measured() {
+        let pool = pool(2);
+        let urls = pool.all();
+        assert_eq!(pool.next(), urls[0]);
+        let picks = (1..30).filter(|_| pool.next() == urls[0]).count();
+        assert_eq!(picks + 1, 20);
+
+        // Measured throughput takes over from the configured order.
+        pool.record_segment(&urls[0], &stats(10 << 20, 1000));
+        pool.record_segment(&urls[1], &stats(10 << 20, 1000));
+        let picks = (0..40).filter(|_| pool.next() == urls[0]).count();
+        assert_eq!(picks, 20);
+    }
+
+    #[test]
+    fn failing_mirrors_are_benched() {
+        let pool = pool(2);
+        let urls = pool.all();
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            pool.record_failure(&urls[0], "HTTP 503");
+        }
+        assert!((0..10).all(|_| pool.next() == urls[1]));
+        // A benched mirror is still used when nothing else is left.
+        pool.disable(&urls[1]);
+        assert_eq!(pool.next(), urls[0]);
+    }
+
+    #[tokio::test]
+    async fn pools_share_host_limits() {
+        let limits = HostLimits::new(1);
+        let url = |path: &str| Url::parse(&format!("http://a{path}")).unwrap();
+        let first = MirrorPool::with_host_limits(vec![url("/one")], &limits);
+        let second = MirrorPool::with_host_limits(vec![url("/two")], &limits);
+        let held = first.acquire(&url("/one")).await.unwrap();
+        let two = url("/two");
+        assert!(timeout(Duration::from_millis(50), second.acquire(&two))
+            .await
+            .is_err());
+        drop(held);
+        assert!(second.acquire(&two).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn mirrors_on_one_host_share_its_connections() {
+        let urls: Vec<Url> = ["http://a/file", "http://a:80/other", "http://b/file"]
+            .iter()
+            .map(|url| Url::parse(url).unwrap())
+            .collect();
+        assert_eq!(host_count(&urls), 2);
+        let pool = MirrorPool::new(urls.clone(), 1);
+        let _held = pool.acquire(&urls[0]).await.unwrap();
+        // Host a is busy, so both picks go to b.
+        assert_eq!(pool.next(), urls[2]);
+        assert_eq!(pool.next(), urls[2]);
+    }
 }
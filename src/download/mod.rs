@@ -2,15 +2,31 @@ mod bandwidth;
 mod manager;
 mod mirror;
 mod partmap;
+mod piece_tracker;
+mod prefix_hash;
+mod sink;
 
-pub use manager::DownloadManager;
+pub use bandwidth::BandwidthLimiter;
+pub use manager::{build_client, DownloadManager};
+pub use mirror::{HostLimits, MirrorFailure};
+pub use partmap::{read_resume_source, Durability, ResumeSource, Validator};
+pub use sink::{FileSink, MemorySink, NullSink, OutputSink, SinkReader};
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
 
+use anyhow::{anyhow, Context, Result};
 use reqwest::Url;
 
 use crate::checksum::ChecksumSpec;
+use crate::pieces::PieceHashes;
+use crate::signature::SignatureSpec;
+use crate::util::{derive_partmap_path, infer_output_path};
+
+const DEFAULT_CONNECTIONS: usize = 32;
+const DEFAULT_SEGMENTS: usize = 4;
+const SAFE_CONNECTION_CAP: usize = 64;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProgressMode {
@@ -19,6 +35,29 @@ pub enum ProgressMode {
     Json,
 }
 
+/// What a resumed download does when the file changed on the server since
+/// it started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChangePolicy {
+    /// Fail and keep the partial data.
+    #[default]
+    Abort,
+    /// Discard the partial data and download the new file.
+    Restart,
+}
+
+impl ChangePolicy {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "abort" => Ok(Self::Abort),
+            "restart" => Ok(Self::Restart),
+            other => Err(anyhow!(
+                "unknown change policy {other:?}; expected abort or restart"
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct DownloadConfig {
     pub urls: Vec<Url>,
@@ -27,17 +66,315 @@ pub struct DownloadConfig {
     pub resume: bool,
     pub initial_segments: usize,
     pub max_connections_per_host: usize,
+    /// Limit on connections across all mirrors; by default only the
+    /// per-host limit applies.
+    pub max_connections: Option<usize>,
     pub unsafe_connection_cap: usize,
     pub timeout: Option<Duration>,
     pub bandwidth_limit: Option<u64>,
-    pub expected_sha256: Option<ChecksumSpec>,
+    /// Known size of the file; mirrors reporting another length are skipped.
+    pub expected_size: Option<u64>,
+    /// Whole-file digest checked once the download completes.
+    pub checksum: Option<ChecksumSpec>,
+    /// Detached signature checked after the checksum; output failing it is
+    /// moved aside with an `.unverified` suffix.
+    pub signature: Option<SignatureSpec>,
+    /// Verified per segment; corrupt ranges are downloaded again.
+    pub piece_hashes: Option<Arc<PieceHashes>>,
+    /// When the output and the `.kdl.partmap` journal are synced to disk.
+    pub durability: Durability,
+    /// Applied when resuming a file that changed on the server.
+    pub on_change: ChangePolicy,
     pub progress: ProgressMode,
 }
 
 impl DownloadConfig {
-    pub fn max_parallelism(&self) -> usize {
+    /// Starts a builder for downloading `url`. Further URLs can be added as
+    /// mirrors of the same file.
+    pub fn builder(url: impl Into<String>) -> DownloadConfigBuilder {
+        DownloadConfigBuilder::new(url)
+    }
+
+    /// Connections allowed to each host serving the file.
+    pub fn connections_per_host(&self) -> usize {
         self.max_connections_per_host
             .min(self.unsafe_connection_cap)
             .max(1)
     }
+
+    /// Connections allowed in total: the per-host limit for every distinct
+    /// host, capped by [`DownloadConfig::max_connections`].
+    pub fn max_parallelism(&self) -> usize {
+        let total = self.connections_per_host() * mirror::host_count(&self.urls).max(1);
+        self.max_connections
+            .map_or(total, |limit| total.min(limit))
+            .max(1)
+    }
+}
+
+/// Result of a successful [`DownloadManager::run`].
+#[derive(Debug, Clone)]
+pub struct DownloadOutcome {
+    /// Final size of the output in bytes.
+    pub bytes: u64,
+    /// Wall-clock time spent in `run`, including probing and verification.
+    pub duration: Duration,
+    /// Path the data was written to, if the sink is backed by a file.
+    pub path: Option<PathBuf>,
+    /// Hex-encoded digest, present when a checksum was verified.
+    pub digest: Option<String>,
+    /// Mirrors that failed at least one request, with the last error.
+    pub mirror_failures: Vec<MirrorFailure>,
+}
+
+/// Validating builder for [`DownloadConfig`].
+///
+/// Applies the same defaults and connection limits as the command-line
+/// interface, so embedders and the CLI behave identically.
+#[derive(Debug, Clone)]
+pub struct DownloadConfigBuilder {
+    urls: Vec<String>,
+    output: Option<PathBuf>,
+    resume: bool,
+    segments: usize,
+    connections: usize,
+    max_connections: Option<usize>,
+    unsafe_conn: Option<usize>,
+    timeout: Option<Duration>,
+    bandwidth_limit: Option<u64>,
+    size: Option<u64>,
+    checksum: Option<ChecksumSpec>,
+    signature: Option<SignatureSpec>,
+    piece_hashes: Option<PieceHashes>,
+    durability: Durability,
+    on_change: ChangePolicy,
+    progress: ProgressMode,
+}
+
+impl DownloadConfigBuilder {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            urls: vec![url.into()],
+            output: None,
+            resume: false,
+            segments: DEFAULT_SEGMENTS,
+            connections: DEFAULT_CONNECTIONS,
+            max_connections: None,
+            unsafe_conn: None,
+            timeout: None,
+            bandwidth_limit: None,
+            size: None,
+            checksum: None,
+            signature: None,
+            piece_hashes: None,
+            durability: Durability::default(),
+            on_change: ChangePolicy::default(),
+            progress: ProgressMode::Quiet,
+        }
+    }
+
+    /// Registers an additional URL serving the same file.
+    pub fn mirror(mut self, url: impl Into<String>) -> Self {
+        self.urls.push(url.into());
+        self
+    }
+
+    pub fn mirrors<I, S>(mut self, urls: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.urls.extend(urls.into_iter().map(Into::into));
+        self
+    }
+
+    /// Output file, or directory to place the file in.
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.output = Some(path.into());
+        self
+    }
+
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    pub fn segments(mut self, segments: usize) -> Self {
+        self.segments = segments;
+        self
+    }
+
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Caps connections across all mirrors, on top of the per-host limit.
+    pub fn max_connections(mut self, connections: usize) -> Self {
+        self.max_connections = Some(connections);
+        self
+    }
+
+    /// Lifts the safety cap on connections up to `cap`.
+    pub fn unsafe_connection_cap(mut self, cap: usize) -> Self {
+        self.unsafe_conn = Some(cap);
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Bandwidth limit in bytes per second.
+    pub fn bandwidth_limit(mut self, bytes_per_sec: u64) -> Self {
+        self.bandwidth_limit = Some(bytes_per_sec);
+        self
+    }
+
+    /// Authoritative file size, e.g. from a metalink.
+    pub fn size(mut self, bytes: u64) -> Self {
+        self.size = Some(bytes);
+        self
+    }
+
+    pub fn checksum(mut self, spec: ChecksumSpec) -> Self {
+        self.checksum = Some(spec);
+        self
+    }
+
+    pub fn signature(mut self, spec: SignatureSpec) -> Self {
+        self.signature = Some(spec);
+        self
+    }
+
+    pub fn piece_hashes(mut self, hashes: PieceHashes) -> Self {
+        self.piece_hashes = Some(hashes);
+        self
+    }
+
+    /// Sync policy for resume data; defaults to [`Durability::Balanced`].
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// What a resume does when the file changed on the server; defaults to
+    /// [`ChangePolicy::Abort`].
+    pub fn on_change(mut self, policy: ChangePolicy) -> Self {
+        self.on_change = policy;
+        self
+    }
+
+    /// Progress rendering; defaults to [`ProgressMode::Quiet`].
+    pub fn progress(mut self, mode: ProgressMode) -> Self {
+        self.progress = mode;
+        self
+    }
+
+    pub fn build(self) -> Result<DownloadConfig> {
+        let mut urls = vec![];
+        for url in &self.urls {
+            let parsed = Url::parse(url).with_context(|| format!("invalid URL: {url}"))?;
+            if parsed.scheme() != "http" && parsed.scheme() != "https" {
+                return Err(anyhow!("unsupported URL scheme: {}", parsed.scheme()));
+            }
+            urls.push(parsed);
+        }
+
+        let allow_unsafe = self.unsafe_conn.unwrap_or(SAFE_CONNECTION_CAP);
+        let max_per_host = if self.unsafe_conn.is_some() {
+            self.connections.max(1)
+        } else {
+            self.connections.clamp(1, SAFE_CONNECTION_CAP)
+        };
+        if self.unsafe_conn.is_some() && self.connections > allow_unsafe {
+            return Err(anyhow!(
+                "--connections exceeds unsafe limit; either lower it or raise --unsafe-conn"
+            ));
+        }
+
+        let output_path = infer_output_path(self.output, &urls)?;
+        let partmap_path = derive_partmap_path(&output_path);
+        let checksum = match self.checksum {
+            Some(spec) => Some(spec.resolve(&checksum_names(&output_path, &urls))?),
+            None => None,
+        };
+
+        Ok(DownloadConfig {
+            urls,
+            output_path,
+            partmap_path,
+            resume: self.resume,
+            initial_segments: self.segments.max(1),
+            max_connections_per_host: max_per_host,
+            max_connections: self.max_connections.map(|limit| limit.max(1)),
+            unsafe_connection_cap: allow_unsafe,
+            timeout: self.timeout,
+            bandwidth_limit: self.bandwidth_limit,
+            expected_size: self.size,
+            checksum,
+            signature: self.signature,
+            piece_hashes: self.piece_hashes.map(Arc::new),
+            durability: self.durability,
+            on_change: self.on_change,
+            progress: self.progress,
+        })
+    }
+}
+
+/// Names to look up in a checksum file: the output file, then the remote one.
+pub(crate) fn checksum_names<'a>(output_path: &'a Path, urls: &'a [Url]) -> Vec<&'a str> {
+    let remote = urls
+        .iter()
+        .filter_map(|url| url.path_segments()?.next_back());
+    output_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .into_iter()
+        .chain(remote)
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_rejects_unsupported_scheme() {
+        let err = DownloadConfig::builder("ftp://example.com/file")
+            .build()
+            .unwrap_err();
+        assert!(err.to_string().contains("unsupported URL scheme"));
+    }
+
+    #[test]
+    fn builder_clamps_connections_without_unsafe_cap() {
+        let config = DownloadConfig::builder("https://example.com/file")
+            .connections(500)
+            .build()
+            .expect("config");
+        assert_eq!(config.max_connections_per_host, SAFE_CONNECTION_CAP);
+        assert_eq!(config.output_path, PathBuf::from("file"));
+    }
+
+    #[test]
+    fn connection_limits_apply_per_host() {
+        let config = DownloadConfig::builder("https://a.example.com/file")
+            .mirror("https://a.example.com/mirror/file")
+            .mirror("https://b.example.com/file")
+            .connections(8)
+            .build()
+            .expect("config");
+        assert_eq!(config.max_parallelism(), 16);
+
+        let config = DownloadConfig::builder("https://a.example.com/file")
+            .mirror("https://b.example.com/file")
+            .connections(8)
+            .max_connections(10)
+            .build()
+            .expect("config");
+        assert_eq!(config.max_parallelism(), 10);
+    }
 }
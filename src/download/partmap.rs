@@ -1,11 +1,61 @@
-use std::path::PathBuf;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::{anyhow, Context, Result};
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
 use tokio::fs::{self, File, OpenOptions};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::sync::Mutex;
 
+use super::sink::OutputSink;
+
+/// Start of every part map, followed by the format version.
+const MAGIC: &[u8; 8] = b"KDLPMAP\0";
+/// Magic and version, then records framed by length and CRC-32: the base
+/// map first, segment updates after it. Version 1 had no header and no
+/// framing, just the bincode map followed by updates; version 2 did not
+/// record the [`ResumeSource`] and version 3 kept a single ETag instead of
+/// per-mirror [`Validator`]s.
+const FORMAT_VERSION: u16 = 4;
+const HEADER_LEN: usize = MAGIC.len() + 2;
+/// Payload length and CRC-32, both little-endian `u32`.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Longest [`Durability::Balanced`] holds progress back before syncing.
+const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+/// Journal records after which the base map is rewritten.
+const COMPACT_AFTER_RECORDS: usize = 1024;
+
+/// How closely the part map tracks what survives a power loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Never syncs; after a power loss the journal may claim bytes that
+    /// never reached the disk.
+    Fast,
+    /// Syncs the output, then the journal, about once a second and whenever
+    /// a segment completes. Progress since the last sync is lost on a crash.
+    #[default]
+    Balanced,
+    /// Syncs the output, then the journal, on every record.
+    Strict,
+}
+
+impl Durability {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "fast" => Ok(Self::Fast),
+            "balanced" => Ok(Self::Balanced),
+            "strict" => Ok(Self::Strict),
+            other => Err(anyhow!(
+                "unknown durability {other:?}; expected fast, balanced or strict"
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PartSegment {
     pub id: usize,
@@ -24,16 +74,69 @@ impl PartSegment {
     }
 }
 
+/// Where a download came from, so it can be resumed from its part map alone.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeSource {
+    /// Primary URL first, then the mirrors.
+    pub urls: Vec<String>,
+    pub output: PathBuf,
+    /// Checksum as [`ChecksumSpec::to_input`](crate::ChecksumSpec::to_input)
+    /// writes it.
+    pub checksum: Option<String>,
+    /// What each mirror reported about its copy when the map was written.
+    pub validators: Vec<Validator>,
+}
+
+/// `ETag` and `Last-Modified` a mirror reported for the file. Mirrors name
+/// the same file differently, so each is compared only with itself.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Validator {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl Validator {
+    /// Value for `If-Range`: the ETag unless it is weak, which `If-Range`
+    /// does not allow, else `Last-Modified`.
+    pub fn if_range(&self) -> Option<&str> {
+        self.etag
+            .as_deref()
+            .filter(|etag| !etag.starts_with("W/"))
+            .or(self.last_modified.as_deref())
+    }
+
+    /// Why `current`, a later answer from the same mirror, describes another
+    /// file, if it does.
+    pub fn changed(&self, current: &Validator) -> Option<String> {
+        for (name, before, now) in [
+            ("ETag", &self.etag, &current.etag),
+            ("Last-Modified", &self.last_modified, &current.last_modified),
+        ] {
+            if let (Some(before), Some(now)) = (before, now) {
+                return (before != now).then(|| format!("{name} changed from {before} to {now}"));
+            }
+        }
+        None
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PartMap {
     pub file_size: u64,
     pub chunk_size: u64,
+    /// Unix time the download started.
+    pub created: u64,
+    pub source: ResumeSource,
     pub segments: Vec<PartSegment>,
 }
 
 impl PartMap {
     pub fn new(file_size: u64, chunk_size: u64) -> Self {
         let chunk_size = chunk_size.max(1);
+        let created = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
         let mut segments = Vec::new();
         if file_size == 0 {
             segments.push(PartSegment {
@@ -45,6 +148,8 @@ impl PartMap {
             return Self {
                 file_size,
                 chunk_size,
+                created,
+                source: ResumeSource::default(),
                 segments,
             };
         }
@@ -66,9 +171,95 @@ impl PartMap {
         Self {
             file_size,
             chunk_size,
+            created,
+            source: ResumeSource::default(),
             segments,
         }
     }
+
+    /// Serializes the map as a fresh part map file.
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        encode_record(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    fn apply(&mut self, update: &SegmentUpdate) -> std::result::Result<(), String> {
+        // Segment ids are their index; splits append new segments.
+        let segment = self
+            .segments
+            .get_mut(update.id)
+            .ok_or_else(|| format!("update for unknown segment {}", update.id))?;
+        segment.downloaded = update.downloaded.min(segment.len());
+        Ok(())
+    }
+}
+
+/// Base map of format version 1, which had no creation time.
+#[derive(Serialize, Deserialize)]
+struct PartMapV1 {
+    file_size: u64,
+    chunk_size: u64,
+    segments: Vec<PartSegment>,
+}
+
+/// Base map of format version 2, which had no [`ResumeSource`].
+#[derive(Serialize, Deserialize)]
+struct PartMapV2 {
+    file_size: u64,
+    chunk_size: u64,
+    created: u64,
+    segments: Vec<PartSegment>,
+}
+
+impl From<PartMapV2> for PartMap {
+    fn from(map: PartMapV2) -> Self {
+        Self {
+            file_size: map.file_size,
+            chunk_size: map.chunk_size,
+            created: map.created,
+            source: ResumeSource::default(),
+            segments: map.segments,
+        }
+    }
+}
+
+/// Base map of format version 3, which kept one ETag for all mirrors.
+#[derive(Serialize, Deserialize)]
+struct PartMapV3 {
+    file_size: u64,
+    chunk_size: u64,
+    created: u64,
+    source: ResumeSourceV3,
+    segments: Vec<PartSegment>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResumeSourceV3 {
+    urls: Vec<String>,
+    output: PathBuf,
+    checksum: Option<String>,
+    etag: Option<String>,
+}
+
+impl From<PartMapV3> for PartMap {
+    /// The ETag is dropped as it cannot be traced to a mirror; such maps
+    /// resume unchecked.
+    fn from(map: PartMapV3) -> Self {
+        Self {
+            file_size: map.file_size,
+            chunk_size: map.chunk_size,
+            created: map.created,
+            source: ResumeSource {
+                urls: map.source.urls,
+                output: map.source.output,
+                checksum: map.source.checksum,
+                validators: Vec::new(),
+            },
+            segments: map.segments,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -77,98 +268,378 @@ struct SegmentUpdate {
     downloaded: u64,
 }
 
+fn encode_record<T: Serialize>(out: &mut Vec<u8>, value: &T) -> Result<()> {
+    let payload = bincode::serialize(value)?;
+    let len = u32::try_from(payload.len()).context("part map record too large")?;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    Ok(())
+}
+
+enum Frame<'a> {
+    Record {
+        payload: &'a [u8],
+        next: usize,
+    },
+    /// Cut off by the end of the file.
+    Truncated,
+    /// Complete, but the payload does not match its CRC.
+    Corrupt {
+        next: usize,
+    },
+}
+
+fn read_frame(data: &[u8], offset: usize) -> Frame<'_> {
+    let Some(header) = data.get(offset..offset + FRAME_HEADER_LEN) else {
+        return Frame::Truncated;
+    };
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let start = offset + FRAME_HEADER_LEN;
+    let Some(payload) = data.get(start..start.saturating_add(len)) else {
+        return Frame::Truncated;
+    };
+    let next = start + len;
+    if crc32fast::hash(payload) == crc {
+        Frame::Record { payload, next }
+    } else {
+        Frame::Corrupt { next }
+    }
+}
+
+/// A part map read back from disk.
+struct Decoded {
+    map: PartMap,
+    /// Length of the intact prefix; anything after it is a torn append.
+    valid_len: usize,
+    /// Read from an older format version.
+    migrated: bool,
+}
+
+/// Parses a part map file, explaining why when it cannot be used.
+fn decode(data: &[u8]) -> std::result::Result<Decoded, String> {
+    if !data.starts_with(MAGIC) {
+        return decode_v1(data);
+    }
+    let version = data
+        .get(MAGIC.len()..HEADER_LEN)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+        .ok_or("header is truncated")?;
+    if version > FORMAT_VERSION {
+        return Err(format!(
+            "it was written by a newer kdownload (format version {version}, this one reads up to {FORMAT_VERSION})"
+        ));
+    }
+    let (mut map, mut offset) = match read_frame(data, HEADER_LEN) {
+        Frame::Record { payload, next } => {
+            let map = match version {
+                2 => bincode::deserialize::<PartMapV2>(payload).map(PartMap::from),
+                3 => bincode::deserialize::<PartMapV3>(payload).map(PartMap::from),
+                FORMAT_VERSION => bincode::deserialize(payload),
+                _ => return Err(format!("unknown format version {version}")),
+            };
+            (
+                map.map_err(|err| format!("base map is unreadable: {err}"))?,
+                next,
+            )
+        }
+        Frame::Truncated => return Err("base map is truncated".into()),
+        Frame::Corrupt { .. } => return Err("base map fails its checksum".into()),
+    };
+    while offset < data.len() {
+        match read_frame(data, offset) {
+            Frame::Record { payload, next } => {
+                let update: SegmentUpdate = bincode::deserialize(payload)
+                    .map_err(|err| format!("record at byte {offset} is unreadable: {err}"))?;
+                map.apply(&update)
+                    .map_err(|reason| format!("record at byte {offset}: {reason}"))?;
+                offset = next;
+            }
+            // The last append was cut short by a crash; earlier records hold.
+            Frame::Truncated => break,
+            Frame::Corrupt { next } if next == data.len() => break,
+            Frame::Corrupt { .. } => {
+                return Err(format!("record at byte {offset} fails its checksum"));
+            }
+        }
+    }
+    Ok(Decoded {
+        map,
+        valid_len: offset,
+        migrated: version != FORMAT_VERSION,
+    })
+}
+
+/// Reads a version 1 map, which cannot tell a torn update from a corrupt one:
+/// replay stops at the first update that does not parse.
+fn decode_v1(data: &[u8]) -> std::result::Result<Decoded, String> {
+    const NOT_A_PART_MAP: &str = "it is not a kdownload part map";
+    let base: PartMapV1 = bincode::deserialize(data).map_err(|_| NOT_A_PART_MAP)?;
+    let plausible = base.segments.iter().enumerate().all(|(index, seg)| {
+        seg.id == index && seg.start <= seg.end && seg.end < base.file_size.max(1)
+    });
+    if !plausible {
+        return Err(NOT_A_PART_MAP.into());
+    }
+    let mut offset = bincode::serialized_size(&base).map_err(|err| err.to_string())? as usize;
+    let mut map = PartMap {
+        file_size: base.file_size,
+        chunk_size: base.chunk_size,
+        created: 0,
+        source: ResumeSource::default(),
+        segments: base.segments,
+    };
+    while let Ok(update) = bincode::deserialize::<SegmentUpdate>(&data[offset..]) {
+        if map.apply(&update).is_err() {
+            break;
+        }
+        offset += bincode::serialized_size(&update).map_err(|err| err.to_string())? as usize;
+    }
+    Ok(Decoded {
+        map,
+        valid_len: offset,
+        migrated: true,
+    })
+}
+
+/// Reads what the part map at `path` was started from.
+pub fn read_resume_source(path: &Path) -> Result<ResumeSource> {
+    let data =
+        std::fs::read(path).with_context(|| format!("failed to open part map {:?}", path))?;
+    Ok(decode(&data)
+        .map_err(|reason| unusable(path, reason))?
+        .map
+        .source)
+}
+
+/// Size of the file the part map at `path` describes; streamed downloads of
+/// unknown size record `0`.
+pub(crate) fn read_file_size(path: &Path) -> Result<u64> {
+    let data =
+        std::fs::read(path).with_context(|| format!("failed to open part map {:?}", path))?;
+    Ok(decode(&data)
+        .map_err(|reason| unusable(path, reason))?
+        .map
+        .file_size)
+}
+
+fn unusable(path: &Path, reason: String) -> anyhow::Error {
+    anyhow!("cannot resume from part map {path:?}: {reason}; delete it to start over")
+}
+
 struct PartMapState {
     map: PartMap,
-    file: File,
+    /// Journal file; `None` for sinks that cannot be resumed.
+    file: Option<File>,
+    /// Segments whose progress is not journaled yet.
+    dirty: BTreeSet<usize>,
+    /// Records appended since the base map was written.
+    records: usize,
+    last_sync: Instant,
+}
+
+impl PartMapState {
+    fn new(map: PartMap, file: Option<File>) -> Self {
+        Self {
+            map,
+            file,
+            dirty: BTreeSet::new(),
+            records: 0,
+            last_sync: Instant::now(),
+        }
+    }
 }
 
 pub struct PartMapHandle {
     path: PathBuf,
     state: Mutex<PartMapState>,
+    durability: Durability,
+    /// Synced before the journal so it never claims unwritten data.
+    output: Option<Arc<dyn OutputSink>>,
 }
 
 impl PartMapHandle {
-    pub async fn load_or_create(path: PathBuf, file_size: u64, chunk_size: u64) -> Result<Self> {
+    /// Opens the part map at `path`, or starts a new one when there is none
+    /// or it describes a file of another size. A map that cannot be read is
+    /// an error rather than silently replaced. `source` replaces the recorded
+    /// one.
+    pub async fn load_or_create(
+        path: PathBuf,
+        file_size: u64,
+        chunk_size: u64,
+        source: ResumeSource,
+    ) -> Result<Self> {
         if path.exists() {
-            let mut file = OpenOptions::new()
-                .read(true)
-                .write(true)
-                .open(&path)
+            let data = fs::read(&path)
                 .await
                 .with_context(|| format!("failed to open part map {:?}", path))?;
 
-            let mut data = Vec::new();
-            file.read_to_end(&mut data).await?;
-
             if !data.is_empty() {
-                // Try to deserialize the base map
-                let mut offset = 0;
-                match bincode::deserialize::<PartMap>(&data) {
-                    Ok(mut map) => {
-                        offset += bincode::serialized_size(&map)? as usize;
-                        
-                        // Check if valid
-                        if map.file_size == file_size {
-                             // Replay updates
-                             while offset < data.len() {
-                                 match bincode::deserialize::<SegmentUpdate>(&data[offset..]) {
-                                     Ok(update) => {
-                                         if let Some(seg) = map.segments.get_mut(update.id) {
-                                             seg.downloaded = update.downloaded;
-                                         }
-                                         offset += bincode::serialized_size(&update)? as usize;
-                                     }
-                                     Err(_) => break, // Stop on partial/corrupt update
-                                 }
-                             }
-                             
-                             // Re-open in append mode
-                             let file = OpenOptions::new()
-                                .append(true)
-                                .open(&path)
-                                .await?;
-
-                             return Ok(Self {
-                                 path,
-                                 state: Mutex::new(PartMapState { map, file }),
-                             });
-                        }
-                    }
-                    Err(_) => {
-                        // Invalid format, ignore and overwrite
+                let decoded = decode(&data).map_err(|reason| unusable(&path, reason))?;
+                if decoded.map.file_size == file_size {
+                    if decoded.map.chunk_size != chunk_size.max(1) {
+                        info!(
+                            "part map {:?} keeps its recorded layout of {} segments",
+                            path,
+                            decoded.map.segments.len()
+                        );
                     }
+                    return Self::reopen(path, decoded, data.len(), source).await;
                 }
+                warn!(
+                    "part map {:?} describes a {}-byte file, not {file_size} bytes; starting over",
+                    path, decoded.map.file_size
+                );
             }
         }
 
         // Create new
-        let map = PartMap::new(file_size, chunk_size);
+        let mut map = PartMap::new(file_size, chunk_size);
+        map.source = source;
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
             .open(&path)
             .await?;
-        
-        let bytes = bincode::serialize(&map)?;
-        file.write_all(&bytes).await?;
 
-        Ok(Self {
+        file.write_all(&map.encode()?).await?;
+        file.flush().await?;
+
+        Ok(Self::with_state(path, PartMapState::new(map, Some(file))))
+    }
+
+    /// Continues the journal of a loaded map, dropping a torn last record
+    /// and upgrading older formats.
+    async fn reopen(
+        path: PathBuf,
+        mut decoded: Decoded,
+        len: usize,
+        source: ResumeSource,
+    ) -> Result<Self> {
+        if decoded.valid_len < len && !decoded.migrated {
+            warn!(
+                "part map {:?} ends in an incomplete record; dropping {} bytes",
+                path,
+                len - decoded.valid_len
+            );
+            let file = OpenOptions::new().write(true).open(&path).await?;
+            file.set_len(decoded.valid_len as u64).await?;
+        }
+        let stale = decoded.migrated || decoded.map.source != source;
+        decoded.map.source = source;
+        let file = OpenOptions::new().append(true).open(&path).await?;
+        let handle = Self::with_state(path, PartMapState::new(decoded.map, Some(file)));
+        if stale {
+            let mut state = handle.state.lock().await;
+            handle.rewrite(&mut state).await?;
+        }
+        if decoded.migrated {
+            info!(
+                "upgraded part map {:?} to format version {FORMAT_VERSION}",
+                handle.path
+            );
+        }
+        Ok(handle)
+    }
+
+    /// Tracks progress in memory only, without a journal on disk.
+    pub fn ephemeral(file_size: u64, chunk_size: u64) -> Self {
+        Self::with_state(
+            PathBuf::new(),
+            PartMapState::new(PartMap::new(file_size, chunk_size), None),
+        )
+    }
+
+    fn with_state(path: PathBuf, state: PartMapState) -> Self {
+        Self {
             path,
-            state: Mutex::new(PartMapState { map, file }),
-        })
+            state: Mutex::new(state),
+            durability: Durability::Fast,
+            output: None,
+        }
     }
 
+    /// Syncs `output` before the journal as `durability` asks. Without it
+    /// nothing is synced.
+    pub fn with_durability(mut self, durability: Durability, output: Arc<dyn OutputSink>) -> Self {
+        self.durability = durability;
+        self.output = Some(output);
+        self
+    }
+
+    /// Every segment, sorted by offset.
     pub async fn segments(&self) -> Vec<PartSegment> {
-        self.state.lock().await.map.segments.clone()
+        let mut segments = self.state.lock().await.map.segments.clone();
+        segments.sort_by_key(|segment| segment.start);
+        segments
+    }
+
+    /// Cuts segment `id` at offset `at`: it keeps the bytes before `at` and a
+    /// new segment takes the rest. The map is rewritten so a resume sees the
+    /// new layout. The request for `id` stops at `at` before the split is
+    /// recorded, so it may already have everything up to `at`.
+    pub async fn split(&self, id: usize, at: u64) -> Result<PartSegment> {
+        let mut state = self.state.lock().await;
+        let new_id = state.map.segments.len();
+        let segment = state
+            .map
+            .segments
+            .iter_mut()
+            .find(|seg| seg.id == id)
+            .ok_or_else(|| anyhow!("segment {id} not found in part map"))?;
+        if at <= segment.start || at < segment.start + segment.downloaded || at > segment.end {
+            return Err(anyhow!(
+                "cannot split segment {id} ({}-{}) at {at}",
+                segment.start,
+                segment.end
+            ));
+        }
+        let tail = PartSegment {
+            id: new_id,
+            start: at,
+            end: segment.end,
+            downloaded: 0,
+        };
+        segment.end = at - 1;
+        state.map.segments.push(tail.clone());
+        self.rewrite(&mut state).await?;
+        Ok(tail)
     }
 
-    pub async fn record_progress(
-        &self,
-        id: usize,
-        downloaded: u64,
-        _force_flush: bool,
-    ) -> Result<()> {
+    /// Replaces the journal with the current map, written to a temporary
+    /// file first so a crash leaves either the old or the new map.
+    async fn rewrite(&self, state: &mut PartMapState) -> Result<()> {
+        if state.file.is_none() {
+            return Ok(());
+        }
+        if self.durability != Durability::Fast {
+            self.sync_output().await?;
+        }
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let temp_path = PathBuf::from(temp_path);
+        let mut temp = File::create(&temp_path)
+            .await
+            .with_context(|| format!("failed to write part map {:?}", temp_path))?;
+        temp.write_all(&state.map.encode()?).await?;
+        temp.sync_data().await?;
+        drop(temp);
+        fs::rename(&temp_path, &self.path)
+            .await
+            .with_context(|| format!("failed to replace part map {:?}", self.path))?;
+        state.file = Some(OpenOptions::new().append(true).open(&self.path).await?);
+        state.dirty.clear();
+        state.records = 0;
+        state.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Records that segment `id` has `downloaded` bytes on disk. `flush`
+    /// marks milestones such as a completed segment, which
+    /// [`Durability::Balanced`] syncs right away.
+    pub async fn record_progress(&self, id: usize, downloaded: u64, flush: bool) -> Result<()> {
         let mut state = self.state.lock().await;
         let segment = state
             .map
@@ -176,28 +647,75 @@ impl PartMapHandle {
             .iter_mut()
             .find(|seg| seg.id == id)
             .ok_or_else(|| anyhow!("segment {id} not found in part map"))?;
-        
+
         segment.downloaded = downloaded.min(segment.len());
-        
-        let update = SegmentUpdate {
-            id,
-            downloaded: segment.downloaded,
+        if state.file.is_none() {
+            return Ok(());
+        }
+        state.dirty.insert(id);
+
+        let due = match self.durability {
+            Durability::Fast | Durability::Strict => true,
+            Durability::Balanced => flush || state.last_sync.elapsed() >= SYNC_INTERVAL,
         };
-        let bytes = bincode::serialize(&update)?;
-        state.file.write_all(&bytes).await?;
-        
-        // We rely on OS buffering and occasional syncs by the user or OS.
-        // If we want durability, we could sync_data periodically, but speed is priority here.
+        if !due {
+            return Ok(());
+        }
+        if state.records + state.dirty.len() > COMPACT_AFTER_RECORDS {
+            return self.rewrite(&mut state).await;
+        }
+        self.write_dirty(&mut state).await
+    }
+
+    /// Appends the pending records, after the output data they describe.
+    async fn write_dirty(&self, state: &mut PartMapState) -> Result<()> {
+        let synced = self.durability != Durability::Fast;
+        if synced {
+            self.sync_output().await?;
+        }
+        let mut bytes = Vec::new();
+        for id in std::mem::take(&mut state.dirty) {
+            if let Some(segment) = state.map.segments.iter().find(|seg| seg.id == id) {
+                encode_record(
+                    &mut bytes,
+                    &SegmentUpdate {
+                        id,
+                        downloaded: segment.downloaded,
+                    },
+                )?;
+                state.records += 1;
+            }
+        }
+        let Some(file) = state.file.as_mut() else {
+            return Ok(());
+        };
+        // Tokio writes in the background; flush so the records are handed
+        // to the OS before anything else touches the file.
+        file.write_all(&bytes).await?;
+        file.flush().await?;
+        if synced {
+            file.sync_data().await?;
+        }
+        state.last_sync = Instant::now();
         Ok(())
     }
 
+    async fn sync_output(&self) -> Result<()> {
+        let Some(output) = self.output.clone() else {
+            return Ok(());
+        };
+        tokio::task::spawn_blocking(move || output.sync())
+            .await?
+            .context("failed to sync output before the part map")
+    }
+
     pub async fn segment(&self, id: usize) -> Option<PartSegment> {
         let state = self.state.lock().await;
         state.map.segments.iter().find(|seg| seg.id == id).cloned()
     }
 
     pub async fn finalize(&self) -> Result<()> {
-        if self.path.exists() {
+        if !self.path.as_os_str().is_empty() && self.path.exists() {
             fs::remove_file(&self.path)
                 .await
                 .with_context(|| format!("failed to remove part map {:?}", self.path))?;
@@ -205,3 +723,222 @@ impl PartMapHandle {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::MemorySink;
+
+    async fn reload(path: &std::path::Path) -> Vec<u64> {
+        let handle =
+            PartMapHandle::load_or_create(path.to_path_buf(), 100, 50, ResumeSource::default())
+                .await
+                .unwrap();
+        handle
+            .segments()
+            .await
+            .iter()
+            .map(|seg| seg.downloaded)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn damaged_maps_are_reported_or_repaired() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let handle = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .unwrap();
+        handle.record_progress(0, 10, false).await.unwrap();
+        handle.record_progress(1, 20, false).await.unwrap();
+        drop(handle);
+        let intact = std::fs::read(&path).unwrap();
+
+        // A torn append after a crash is dropped; earlier records survive.
+        let mut torn = intact.clone();
+        torn.extend_from_slice(&[9, 0, 0]);
+        std::fs::write(&path, &torn).unwrap();
+        assert_eq!(reload(&path).await, [10, 20]);
+        assert_eq!(std::fs::read(&path).unwrap(), intact);
+
+        let mut corrupt = intact.clone();
+        let record_len = FRAME_HEADER_LEN + 16;
+        corrupt[intact.len() - 2 * record_len + FRAME_HEADER_LEN] ^= 1;
+        std::fs::write(&path, &corrupt).unwrap();
+        let err = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("fails its checksum"), "{err}");
+        assert_eq!(std::fs::read(&path).unwrap(), corrupt);
+
+        let mut newer = intact;
+        newer[MAGIC.len()] = 99;
+        std::fs::write(&path, &newer).unwrap();
+        let err = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("newer kdownload"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn version_one_maps_are_migrated() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let map = PartMap::new(100, 50);
+        let mut legacy = bincode::serialize(&PartMapV1 {
+            file_size: map.file_size,
+            chunk_size: map.chunk_size,
+            segments: map.segments,
+        })
+        .unwrap();
+        bincode::serialize_into(
+            &mut legacy,
+            &SegmentUpdate {
+                id: 1,
+                downloaded: 7,
+            },
+        )
+        .unwrap();
+        std::fs::write(&path, &legacy).unwrap();
+
+        assert_eq!(reload(&path).await, [0, 7]);
+        assert!(std::fs::read(&path).unwrap().starts_with(MAGIC));
+        assert_eq!(reload(&path).await, [0, 7]);
+    }
+
+    #[test]
+    fn validators_compare_etag_before_last_modified() {
+        let validator = |etag: Option<&str>, last_modified: Option<&str>| Validator {
+            url: "https://example.com/file".into(),
+            etag: etag.map(Into::into),
+            last_modified: last_modified.map(Into::into),
+        };
+        let stored = validator(Some("\"a\""), Some("Mon, 01 Jan 2024 00:00:00 GMT"));
+        assert_eq!(stored.if_range(), Some("\"a\""));
+        assert!(stored
+            .changed(&validator(
+                Some("\"a\""),
+                Some("Tue, 02 Jan 2024 00:00:00 GMT")
+            ))
+            .is_none());
+        assert!(stored.changed(&validator(Some("\"b\""), None)).is_some());
+        assert!(stored
+            .changed(&validator(None, Some("Tue, 02 Jan 2024 00:00:00 GMT")))
+            .is_some());
+        assert!(stored.changed(&validator(None, None)).is_none());
+
+        let weak = validator(Some("W/\"a\""), Some("Mon, 01 Jan 2024 00:00:00 GMT"));
+        assert_eq!(weak.if_range(), Some("Mon, 01 Jan 2024 00:00:00 GMT"));
+    }
+
+    #[tokio::test]
+    async fn resume_source_follows_the_latest_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let mut source = ResumeSource {
+            urls: vec!["https://example.com/file".into()],
+            output: dir.path().join("file"),
+            checksum: Some("sha256:00".into()),
+            validators: vec![Validator {
+                url: "https://example.com/file".into(),
+                etag: Some("\"v1\"".into()),
+                last_modified: None,
+            }],
+        };
+        let handle = PartMapHandle::load_or_create(path.clone(), 100, 50, source.clone())
+            .await
+            .unwrap();
+        handle.record_progress(0, 10, false).await.unwrap();
+        drop(handle);
+        assert_eq!(read_resume_source(&path).unwrap(), source);
+
+        source.urls.push("https://mirror.example.com/file".into());
+        PartMapHandle::load_or_create(path.clone(), 100, 20, source.clone())
+            .await
+            .unwrap();
+        assert_eq!(read_resume_source(&path).unwrap(), source);
+        assert_eq!(reload(&path).await, [10, 0]);
+    }
+
+    #[tokio::test]
+    async fn balanced_journals_on_flush() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let handle = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .unwrap()
+            .with_durability(Durability::Balanced, Arc::new(MemorySink::new()));
+        handle.record_progress(0, 10, false).await.unwrap();
+        assert_eq!(reload(&path).await, [0, 0]);
+        handle.record_progress(1, 5, true).await.unwrap();
+        assert_eq!(reload(&path).await, [10, 5]);
+    }
+
+    #[tokio::test]
+    async fn long_journals_are_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let handle = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .unwrap();
+        for downloaded in 0..COMPACT_AFTER_RECORDS as u64 + 10 {
+            handle
+                .record_progress(0, downloaded % 50, false)
+                .await
+                .unwrap();
+        }
+        let map_len = PartMap::new(100, 50).encode().unwrap().len() as u64;
+        let record_len = FRAME_HEADER_LEN as u64
+            + bincode::serialized_size(&SegmentUpdate {
+                id: 0,
+                downloaded: 0,
+            })
+            .unwrap();
+        let journal_len = std::fs::metadata(&path).unwrap().len();
+        assert!(journal_len <= map_len + 10 * record_len);
+        assert_eq!(
+            reload(&path).await,
+            [(COMPACT_AFTER_RECORDS as u64 + 9) % 50, 0]
+        );
+    }
+
+    #[tokio::test]
+    async fn splits_survive_a_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.kdl.partmap");
+        let handle = PartMapHandle::load_or_create(path.clone(), 100, 50, ResumeSource::default())
+            .await
+            .unwrap();
+        handle.record_progress(0, 10, false).await.unwrap();
+        let tail = handle.split(0, 30).await.unwrap();
+        assert_eq!((tail.id, tail.start, tail.end), (2, 30, 49));
+        assert!(handle.split(0, 5).await.is_err());
+        handle.record_progress(2, 20, true).await.unwrap();
+        // The head delivered everything up to the cut before it was recorded.
+        handle.record_progress(1, 25, true).await.unwrap();
+        let late = handle.split(1, 75).await.unwrap();
+        assert_eq!((late.id, late.start, late.end), (3, 75, 99));
+        drop(handle);
+
+        let handle = PartMapHandle::load_or_create(path, 100, 50, ResumeSource::default())
+            .await
+            .unwrap();
+        let layout: Vec<_> = handle
+            .segments()
+            .await
+            .iter()
+            .map(|seg| (seg.id, seg.start, seg.end, seg.downloaded))
+            .collect();
+        assert_eq!(
+            layout,
+            [
+                (0, 0, 29, 10),
+                (2, 30, 49, 20),
+                (1, 50, 74, 25),
+                (3, 75, 99, 0)
+            ]
+        );
+    }
+}
@@ -0,0 +1,92 @@
+use std::sync::Arc;
+
+use crate::pieces::{Piece, PieceHashes};
+
+use super::partmap::PartSegment;
+
+/// Which pieces of a segmented download have been verified so far.
+pub(crate) struct PieceTracker {
+    hashes: Arc<PieceHashes>,
+    total: u64,
+    verified: Vec<bool>,
+    failures: Vec<u32>,
+}
+
+impl PieceTracker {
+    pub(crate) fn new(hashes: Arc<PieceHashes>, total: u64) -> Self {
+        let count = hashes.pieces.len();
+        Self {
+            hashes,
+            total,
+            verified: vec![false; count],
+            failures: vec![0; count],
+        }
+    }
+
+    pub(crate) fn hashes(&self) -> Arc<PieceHashes> {
+        self.hashes.clone()
+    }
+
+    pub(crate) fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub(crate) fn piece(&self, index: usize) -> &Piece {
+        &self.hashes.pieces[index]
+    }
+
+    /// Inclusive byte range of piece `index`, cut at the end of the file.
+    pub(crate) fn range(&self, index: usize) -> (u64, u64) {
+        let piece = self.piece(index);
+        (piece.start, piece.end.min(self.total.saturating_sub(1)))
+    }
+
+    /// Unverified pieces whose bytes have all been downloaded. `segments`
+    /// must be sorted by offset.
+    pub(crate) fn ready(&self, segments: &[PartSegment]) -> Vec<usize> {
+        (0..self.verified.len())
+            .filter(|&index| !self.verified[index])
+            .filter(|&index| {
+                let (start, end) = self.range(index);
+                overlapping(segments, start, end).all(|segment| segment.remaining() == 0)
+            })
+            .collect()
+    }
+
+    pub(crate) fn mark_verified(&mut self, index: usize) {
+        self.verified[index] = true;
+    }
+
+    /// Counts a failed verification and returns how often piece `index` has
+    /// failed.
+    pub(crate) fn record_failure(&mut self, index: usize) -> u32 {
+        self.failures[index] += 1;
+        self.failures[index]
+    }
+
+    /// Marks every piece touching `start..=end` as needing verification again.
+    pub(crate) fn invalidate(&mut self, start: u64, end: u64) {
+        for index in 0..self.verified.len() {
+            let (piece_start, piece_end) = self.range(index);
+            if piece_start <= end && piece_end >= start {
+                self.verified[index] = false;
+            }
+        }
+    }
+
+    pub(crate) fn unverified(&self) -> usize {
+        self.verified.iter().filter(|verified| !**verified).count()
+    }
+}
+
+/// Segments intersecting `start..=end`; `segments` must be sorted by offset.
+pub(crate) fn overlapping(
+    segments: &[PartSegment],
+    start: u64,
+    end: u64,
+) -> impl Iterator<Item = &PartSegment> {
+    let first = segments.partition_point(|segment| segment.end < start);
+    segments[first..]
+        .iter()
+        .take_while(move |segment| segment.start <= end)
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::Mutex;
+
+use log::debug;
+
+use crate::checksum::{read_chunks, HashAlgorithm, Hasher};
+
+use super::sink::{OutputSink, SinkReader};
+
+/// Hashes the contiguous prefix of the output while segments are written, so
+/// the whole-file checksum does not have to read everything back.
+///
+/// Data written past the prefix is remembered by range and read back from
+/// the sink once the gap before it is filled.
+pub(crate) struct PrefixHasher {
+    algorithm: HashAlgorithm,
+    state: Mutex<PrefixState>,
+}
+
+struct PrefixState {
+    hasher: Hasher,
+    /// Length of the hashed prefix.
+    hashed: u64,
+    /// Written but unhashed ranges, `start -> end` (exclusive).
+    written: BTreeMap<u64, u64>,
+}
+
+impl PrefixState {
+    fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            hasher: algorithm.hasher(),
+            hashed: 0,
+            written: BTreeMap::new(),
+        }
+    }
+
+    fn mark(&mut self, start: u64, end: u64) {
+        if let Some((_, previous_end)) = self.written.range_mut(..=start).next_back() {
+            if *previous_end >= start {
+                *previous_end = (*previous_end).max(end);
+                return;
+            }
+        }
+        self.written.insert(start, end);
+    }
+
+    /// Drops `start..end` from the written ranges, keeping what lies around it.
+    fn forget(&mut self, start: u64, end: u64) {
+        let overlapping: Vec<(u64, u64)> = self
+            .written
+            .range(..end)
+            .filter(|(_, &range_end)| range_end > start)
+            .map(|(&range_start, &range_end)| (range_start, range_end))
+            .collect();
+        for (range_start, range_end) in overlapping {
+            self.written.remove(&range_start);
+            if range_start < start {
+                self.written.insert(range_start, start);
+            }
+            if range_end > end {
+                self.written.insert(end, range_end);
+            }
+        }
+    }
+
+    /// Hashes written ranges that now touch the prefix.
+    fn drain(&mut self, sink: &dyn OutputSink) -> io::Result<()> {
+        while let Some(entry) = self.written.first_entry() {
+            if *entry.key() > self.hashed {
+                break;
+            }
+            let end = entry.remove();
+            if end > self.hashed {
+                let reader = SinkReader::at(sink, self.hashed).take(end - self.hashed);
+                let hasher = &mut self.hasher;
+                read_chunks(reader, |chunk| hasher.update(chunk)).map_err(io::Error::other)?;
+                self.hashed = end;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PrefixHasher {
+    pub(crate) fn new(algorithm: HashAlgorithm) -> Self {
+        Self {
+            algorithm,
+            state: Mutex::new(PrefixState::new(algorithm)),
+        }
+    }
+
+    /// Records `start..end` as already on disk, e.g. restored on resume. It is
+    /// read back once the prefix reaches it.
+    pub(crate) fn mark_written(&self, start: u64, end: u64) {
+        if start < end {
+            self.state.lock().unwrap().mark(start, end);
+        }
+    }
+
+    /// Feeds `data` that was just written at `position`.
+    pub(crate) fn on_write(
+        &self,
+        data: &[u8],
+        position: u64,
+        sink: &dyn OutputSink,
+    ) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let end = position + data.len() as u64;
+        if end <= state.hashed {
+            return Ok(());
+        }
+        let (data, position) = if position < state.hashed {
+            // A retry or endgame request wrote bytes that are hashed already.
+            // They are the same bytes; data that differs is announced through
+            // `invalidate` first.
+            let skip = (state.hashed - position) as usize;
+            (&data[skip..], state.hashed)
+        } else {
+            (data, position)
+        };
+        if position == state.hashed {
+            state.hasher.update(data);
+            state.hashed = end;
+        } else {
+            state.mark(position, end);
+        }
+        state.drain(sink)
+    }
+
+    /// Forgets `start..end` because different bytes will be written there,
+    /// e.g. a segment refetched after a failed piece. If that range is hashed
+    /// already the checksum starts over and `finish` re-reads the file.
+    pub(crate) fn invalidate(&self, start: u64, end: u64) {
+        let mut state = self.state.lock().unwrap();
+        if start < state.hashed {
+            debug!("bytes {start}-{end} below the hashed prefix change; checksum restarts");
+            *state = PrefixState::new(self.algorithm);
+        } else {
+            state.forget(start, end);
+        }
+    }
+
+    /// Reads whatever the prefix does not cover yet, up to `total`, and
+    /// returns the digest of the whole file.
+    pub(crate) fn finish(&self, sink: &dyn OutputSink, total: u64) -> io::Result<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.written.clear();
+        if state.hashed < total {
+            debug!("re-reading {} bytes for the checksum", total - state.hashed);
+            let hashed = state.hashed;
+            state.mark(hashed, total);
+            state.drain(sink)?;
+        }
+        let state = std::mem::replace(&mut *state, PrefixState::new(self.algorithm));
+        Ok(state.hasher.finalize())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::MemorySink;
+
+    fn write(hasher: &PrefixHasher, sink: &MemorySink, data: &[u8], position: u64) {
+        sink.write_all_at(data, position).unwrap();
+        hasher.on_write(data, position, sink).unwrap();
+    }
+
+    #[test]
+    fn out_of_order_segments_join_the_prefix() {
+        let sink = MemorySink::new();
+        let hasher = PrefixHasher::new(HashAlgorithm::Sha256);
+        write(&hasher, &sink, b"def", 3);
+        write(&hasher, &sink, b"ghi", 6);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 0);
+        write(&hasher, &sink, b"abc", 0);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 9);
+
+        let expected = HashAlgorithm::Sha256
+            .hash_reader(&b"abcdefghi"[..])
+            .unwrap();
+        assert_eq!(hasher.finish(&sink, 9).unwrap(), expected);
+    }
+
+    #[test]
+    fn rewrites_and_resumed_data_are_read_back() {
+        let sink = MemorySink::new();
+        sink.write_all_at(b"abc", 0).unwrap();
+        let hasher = PrefixHasher::new(HashAlgorithm::Sha1);
+        hasher.mark_written(0, 3);
+        write(&hasher, &sink, b"dXf", 3);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 6);
+        hasher.invalidate(3, 6);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 0);
+        write(&hasher, &sink, b"def", 3);
+        write(&hasher, &sink, b"gh", 6);
+
+        let expected = HashAlgorithm::Sha1.hash_reader(&b"abcdefgh"[..]).unwrap();
+        assert_eq!(hasher.finish(&sink, 8).unwrap(), expected);
+    }
+
+    #[test]
+    fn repeated_bytes_keep_the_prefix() {
+        let sink = MemorySink::new();
+        let hasher = PrefixHasher::new(HashAlgorithm::Sha256);
+        write(&hasher, &sink, b"abcdef", 0);
+        // A retry from an older checkpoint sends hashed bytes again.
+        write(&hasher, &sink, b"cdefgh", 2);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 8);
+        write(&hasher, &sink, b"ab", 0);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 8);
+
+        // Ranges past the prefix that are refetched are not read back stale.
+        write(&hasher, &sink, b"XY", 10);
+        hasher.invalidate(10, 12);
+        write(&hasher, &sink, b"ij", 8);
+        assert_eq!(hasher.state.lock().unwrap().hashed, 10);
+        write(&hasher, &sink, b"kl", 10);
+
+        let expected = HashAlgorithm::Sha256
+            .hash_reader(&b"abcdefghijkl"[..])
+            .unwrap();
+        assert_eq!(hasher.finish(&sink, 12).unwrap(), expected);
+    }
+}
@@ -0,0 +1,322 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+#[cfg(unix)]
+use std::os::unix::fs::FileExt;
+#[cfg(windows)]
+use std::os::windows::fs::FileExt as WindowsFileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{anyhow, Context, Result};
+
+use crate::util::ensure_parent_dir;
+
+#[cfg(target_os = "linux")]
+use nix::errno::Errno;
+#[cfg(target_os = "linux")]
+use nix::fcntl::{fallocate, FallocateFlags};
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+
+/// Destination for downloaded bytes.
+///
+/// Segments finish out of order, so every write carries its absolute offset.
+/// Implementations must tolerate concurrent writes to disjoint ranges.
+pub trait OutputSink: Send + Sync {
+    /// Opens the sink and returns the number of bytes it already holds.
+    fn open(&self) -> Result<u64>;
+
+    /// Resizes the sink to exactly `size` bytes, preallocating storage where
+    /// the backend supports it.
+    fn set_len(&self, size: u64) -> Result<()>;
+
+    fn write_all_at(&self, buf: &[u8], position: u64) -> io::Result<()>;
+
+    /// Reads back previously written data; used for checksum verification.
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize>;
+
+    fn sync(&self) -> io::Result<()>;
+
+    /// Whether data survives the process, i.e. a part map is worth keeping
+    /// for `--resume`.
+    fn is_persistent(&self) -> bool {
+        true
+    }
+
+    /// Whether [`OutputSink::read_at`] returns the written data.
+    fn is_readable(&self) -> bool {
+        true
+    }
+
+    /// Filesystem location of the output, if any.
+    fn path(&self) -> Option<&Path> {
+        None
+    }
+}
+
+/// Writes to a file, a block device or any other pre-opened file descriptor.
+pub struct FileSink {
+    path: PathBuf,
+    file: OnceLock<File>,
+}
+
+impl FileSink {
+    /// Creates a sink that opens (or creates) `path` when the download starts.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            file: OnceLock::new(),
+        }
+    }
+
+    /// Wraps an already opened file. It must be readable and writable.
+    pub fn from_file(path: impl Into<PathBuf>, file: File) -> Self {
+        let sink = Self::new(path);
+        let _ = sink.file.set(file);
+        sink
+    }
+
+    fn file(&self) -> io::Result<&File> {
+        self.file
+            .get()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "output file is not open"))
+    }
+
+    fn is_regular(&self) -> bool {
+        match self.file.get() {
+            Some(file) => file.metadata().map(|m| m.is_file()).unwrap_or(false),
+            None => self.path.metadata().map(|m| m.is_file()).unwrap_or(true),
+        }
+    }
+}
+
+impl OutputSink for FileSink {
+    fn open(&self) -> Result<u64> {
+        if self.file.get().is_none() {
+            ensure_parent_dir(&self.path)?;
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .read(true)
+                .open(&self.path)
+                .with_context(|| format!("failed to open {:?}", self.path))?;
+            let _ = self.file.set(file);
+        }
+        Ok(self.file()?.metadata()?.len())
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        let file = self.file()?;
+        // Block and character devices have a fixed size.
+        if !file.metadata()?.is_file() {
+            return Ok(());
+        }
+        preallocate(file, size)
+    }
+
+    #[cfg(unix)]
+    fn write_all_at(&self, buf: &[u8], position: u64) -> io::Result<()> {
+        self.file()?.write_all_at(buf, position)
+    }
+
+    #[cfg(windows)]
+    fn write_all_at(&self, mut buf: &[u8], mut position: u64) -> io::Result<()> {
+        let file = self.file()?;
+        while !buf.is_empty() {
+            let written = file.seek_write(buf, position)?;
+            if written == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    "failed to write segment data",
+                ));
+            }
+            buf = &buf[written..];
+            position += written as u64;
+        }
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        FileExt::read_at(self.file()?, buf, position)
+    }
+
+    #[cfg(windows)]
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        self.file()?.seek_read(buf, position)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        match self.file()?.sync_all() {
+            // Character devices such as /dev/null cannot be synced.
+            Err(err) if err.kind() == io::ErrorKind::InvalidInput && !self.is_regular() => Ok(()),
+            other => other,
+        }
+    }
+
+    fn is_persistent(&self) -> bool {
+        self.is_regular()
+    }
+
+    fn path(&self) -> Option<&Path> {
+        Some(&self.path)
+    }
+}
+
+fn preallocate(file: &File, size: u64) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    if size > 0 {
+        if let Err(err) = fallocate(
+            file.as_raw_fd(),
+            FallocateFlags::FALLOC_FL_KEEP_SIZE,
+            0,
+            size as i64,
+        ) {
+            if err != Errno::ENOTSUP && err != Errno::EINVAL {
+                return Err(anyhow!("fallocate failed: {err}"));
+            }
+        }
+    }
+
+    file.set_len(size)?;
+    Ok(())
+}
+
+/// Collects the download in memory. Clones share the same buffer, so keep one
+/// to read the data after the download finishes.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    buffer: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Copy of the bytes written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.buffer.lock().unwrap().clone()
+    }
+}
+
+impl OutputSink for MemorySink {
+    fn open(&self) -> Result<u64> {
+        Ok(self.buffer.lock().unwrap().len() as u64)
+    }
+
+    fn set_len(&self, size: u64) -> Result<()> {
+        let size = usize::try_from(size)
+            .map_err(|_| anyhow!("{size} bytes do not fit in memory on this platform"))?;
+        self.buffer.lock().unwrap().resize(size, 0);
+        Ok(())
+    }
+
+    fn write_all_at(&self, buf: &[u8], position: u64) -> io::Result<()> {
+        let mut buffer = self.buffer.lock().unwrap();
+        let start = position as usize;
+        let end = start + buf.len();
+        if buffer.len() < end {
+            buffer.resize(end, 0);
+        }
+        buffer[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+
+    fn read_at(&self, buf: &mut [u8], position: u64) -> io::Result<usize> {
+        let buffer = self.buffer.lock().unwrap();
+        let start = (position as usize).min(buffer.len());
+        let len = buf.len().min(buffer.len() - start);
+        buf[..len].copy_from_slice(&buffer[start..start + len]);
+        Ok(len)
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+}
+
+/// Discards everything; useful for measuring raw throughput.
+#[derive(Clone, Copy, Default)]
+pub struct NullSink;
+
+impl OutputSink for NullSink {
+    fn open(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    fn set_len(&self, _size: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn write_all_at(&self, _buf: &[u8], _position: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read_at(&self, _buf: &mut [u8], _position: u64) -> io::Result<usize> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "null sink discards its data",
+        ))
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn is_persistent(&self) -> bool {
+        false
+    }
+
+    fn is_readable(&self) -> bool {
+        false
+    }
+}
+
+/// Sequential [`Read`] adapter over a sink.
+pub struct SinkReader<'a> {
+    sink: &'a dyn OutputSink,
+    position: u64,
+}
+
+impl<'a> SinkReader<'a> {
+    pub fn new(sink: &'a dyn OutputSink) -> Self {
+        Self::at(sink, 0)
+    }
+
+    /// Starts reading at byte `position`.
+    pub fn at(sink: &'a dyn OutputSink, position: u64) -> Self {
+        Self { sink, position }
+    }
+}
+
+impl Read for SinkReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.sink.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_sink_accepts_out_of_order_writes() {
+        let sink = MemorySink::new();
+        sink.set_len(6).unwrap();
+        sink.write_all_at(b"def", 3).unwrap();
+        sink.write_all_at(b"abc", 0).unwrap();
+
+        let mut read = Vec::new();
+        SinkReader::new(&sink).read_to_end(&mut read).unwrap();
+        assert_eq!(read, b"abcdef");
+        assert_eq!(sink.contents(), b"abcdef");
+    }
+}
@@ -0,0 +1,41 @@
+//! Async segmented file downloader.
+//!
+//! The `kdownload` binary is a thin wrapper around this crate: build a
+//! [`DownloadConfig`] (usually through [`DownloadConfig::builder`]), hand it to
+//! [`DownloadManager::new`] and await [`DownloadManager::run`].
+//!
+//! ```no_run
+//! # async fn example() -> anyhow::Result<()> {
+//! use kdownload::{DownloadConfig, DownloadManager};
+//!
+//! let config = DownloadConfig::builder("https://example.com/file.iso")
+//!     .mirror("https://mirror.example.org/file.iso")
+//!     .output("downloads/")
+//!     .build()?;
+//! let outcome = DownloadManager::new(config)?.run().await?;
+//! println!("{} bytes in {:?}", outcome.bytes, outcome.duration);
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod batch;
+pub mod checksum;
+pub mod daemon;
+pub mod download;
+pub mod metalink;
+pub mod pieces;
+pub mod progress;
+pub mod scheduler;
+pub mod signature;
+pub mod util;
+
+#[cfg(test)]
+mod test_server;
+
+pub use checksum::ChecksumSpec;
+pub use download::{
+    ChangePolicy, DownloadConfig, DownloadConfigBuilder, DownloadManager, DownloadOutcome,
+    Durability, FileSink, MemorySink, MirrorFailure, NullSink, OutputSink, ProgressMode,
+    ResumeSource,
+};
+pub use signature::SignatureSpec;
@@ -1,14 +1,16 @@
-mod checksum;
 mod cli;
-mod download;
-mod progress;
-mod scheduler;
-mod util;
 
-use anyhow::Result;
-use cli::Cli;
-use download::{DownloadConfig, DownloadManager};
-use log::{debug, error, info};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+use cli::{Cli, Command, CtlArgs, CtlCommand, DaemonArgs};
+use kdownload::batch::BatchDownloader;
+use kdownload::daemon::JobInfo;
+use kdownload::pieces::PieceHashes;
+use kdownload::progress::observer_for_mode;
+use kdownload::util::format_bytes;
+use kdownload::{DownloadConfig, DownloadManager};
+use log::{debug, error, info, warn};
 
 #[tokio::main]
 async fn main() {
@@ -26,15 +28,172 @@ async fn run() -> Result<()> {
     init_logger(&cli);
 
     debug!("CLI arguments: {:?}", cli);
-    let config: DownloadConfig = cli.try_into()?;
+    match &cli.command {
+        Some(Command::Daemon(args)) => return run_daemon(args).await,
+        Some(Command::Ctl(args)) => return run_ctl(args).await,
+        Some(Command::Resume(_)) | None => {}
+    }
+    if cli.input_file.is_some() {
+        return run_batch(&cli, cli.batch_configs()?).await;
+    }
+    let config: DownloadConfig = match (&cli.command, &cli.metalink) {
+        (Some(Command::Resume(args)), _) => {
+            let mut configs = cli.resume_configs(&args.dir)?;
+            match configs.len() {
+                0 => return Err(anyhow!("no interrupted downloads in {:?}", args.dir)),
+                1 => configs.remove(0),
+                _ => return run_batch(&cli, configs).await,
+            }
+        }
+        (_, Some(source)) => {
+            let metalink = kdownload::metalink::load(source).await?;
+            let mut configs = cli.metalink_configs(&metalink)?;
+            match configs.len() {
+                0 => return Err(anyhow!("metalink {source} lists no files")),
+                1 => configs.remove(0),
+                _ => return run_batch(&cli, configs).await,
+            }
+        }
+        (_, None) => {
+            let piece_hashes = cli.piece_hashes.clone();
+            let mut config: DownloadConfig = cli.try_into()?;
+            if let Some(source) = piece_hashes {
+                config.piece_hashes = Some(Arc::new(PieceHashes::load(&source).await?));
+            }
+            config
+        }
+    };
 
     let manager = DownloadManager::new(config)?;
-    manager.run().await?;
+    let outcome = manager.run().await?;
+
+    info!(
+        "Download completed successfully: {} in {:.2?}",
+        format_bytes(outcome.bytes),
+        outcome.duration
+    );
+    for failure in &outcome.mirror_failures {
+        warn!(
+            "mirror {} failed {} time(s): {}",
+            failure.url, failure.failures, failure.error
+        );
+    }
+    Ok(())
+}
+
+async fn run_batch(cli: &Cli, configs: Vec<DownloadConfig>) -> Result<()> {
+    let count = configs.len();
+    let mut batch = BatchDownloader::new(
+        configs,
+        cli.max_concurrent_downloads,
+        cli.connection_budget(),
+    );
+    if let Some(observer) = observer_for_mode(cli.progress_mode()) {
+        batch = batch.with_observer(observer);
+    }
+    let items = batch.run().await?;
+
+    let failed = items.iter().filter(|item| item.result.is_err()).count();
+    let bytes: u64 = items
+        .iter()
+        .filter_map(|item| item.result.as_ref().ok())
+        .map(|outcome| outcome.bytes)
+        .sum();
+    info!(
+        "{} of {count} downloads completed ({})",
+        count - failed,
+        format_bytes(bytes)
+    );
+    if failed > 0 {
+        return Err(anyhow!("{failed} of {count} downloads failed"));
+    }
+    Ok(())
+}
 
-    info!("Download completed successfully");
+#[cfg(unix)]
+async fn run_daemon(args: &DaemonArgs) -> Result<()> {
+    use anyhow::Context;
+    use kdownload::daemon::{rpc, server, Daemon};
+
+    let daemon = Daemon::new(args.settings()?)?;
+    let socket = args.socket_path();
+    let control = server::serve(daemon.clone(), &socket);
+    match args.rpc_listen {
+        Some(addr) => {
+            let listener = std::net::TcpListener::bind(addr)
+                .with_context(|| format!("failed to bind JSON-RPC address {addr}"))?;
+            let settings = rpc::RpcSettings {
+                secret: args.rpc_secret.clone(),
+                allowed_origins: args.rpc_allow_origin.clone(),
+            };
+            let rpc = rpc::serve(daemon, listener, settings);
+            tokio::try_join!(control, rpc).map(|_| ())
+        }
+        None => control.await,
+    }
+}
+
+#[cfg(unix)]
+async fn run_ctl(args: &CtlArgs) -> Result<()> {
+    use kdownload::daemon::server;
+
+    let response = server::send(&args.socket_path(), &args.command.request()).await?;
+    match &args.command {
+        CtlCommand::Add { .. } => {
+            if let Some(id) = response.id {
+                println!("{id}");
+            }
+        }
+        CtlCommand::List { json } => {
+            let jobs = response.jobs.unwrap_or_default();
+            if *json {
+                println!("{}", serde_json::to_string_pretty(&jobs)?);
+            } else {
+                print_jobs(&jobs);
+            }
+        }
+        _ => {}
+    }
     Ok(())
 }
 
+#[cfg(not(unix))]
+async fn run_daemon(_args: &DaemonArgs) -> Result<()> {
+    Err(anyhow!("the daemon is only supported on Unix"))
+}
+
+#[cfg(not(unix))]
+async fn run_ctl(_args: &CtlArgs) -> Result<()> {
+    Err(anyhow!("the daemon is only supported on Unix"))
+}
+
+fn print_jobs(jobs: &[JobInfo]) {
+    println!(
+        "{:>4}  {:<9}  {:>4}  {:>21}  {:>12}  {:>5}  OUTPUT",
+        "ID", "STATE", "PRIO", "PROGRESS", "SPEED", "CONNS"
+    );
+    for job in jobs {
+        let progress = match job.total {
+            Some(total) => format!("{} / {}", format_bytes(job.downloaded), format_bytes(total)),
+            None => format_bytes(job.downloaded),
+        };
+        let state = format!("{:?}", job.state).to_lowercase();
+        println!(
+            "{:>4}  {:<9}  {:>4}  {:>21}  {:>10}/s  {:>5}  {}",
+            job.id,
+            state,
+            job.priority,
+            progress,
+            format_bytes(job.speed),
+            job.connections,
+            job.output.display()
+        );
+        if let Some(err) = &job.error {
+            println!("      {err}");
+        }
+    }
+}
+
 fn init_logger(cli: &Cli) {
     use env_logger::Env;
     use log::LevelFilter;
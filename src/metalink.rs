@@ -0,0 +1,325 @@
+//! Metalink input: RFC 5854 (`.meta4`) and the older v3 format (`.metalink`).
+
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::debug;
+use roxmltree::{Document, Node};
+
+use crate::util::read_text_source;
+
+const NS_V4: &str = "urn:ietf:params:xml:ns:metalink";
+const NS_V3: &str = "http://www.metalinker.org/";
+
+/// Priority given to URLs that do not declare one; lower is preferred.
+const DEFAULT_PRIORITY: u32 = 999_999;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metalink {
+    pub files: Vec<MetalinkFile>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetalinkFile {
+    /// Relative path of the file; never absolute and never contains `..`.
+    pub name: PathBuf,
+    /// Authoritative size in bytes.
+    pub size: Option<u64>,
+    pub hashes: Vec<MetalinkHash>,
+    pub pieces: Option<MetalinkPieces>,
+    pub urls: Vec<MetalinkUrl>,
+}
+
+/// Whole-file digest. `algorithm` uses the RFC 5854 names (`sha-256`,
+/// `sha-1`, `md5`, ...), also for v3 documents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetalinkHash {
+    pub algorithm: String,
+    pub value: String,
+}
+
+/// Digests of consecutive `length`-byte pieces of the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetalinkPieces {
+    pub algorithm: String,
+    pub length: u64,
+    pub hashes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetalinkUrl {
+    pub url: String,
+    /// 1 is the most preferred. v3 `preference` values are mapped onto it.
+    pub priority: u32,
+    /// ISO 3166-1 country code of the mirror.
+    pub location: Option<String>,
+}
+
+impl MetalinkFile {
+    /// Digest for `algorithm`, if the file lists one.
+    pub fn hash(&self, algorithm: &str) -> Option<&str> {
+        self.hashes
+            .iter()
+            .find(|hash| hash.algorithm == algorithm)
+            .map(|hash| hash.value.as_str())
+    }
+
+    /// URLs ordered for a [`MirrorPool`](crate::download::MirrorPool): mirrors
+    /// in one of `locations` first, then by priority, keeping document order
+    /// among equals.
+    pub fn ordered_urls(&self, locations: &[String]) -> Vec<String> {
+        let mut urls: Vec<&MetalinkUrl> = self.urls.iter().collect();
+        urls.sort_by_key(|url| {
+            let preferred = url.location.as_ref().is_some_and(|location| {
+                locations
+                    .iter()
+                    .any(|wanted| wanted.eq_ignore_ascii_case(location))
+            });
+            (!preferred, url.priority)
+        });
+        urls.into_iter().map(|url| url.url.clone()).collect()
+    }
+}
+
+/// Reads a metalink from a local path or an http(s) URL.
+pub async fn load(source: &str) -> Result<Metalink> {
+    let text = read_text_source(source).await?;
+    parse(&text).with_context(|| format!("invalid metalink {source}"))
+}
+
+/// Parses a v4 or v3 metalink document.
+pub fn parse(text: &str) -> Result<Metalink> {
+    let document = Document::parse(text)?;
+    let root = document.root_element();
+    if root.tag_name().name() != "metalink" {
+        bail!(
+            "root element is <{}>, not <metalink>",
+            root.tag_name().name()
+        );
+    }
+    let files = match root.tag_name().namespace() {
+        Some(NS_V4) => children(root, "file").map(parse_v4_file).collect(),
+        Some(NS_V3) => children(root, "files")
+            .flat_map(|files| children(files, "file"))
+            .map(parse_v3_file)
+            .collect(),
+        other => Err(anyhow!("unknown metalink namespace {other:?}")),
+    }?;
+    Ok(Metalink { files })
+}
+
+fn parse_v4_file(node: Node) -> Result<MetalinkFile> {
+    let mut file = MetalinkFile {
+        name: file_name(node)?,
+        ..MetalinkFile::default()
+    };
+    for child in node.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "size" => file.size = Some(parse_number(child)?),
+            "hash" => file.hashes.push(parse_hash(child)?),
+            "pieces" => file.pieces = Some(parse_pieces(child)?),
+            "url" => {
+                let priority = match child.attribute("priority") {
+                    Some(value) => value.parse().context("invalid url priority")?,
+                    None => DEFAULT_PRIORITY,
+                };
+                push_url(&mut file, child, priority);
+            }
+            _ => {}
+        }
+    }
+    Ok(file)
+}
+
+fn parse_v3_file(node: Node) -> Result<MetalinkFile> {
+    let mut file = MetalinkFile {
+        name: file_name(node)?,
+        ..MetalinkFile::default()
+    };
+    for child in node.children().filter(Node::is_element) {
+        match child.tag_name().name() {
+            "size" => file.size = Some(parse_number(child)?),
+            "verification" => {
+                for entry in child.children().filter(Node::is_element) {
+                    match entry.tag_name().name() {
+                        "hash" => file.hashes.push(parse_hash(entry)?),
+                        "pieces" => file.pieces = Some(parse_pieces(entry)?),
+                        _ => {}
+                    }
+                }
+            }
+            "resources" => {
+                for url in children(child, "url") {
+                    // v3 preferences run from 100 (best) down to 1.
+                    let priority = match url.attribute("preference") {
+                        Some(value) => {
+                            let preference: u32 =
+                                value.parse().context("invalid url preference")?;
+                            101u32.saturating_sub(preference.min(100))
+                        }
+                        None => DEFAULT_PRIORITY,
+                    };
+                    push_url(&mut file, url, priority);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(file)
+}
+
+fn push_url(file: &mut MetalinkFile, node: Node, priority: u32) {
+    let url = node.text().unwrap_or_default().trim();
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        debug!("skipping unsupported metalink URL {url:?}");
+        return;
+    }
+    file.urls.push(MetalinkUrl {
+        url: url.to_string(),
+        priority,
+        location: node.attribute("location").map(str::to_ascii_lowercase),
+    });
+}
+
+fn parse_hash(node: Node) -> Result<MetalinkHash> {
+    Ok(MetalinkHash {
+        algorithm: algorithm_name(node)?,
+        value: node.text().unwrap_or_default().trim().to_ascii_lowercase(),
+    })
+}
+
+fn parse_pieces(node: Node) -> Result<MetalinkPieces> {
+    let length = node
+        .attribute("length")
+        .ok_or_else(|| anyhow!("<pieces> without length"))?
+        .parse()
+        .context("invalid piece length")?;
+    if length == 0 {
+        bail!("piece length must be positive");
+    }
+    Ok(MetalinkPieces {
+        algorithm: algorithm_name(node)?,
+        length,
+        hashes: children(node, "hash")
+            .map(|hash| hash.text().unwrap_or_default().trim().to_ascii_lowercase())
+            .collect(),
+    })
+}
+
+/// Maps v3 names such as `sha256` onto the RFC 5854 registry names.
+fn algorithm_name(node: Node) -> Result<String> {
+    let name = node
+        .attribute("type")
+        .ok_or_else(|| anyhow!("<{}> without type", node.tag_name().name()))?
+        .to_ascii_lowercase();
+    Ok(match name.as_str() {
+        "sha1" => "sha-1".to_string(),
+        "sha256" => "sha-256".to_string(),
+        "sha384" => "sha-384".to_string(),
+        "sha512" => "sha-512".to_string(),
+        _ => name,
+    })
+}
+
+/// The name must stay inside the output directory.
+fn file_name(node: Node) -> Result<PathBuf> {
+    let name = node
+        .attribute("name")
+        .ok_or_else(|| anyhow!("<file> without name"))?;
+    let path = Path::new(name);
+    let safe = path
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    if !safe || name.is_empty() {
+        bail!("unsafe file name {name:?}");
+    }
+    Ok(path.to_path_buf())
+}
+
+fn parse_number(node: Node) -> Result<u64> {
+    node.text()
+        .unwrap_or_default()
+        .trim()
+        .parse()
+        .with_context(|| format!("invalid <{}>", node.tag_name().name()))
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    name: &'static str,
+) -> impl Iterator<Item = Node<'a, 'input>> {
+    node.children()
+        .filter(move |child| child.is_element() && child.tag_name().name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_v4_mirrors_and_hashes() {
+        let doc = r#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="images/disk.img">
+    <size>1048576</size>
+    <hash type="sha-256">ABCDEF</hash>
+    <pieces length="262144" type="sha-1">
+      <hash>01</hash><hash>02</hash><hash>03</hash><hash>04</hash>
+    </pieces>
+    <url location="us" priority="2">https://us.example/disk.img</url>
+    <url location="de" priority="1">https://de.example/disk.img</url>
+    <url>ftp://ftp.example/disk.img</url>
+    <url>https://any.example/disk.img</url>
+  </file>
+</metalink>"#;
+        let metalink = parse(doc).expect("parse");
+        let file = &metalink.files[0];
+        assert_eq!(file.name, PathBuf::from("images/disk.img"));
+        assert_eq!(file.size, Some(1_048_576));
+        assert_eq!(file.hash("sha-256"), Some("abcdef"));
+        assert_eq!(file.pieces.as_ref().unwrap().hashes.len(), 4);
+        assert_eq!(
+            file.ordered_urls(&[]),
+            vec![
+                "https://de.example/disk.img",
+                "https://us.example/disk.img",
+                "https://any.example/disk.img",
+            ]
+        );
+        assert_eq!(
+            file.ordered_urls(&["US".to_string()])[0],
+            "https://us.example/disk.img"
+        );
+    }
+
+    #[test]
+    fn parses_v3_preferences() {
+        let doc = r#"<metalink version="3.0" xmlns="http://www.metalinker.org/">
+  <files>
+    <file name="tool.tar.gz">
+      <size>42</size>
+      <verification>
+        <hash type="sha256">aa</hash>
+        <pieces length="16" type="sha1"><hash piece="0">bb</hash></pieces>
+      </verification>
+      <resources>
+        <url type="http" preference="10">http://slow.example/tool.tar.gz</url>
+        <url type="http" preference="100" location="fr">http://fast.example/tool.tar.gz</url>
+      </resources>
+    </file>
+  </files>
+</metalink>"#;
+        let file = &parse(doc).expect("parse").files[0];
+        assert_eq!(file.hash("sha-256"), Some("aa"));
+        assert_eq!(file.pieces.as_ref().unwrap().algorithm, "sha-1");
+        assert_eq!(file.ordered_urls(&[])[0], "http://fast.example/tool.tar.gz");
+    }
+
+    #[test]
+    fn rejects_escaping_file_names() {
+        let doc = r#"<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="../../etc/passwd"><url>https://a.example/x</url></file>
+</metalink>"#;
+        assert!(parse(doc).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Piece hashes: digests of byte ranges that can be checked while a download
+//! is still running.
+//!
+//! Sources are Metalink `<pieces>`, bmap files (`<BlockMap>` ranges) and a
+//! plain sidecar list:
+//!
+//! ```text
+//! # <algorithm> <piece length>, then one hex digest per piece
+//! sha-256 1048576
+//! 6b86b273ff34fce19d6b804eff5a3f5747ada4ea22f1d49c01e52ddb7875b4b
+//! ...
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use roxmltree::Document;
+
+use crate::checksum::{decode_digest, HashAlgorithm};
+use crate::download::{OutputSink, SinkReader};
+use crate::metalink::MetalinkPieces;
+use crate::util::read_text_source;
+
+/// Digest of the inclusive byte range `start..=end`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    pub start: u64,
+    pub end: u64,
+    pub digest: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceHashes {
+    pub algorithm: HashAlgorithm,
+    pub pieces: Vec<Piece>,
+}
+
+impl PieceHashes {
+    /// Consecutive pieces of `length` bytes starting at offset 0. The last
+    /// piece is cut at the end of the file.
+    pub fn uniform<S: AsRef<str>>(
+        algorithm: HashAlgorithm,
+        length: u64,
+        digests: &[S],
+    ) -> Result<Self> {
+        if length == 0 {
+            bail!("piece length must be positive");
+        }
+        let pieces = digests
+            .iter()
+            .enumerate()
+            .map(|(index, digest)| {
+                let start = index as u64 * length;
+                Ok(Piece {
+                    start,
+                    end: start + length - 1,
+                    digest: decode_digest(algorithm, digest.as_ref())?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { algorithm, pieces })
+    }
+
+    pub fn from_metalink(pieces: &MetalinkPieces) -> Result<Self> {
+        let algorithm = HashAlgorithm::from_name(&pieces.algorithm)?;
+        Self::uniform(algorithm, pieces.length, &pieces.hashes)
+    }
+
+    /// Loads a bmap file or a sidecar list from a path or URL.
+    pub async fn load(source: &str) -> Result<Self> {
+        let text = read_text_source(source).await?;
+        Self::parse(&text).with_context(|| format!("invalid piece hashes in {source}"))
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        if text.trim_start().starts_with('<') {
+            return parse_bmap(text);
+        }
+
+        let mut lines = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'));
+        let header = lines
+            .next()
+            .ok_or_else(|| anyhow!("piece hash list is empty"))?;
+        let (algorithm, length) = header
+            .split_once(char::is_whitespace)
+            .ok_or_else(|| anyhow!("expected '<algorithm> <piece length>', got {header:?}"))?;
+        let algorithm = HashAlgorithm::from_name(algorithm)?;
+        let length = length.trim().parse().context("invalid piece length")?;
+        let digests: Vec<&str> = lines.collect();
+        Self::uniform(algorithm, length, &digests)
+    }
+
+    /// Rejects hashes that describe data beyond the end of the file.
+    pub fn check_size(&self, total: u64) -> Result<()> {
+        match self.pieces.iter().find(|piece| piece.start >= total) {
+            Some(piece) => Err(anyhow!(
+                "piece at offset {} lies beyond the {total}-byte file",
+                piece.start
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Hashes piece `index` as stored in `sink` and compares it.
+    pub fn verify(&self, index: usize, sink: &dyn OutputSink, total: u64) -> Result<bool> {
+        let piece = &self.pieces[index];
+        let end = piece.end.min(total.saturating_sub(1));
+        let reader = std::io::Read::take(
+            SinkReader::at(sink, piece.start),
+            end.saturating_sub(piece.start) + 1,
+        );
+        Ok(self.algorithm.hash_reader(reader)? == piece.digest)
+    }
+}
+
+/// bmap ranges are inclusive block numbers, e.g. `0-15` or `42`.
+fn parse_bmap(text: &str) -> Result<PieceHashes> {
+    let document = Document::parse(text)?;
+    let root = document.root_element();
+    if root.tag_name().name() != "bmap" {
+        bail!("unknown piece hash document <{}>", root.tag_name().name());
+    }
+    let field = |name: &str| {
+        root.children()
+            .find(|node| node.tag_name().name() == name)
+            .and_then(|node| node.text())
+            .map(str::trim)
+    };
+    let block_size: u64 = field("BlockSize")
+        .ok_or_else(|| anyhow!("bmap without BlockSize"))?
+        .parse()
+        .context("invalid BlockSize")?;
+    // bmap 1.x only knows SHA-1 and stores it in a `sha1` attribute.
+    let algorithm = HashAlgorithm::from_name(field("ChecksumType").unwrap_or("sha1"))?;
+    let block_map = root
+        .children()
+        .find(|node| node.tag_name().name() == "BlockMap")
+        .ok_or_else(|| anyhow!("bmap without BlockMap"))?;
+
+    let mut pieces = Vec::new();
+    for range in block_map
+        .children()
+        .filter(|node| node.tag_name().name() == "Range")
+    {
+        let digest = range
+            .attribute("chksum")
+            .or_else(|| range.attribute("sha1"))
+            .ok_or_else(|| anyhow!("bmap range without checksum"))?;
+        let blocks = range.text().unwrap_or_default().trim();
+        let (first, last) = match blocks.split_once('-') {
+            Some((first, last)) => (first.trim().parse()?, last.trim().parse()?),
+            None => {
+                let block: u64 = blocks.parse()?;
+                (block, block)
+            }
+        };
+        pieces.push(Piece {
+            start: first * block_size,
+            end: (last + 1) * block_size - 1,
+            digest: decode_digest(algorithm, digest)?,
+        });
+    }
+    Ok(PieceHashes { algorithm, pieces })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::MemorySink;
+
+    const SHA1_ABC: &str = "a9993e364706816aba3e25717850c26c9cd0d89d";
+
+    #[test]
+    fn verifies_pieces_read_from_a_sink() {
+        let sink = MemorySink::new();
+        sink.write_all_at(b"abcabX", 0).unwrap();
+        let list = format!("# test\nsha1 3\n{SHA1_ABC}\n{SHA1_ABC}\n");
+        let hashes = PieceHashes::parse(&list).expect("parse");
+        assert_eq!(hashes.pieces[1].start, 3);
+        assert!(hashes.verify(0, &sink, 6).unwrap());
+        assert!(!hashes.verify(1, &sink, 6).unwrap());
+    }
+
+    #[test]
+    fn parses_bmap_block_ranges() {
+        let bmap = format!(
+            r#"<?xml version="1.0" ?>
+<bmap version="2.0">
+    <BlockSize> 4096 </BlockSize>
+    <ChecksumType> sha1 </ChecksumType>
+    <BlockMap>
+        <Range chksum="{SHA1_ABC}"> 0-1 </Range>
+        <Range chksum="{SHA1_ABC}"> 5 </Range>
+    </BlockMap>
+</bmap>"#
+        );
+        let hashes = PieceHashes::parse(&bmap).expect("parse");
+        assert_eq!(hashes.pieces[0].end, 8191);
+        assert_eq!(
+            (hashes.pieces[1].start, hashes.pieces[1].end),
+            (20480, 24575)
+        );
+        assert!(hashes.check_size(20480).is_err());
+    }
+}
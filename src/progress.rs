@@ -1,66 +1,126 @@
 use colored::*;
 use indicatif::{ProgressBar, ProgressStyle};
 use std::io::Write;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use reqwest::Url;
 use serde::Serialize;
 use tokio::sync::oneshot;
 use tokio::task::JoinHandle;
 use tokio::time::{interval, MissedTickBehavior};
 
-use crate::download::ProgressMode;
+use crate::download::{MirrorFailure, ProgressMode};
 use crate::scheduler::Scheduler;
 
 const PROGRESS_TICK: Duration = Duration::from_millis(100);
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProgressFinish {
     Success,
     Failure,
 }
 
-pub struct ProgressReporter {
-    stop_tx: Option<oneshot::Sender<ProgressFinish>>,
-    handle: Option<JoinHandle<()>>,
+/// Typed events emitted by a [`DownloadManager`](crate::DownloadManager)
+/// over the lifetime of a download.
+#[derive(Debug, Clone)]
+pub enum DownloadEvent {
+    Started {
+        urls: Vec<Url>,
+        output: PathBuf,
+    },
+    MetadataProbed {
+        url: Url,
+        total_bytes: Option<u64>,
+        supports_ranges: bool,
+    },
+    SegmentStarted {
+        id: usize,
+        url: Url,
+        start: u64,
+        end: u64,
+    },
+    SegmentCompleted {
+        id: usize,
+        bytes: u64,
+        duration: Duration,
+    },
+    SegmentRetried {
+        id: usize,
+        attempt: usize,
+        error: String,
+    },
+    MirrorSwitched {
+        segment: usize,
+        from: Url,
+        to: Url,
+    },
+    /// Segment `id` was cut at offset `at`; segment `new_id` covers the rest.
+    SegmentSplit {
+        id: usize,
+        new_id: usize,
+        at: u64,
+    },
+    /// `url` failed too often for `segment` and is not tried for it again.
+    MirrorFailed {
+        segment: usize,
+        url: Url,
+        error: String,
+    },
+    /// A piece failed its hash check; the segments holding it are fetched
+    /// again.
+    PieceFailed {
+        index: usize,
+        start: u64,
+        end: u64,
+    },
+    /// Periodic transfer statistics, emitted every progress tick.
+    Progress(ProgressSnapshot),
+    Verifying {
+        algorithm: &'static str,
+    },
+    Finished {
+        result: ProgressFinish,
+        snapshot: ProgressSnapshot,
+        /// Mirrors that failed at least one request during the download.
+        mirror_failures: Vec<MirrorFailure>,
+    },
+    /// One file of a batch finished; the batch itself ends with `Finished`.
+    FileFinished {
+        output: PathBuf,
+        result: ProgressFinish,
+        bytes: u64,
+    },
 }
 
-impl ProgressReporter {
-    pub fn spawn(
-        mode: ProgressMode,
-        total_bytes: Option<u64>,
-        initial_downloaded: u64,
-        progress: Arc<AtomicU64>,
-        scheduler: Option<Arc<Scheduler>>,
-    ) -> Option<Self> {
-        match mode {
-            ProgressMode::Quiet => None,
-            ProgressMode::Text => Some(Self::spawn_text(
-                total_bytes,
-                initial_downloaded,
-                progress,
-                scheduler,
-            )),
-            ProgressMode::Json => Some(Self::spawn_json(
-                total_bytes,
-                initial_downloaded,
-                progress,
-                scheduler,
-            )),
-        }
-    }
+/// Receives [`DownloadEvent`]s as they happen.
+///
+/// Implementations are called from download tasks and must not block.
+pub trait ProgressObserver: Send + Sync {
+    fn on_event(&self, event: &DownloadEvent);
+}
 
-    pub async fn finish(mut self, finish: ProgressFinish) {
-        if let Some(tx) = self.stop_tx.take() {
-            let _ = tx.send(finish);
-        }
-        if let Some(handle) = self.handle.take() {
-            let _ = handle.await;
-        }
+/// Returns the built-in observer for `mode`, if it renders anything.
+pub fn observer_for_mode(mode: ProgressMode) -> Option<Arc<dyn ProgressObserver>> {
+    match mode {
+        ProgressMode::Quiet => None,
+        ProgressMode::Text => Some(Arc::new(TextObserver::new())),
+        ProgressMode::Json => Some(Arc::new(JsonObserver::new())),
     }
+}
+
+/// Periodically samples the shared byte counter and forwards
+/// [`DownloadEvent::Progress`] to an observer.
+pub struct ProgressReporter {
+    stop_tx: Option<oneshot::Sender<()>>,
+    handle: Option<JoinHandle<ProgressSnapshot>>,
+}
 
-    fn spawn_text(
+impl ProgressReporter {
+    pub fn spawn(
+        observer: Arc<dyn ProgressObserver>,
         total_bytes: Option<u64>,
         initial_downloaded: u64,
         progress: Arc<AtomicU64>,
@@ -70,7 +130,6 @@ impl ProgressReporter {
         let handle = tokio::spawn(async move {
             let mut ticker = interval(PROGRESS_TICK);
             ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut renderer = TextRenderer::new(total_bytes);
             let start = Instant::now();
 
             loop {
@@ -82,20 +141,19 @@ impl ProgressReporter {
                             start,
                             &progress,
                             scheduler.as_ref()
-                        ).await;
-                        renderer.render(&snapshot, None);
+                        );
+                        observer.on_event(&DownloadEvent::Progress(snapshot));
                     }
-                    result = &mut stop_rx => {
-                        let finish = result.unwrap_or(ProgressFinish::Failure);
+                    _ = &mut stop_rx => {
                         let snapshot = build_snapshot(
                             total_bytes,
                             initial_downloaded,
                             start,
                             &progress,
                             scheduler.as_ref()
-                        ).await;
-                        renderer.render(&snapshot, Some(finish));
-                        break;
+                        );
+                        observer.on_event(&DownloadEvent::Progress(snapshot.clone()));
+                        return snapshot;
                     }
                 }
             }
@@ -107,50 +165,15 @@ impl ProgressReporter {
         }
     }
 
-    fn spawn_json(
-        total_bytes: Option<u64>,
-        initial_downloaded: u64,
-        progress: Arc<AtomicU64>,
-        scheduler: Option<Arc<Scheduler>>,
-    ) -> Self {
-        let (stop_tx, mut stop_rx) = oneshot::channel();
-        let handle = tokio::spawn(async move {
-            let mut ticker = interval(PROGRESS_TICK);
-            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
-            let mut renderer = JsonRenderer::new();
-            let start = Instant::now();
-
-            loop {
-                tokio::select! {
-                    _ = ticker.tick() => {
-                        let snapshot = build_snapshot(
-                            total_bytes,
-                            initial_downloaded,
-                            start,
-                            &progress,
-                            scheduler.as_ref()
-                        ).await;
-                        renderer.render(&snapshot, JsonRenderKind::Progress);
-                    }
-                    result = &mut stop_rx => {
-                        let finish = result.unwrap_or(ProgressFinish::Failure);
-                        let snapshot = build_snapshot(
-                            total_bytes,
-                            initial_downloaded,
-                            start,
-                            &progress,
-                            scheduler.as_ref()
-                        ).await;
-                        renderer.render(&snapshot, JsonRenderKind::Finish(finish));
-                        break;
-                    }
-                }
-            }
-        });
-
-        Self {
-            stop_tx: Some(stop_tx),
-            handle: Some(handle),
+    /// Stops the ticker after emitting one last progress event, returning
+    /// the final snapshot.
+    pub async fn finish(mut self) -> Option<ProgressSnapshot> {
+        if let Some(tx) = self.stop_tx.take() {
+            let _ = tx.send(());
+        }
+        match self.handle.take() {
+            Some(handle) => handle.await.ok(),
+            None => None,
         }
     }
 }
@@ -163,18 +186,20 @@ impl Drop for ProgressReporter {
     }
 }
 
-struct ProgressSnapshot {
-    downloaded: u64,
-    total: Option<u64>,
-    initial: u64,
-    elapsed: Duration,
-    segments_active: Option<usize>,
-    segments_pending: Option<usize>,
-    target_parallelism: Option<usize>,
+#[derive(Debug, Clone, Default)]
+pub struct ProgressSnapshot {
+    pub downloaded: u64,
+    pub total: Option<u64>,
+    pub initial: u64,
+    pub elapsed: Duration,
+    pub segments_active: Option<usize>,
+    pub segments_pending: Option<usize>,
+    pub target_parallelism: Option<usize>,
 }
 
 impl ProgressSnapshot {
-    fn throughput(&self) -> f64 {
+    /// Average bytes per second transferred in this session.
+    pub fn throughput(&self) -> f64 {
         let elapsed = self.elapsed.as_secs_f64();
         if elapsed <= f64::EPSILON {
             return 0.0;
@@ -183,7 +208,7 @@ impl ProgressSnapshot {
     }
 }
 
-async fn build_snapshot(
+fn build_snapshot(
     total: Option<u64>,
     initial: u64,
     start: Instant,
@@ -191,10 +216,7 @@ async fn build_snapshot(
     scheduler: Option<&Arc<Scheduler>>,
 ) -> ProgressSnapshot {
     let downloaded = progress.load(Ordering::Relaxed);
-    let scheduler_snapshot = match scheduler {
-        Some(s) => Some(s.snapshot()),
-        None => None,
-    };
+    let scheduler_snapshot = scheduler.map(|s| s.snapshot());
 
     ProgressSnapshot {
         downloaded,
@@ -207,13 +229,14 @@ async fn build_snapshot(
     }
 }
 
-struct TextRenderer {
+/// Renders a progress bar on the terminal.
+pub struct TextObserver {
     progress_bar: ProgressBar,
 }
 
-impl TextRenderer {
-    fn new(total_bytes: Option<u64>) -> Self {
-        let pb = ProgressBar::new(total_bytes.unwrap_or(0));
+impl TextObserver {
+    pub fn new() -> Self {
+        let pb = ProgressBar::new(0);
         pb.set_style(
             ProgressStyle::default_bar()
                 .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})")
@@ -222,41 +245,89 @@ impl TextRenderer {
         );
         Self { progress_bar: pb }
     }
+}
 
-    fn render(&mut self, snapshot: &ProgressSnapshot, finish: Option<ProgressFinish>) {
-        self.progress_bar.set_position(snapshot.downloaded);
-        if let Some(finish) = finish {
-            match finish {
-                ProgressFinish::Success => self.progress_bar.finish_with_message("Download complete".green().to_string()),
-                ProgressFinish::Failure => self.progress_bar.finish_with_message("Download failed".red().to_string()),
+impl Default for TextObserver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressObserver for TextObserver {
+    fn on_event(&self, event: &DownloadEvent) {
+        match event {
+            DownloadEvent::MetadataProbed { total_bytes, .. } => {
+                self.progress_bar.set_length(total_bytes.unwrap_or(0));
+            }
+            DownloadEvent::Progress(snapshot) => {
+                if let Some(total) = snapshot.total {
+                    self.progress_bar.set_length(total);
+                }
+                self.progress_bar.set_position(snapshot.downloaded);
+            }
+            DownloadEvent::Finished {
+                result, snapshot, ..
+            } => {
+                self.progress_bar.set_position(snapshot.downloaded);
+                match result {
+                    ProgressFinish::Success => self
+                        .progress_bar
+                        .finish_with_message("Download complete".green().to_string()),
+                    ProgressFinish::Failure => self
+                        .progress_bar
+                        .finish_with_message("Download failed".red().to_string()),
+                }
             }
+            _ => {}
         }
     }
 }
 
+/// Writes every event to stdout as a line of JSON.
+pub struct JsonObserver;
 
-struct JsonRenderer;
-
-impl JsonRenderer {
-    fn new() -> Self {
+impl JsonObserver {
+    pub fn new() -> Self {
         Self
     }
+}
 
-    fn render(&mut self, snapshot: &ProgressSnapshot, kind: JsonRenderKind) {
-        let event = match kind {
-            JsonRenderKind::Progress => JsonProgressEvent::progress(snapshot),
-            JsonRenderKind::Finish(outcome) => JsonProgressEvent::finish(snapshot, outcome),
+impl Default for JsonObserver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressObserver for JsonObserver {
+    fn on_event(&self, event: &DownloadEvent) {
+        let serialized = match event {
+            DownloadEvent::Progress(snapshot) => {
+                serde_json::to_string(&JsonProgressEvent::progress(snapshot))
+            }
+            DownloadEvent::Finished {
+                result,
+                snapshot,
+                mirror_failures,
+            } => serde_json::to_string(&JsonProgressEvent::finish(
+                snapshot,
+                *result,
+                mirror_failures,
+            )),
+            other => serde_json::to_string(&JsonLifecycleEvent::new(other)),
         };
-        if let Ok(serialized) = serde_json::to_string(&event) {
-            println!("{}", serialized);
-            let _ = std::io::stdout().flush();
+        if let Ok(serialized) = serialized {
+            let mut stdout = std::io::stdout().lock();
+            let _ = writeln!(stdout, "{}", serialized);
+            let _ = stdout.flush();
         }
     }
 }
 
-enum JsonRenderKind {
-    Progress,
-    Finish(ProgressFinish),
+fn timestamp_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
 }
 
 #[derive(Serialize)]
@@ -271,6 +342,15 @@ struct JsonProgressEvent {
     active_segments: Option<usize>,
     pending_segments: Option<usize>,
     target_parallelism: Option<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    mirror_failures: Vec<JsonMirrorFailure>,
+}
+
+#[derive(Serialize)]
+struct JsonMirrorFailure {
+    url: String,
+    failures: usize,
+    error: String,
 }
 
 impl JsonProgressEvent {
@@ -278,26 +358,41 @@ impl JsonProgressEvent {
         Self::from_snapshot("progress", snapshot)
     }
 
-    fn finish(snapshot: &ProgressSnapshot, finish: ProgressFinish) -> Self {
+    fn finish(
+        snapshot: &ProgressSnapshot,
+        finish: ProgressFinish,
+        mirror_failures: &[MirrorFailure],
+    ) -> Self {
         let event = match finish {
             ProgressFinish::Success => "complete",
             ProgressFinish::Failure => "failed",
         };
-        Self::from_snapshot(event, snapshot)
+        let mut json = Self::from_snapshot(event, snapshot);
+        json.mirror_failures = mirror_failures
+            .iter()
+            .map(|failure| JsonMirrorFailure {
+                url: failure.url.to_string(),
+                failures: failure.failures,
+                error: failure.error.clone(),
+            })
+            .collect();
+        json
     }
 
     fn from_snapshot(event: &'static str, snapshot: &ProgressSnapshot) -> Self {
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis();
         let elapsed_ms = snapshot.elapsed.as_millis();
-        let fraction = snapshot.total.map(|total| if total > 0 { snapshot.downloaded as f64 / total as f64 } else { 1.0 });
+        let fraction = snapshot.total.map(|total| {
+            if total > 0 {
+                snapshot.downloaded as f64 / total as f64
+            } else {
+                1.0
+            }
+        });
         let bytes_per_second = snapshot.throughput();
 
         JsonProgressEvent {
             event,
-            timestamp_ms: now,
+            timestamp_ms: timestamp_ms(),
             elapsed_ms,
             bytes_downloaded: snapshot.downloaded,
             total_bytes: snapshot.total,
@@ -306,6 +401,202 @@ impl JsonProgressEvent {
             active_segments: snapshot.segments_active,
             pending_segments: snapshot.segments_pending,
             target_parallelism: snapshot.target_parallelism,
+            mirror_failures: Vec::new(),
         }
     }
 }
+
+#[derive(Serialize)]
+struct JsonLifecycleEvent {
+    timestamp_ms: u128,
+    #[serde(flatten)]
+    body: JsonLifecycleBody,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonLifecycleBody {
+    Started {
+        urls: Vec<String>,
+        output: PathBuf,
+    },
+    Metadata {
+        url: String,
+        total_bytes: Option<u64>,
+        supports_ranges: bool,
+    },
+    SegmentStarted {
+        segment: usize,
+        url: String,
+        start: u64,
+        end: u64,
+    },
+    SegmentCompleted {
+        segment: usize,
+        bytes: u64,
+        duration_ms: u128,
+    },
+    SegmentRetried {
+        segment: usize,
+        attempt: usize,
+        error: String,
+    },
+    MirrorSwitched {
+        segment: usize,
+        from: String,
+        to: String,
+    },
+    MirrorFailed {
+        segment: usize,
+        url: String,
+        error: String,
+    },
+    SegmentSplit {
+        segment: usize,
+        new_segment: usize,
+        at: u64,
+    },
+    PieceFailed {
+        piece: usize,
+        start: u64,
+        end: u64,
+    },
+    Verifying {
+        algorithm: &'static str,
+    },
+    FileFinished {
+        output: PathBuf,
+        success: bool,
+        bytes: u64,
+    },
+}
+
+impl JsonLifecycleEvent {
+    fn new(event: &DownloadEvent) -> Self {
+        let body = match event {
+            DownloadEvent::Started { urls, output } => JsonLifecycleBody::Started {
+                urls: urls.iter().map(Url::to_string).collect(),
+                output: output.clone(),
+            },
+            DownloadEvent::MetadataProbed {
+                url,
+                total_bytes,
+                supports_ranges,
+            } => JsonLifecycleBody::Metadata {
+                url: url.to_string(),
+                total_bytes: *total_bytes,
+                supports_ranges: *supports_ranges,
+            },
+            DownloadEvent::SegmentStarted {
+                id,
+                url,
+                start,
+                end,
+            } => JsonLifecycleBody::SegmentStarted {
+                segment: *id,
+                url: url.to_string(),
+                start: *start,
+                end: *end,
+            },
+            DownloadEvent::SegmentCompleted {
+                id,
+                bytes,
+                duration,
+            } => JsonLifecycleBody::SegmentCompleted {
+                segment: *id,
+                bytes: *bytes,
+                duration_ms: duration.as_millis(),
+            },
+            DownloadEvent::SegmentRetried { id, attempt, error } => {
+                JsonLifecycleBody::SegmentRetried {
+                    segment: *id,
+                    attempt: *attempt,
+                    error: error.clone(),
+                }
+            }
+            DownloadEvent::MirrorSwitched { segment, from, to } => {
+                JsonLifecycleBody::MirrorSwitched {
+                    segment: *segment,
+                    from: from.to_string(),
+                    to: to.to_string(),
+                }
+            }
+            DownloadEvent::MirrorFailed {
+                segment,
+                url,
+                error,
+            } => JsonLifecycleBody::MirrorFailed {
+                segment: *segment,
+                url: url.to_string(),
+                error: error.clone(),
+            },
+            DownloadEvent::SegmentSplit { id, new_id, at } => JsonLifecycleBody::SegmentSplit {
+                segment: *id,
+                new_segment: *new_id,
+                at: *at,
+            },
+            DownloadEvent::PieceFailed { index, start, end } => JsonLifecycleBody::PieceFailed {
+                piece: *index,
+                start: *start,
+                end: *end,
+            },
+            DownloadEvent::Verifying { algorithm } => JsonLifecycleBody::Verifying { algorithm },
+            DownloadEvent::FileFinished {
+                output,
+                result,
+                bytes,
+            } => JsonLifecycleBody::FileFinished {
+                output: output.clone(),
+                success: *result == ProgressFinish::Success,
+                bytes: *bytes,
+            },
+            DownloadEvent::Progress(_) | DownloadEvent::Finished { .. } => {
+                unreachable!("snapshot events are rendered by JsonProgressEvent")
+            }
+        };
+        Self {
+            timestamp_ms: timestamp_ms(),
+            body,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lifecycle_events_are_tagged_in_json() {
+        let event = DownloadEvent::SegmentRetried {
+            id: 3,
+            attempt: 2,
+            error: "connection reset".into(),
+        };
+        let value = serde_json::to_value(JsonLifecycleEvent::new(&event)).expect("json");
+        assert_eq!(value["event"], "segment_retried");
+        assert_eq!(value["segment"], 3);
+        assert_eq!(value["attempt"], 2);
+    }
+
+    #[test]
+    fn finish_lists_failed_mirrors() {
+        let failure = MirrorFailure {
+            url: Url::parse("http://mirror/file").unwrap(),
+            failures: 5,
+            error: "HTTP 503".into(),
+        };
+        let snapshot = ProgressSnapshot::default();
+        let value = serde_json::to_value(JsonProgressEvent::finish(
+            &snapshot,
+            ProgressFinish::Success,
+            &[failure],
+        ))
+        .expect("json");
+        assert_eq!(value["event"], "complete");
+        assert_eq!(value["mirror_failures"][0]["url"], "http://mirror/file");
+        assert_eq!(value["mirror_failures"][0]["error"], "HTTP 503");
+
+        let value = serde_json::to_value(JsonProgressEvent::progress(&snapshot)).expect("json");
+        assert!(value.get("mirror_failures").is_none());
+    }
+}
@@ -3,6 +3,9 @@ use std::time::{Duration, Instant};
 
 use std::sync::Mutex;
 
+/// Total throughput must grow by this factor for the ramp to keep doubling.
+const RAMP_GAIN: f64 = 1.1;
+
 #[derive(Debug, Clone)]
 pub struct SegmentTask {
     pub id: usize,
@@ -22,6 +25,7 @@ impl SegmentTask {
         }
     }
 
+    #[allow(clippy::len_without_is_empty)]
     pub fn len(&self) -> u64 {
         self.end.saturating_sub(self.start) + 1
     }
@@ -49,6 +53,13 @@ struct SchedulerState {
     target_parallelism: usize,
     recent_speeds: VecDeque<f64>,
     last_adjustment: Instant,
+    /// Per-connection samples since the last adjustment.
+    interval_speeds: Vec<f64>,
+    /// Slow start: parallelism doubles while total throughput keeps rising,
+    /// then the thresholds take over.
+    ramping: bool,
+    /// Total throughput when the ramp last doubled.
+    ramp_total: f64,
 }
 
 pub struct Scheduler {
@@ -80,11 +91,14 @@ impl Scheduler {
                 target_parallelism: initial_parallelism.clamp(1, max_parallelism.max(1)),
                 recent_speeds: VecDeque::new(),
                 last_adjustment: Instant::now(),
+                interval_speeds: Vec::new(),
+                ramping: true,
+                ramp_total: 0.0,
             }),
             max_parallelism: max_parallelism.max(1),
             throughput_window: 16,
             scale_up_threshold: 8_000_000.0, // ~8 MiB/s per connection (even more aggressive)
-            scale_down_threshold: 50_000.0, // ~50 KiB/s per connection (less sensitive)
+            scale_down_threshold: 50_000.0,  // ~50 KiB/s per connection (less sensitive)
             adjustment_interval: Duration::from_millis(1000), // Even faster adjustment
         }
     }
@@ -107,16 +121,57 @@ impl Scheduler {
         if state.active > 0 {
             state.active -= 1;
         }
-        state.recent_speeds.push_back(stats.throughput());
+        self.adjust(&mut state, stats.throughput());
+    }
+
+    /// Counts an endgame request as an active connection, so it takes a
+    /// slot of the target like a segment does.
+    pub fn on_race_started(&self) {
+        self.state.lock().unwrap().active += 1;
+    }
+
+    /// Frees the slot of an endgame request that finished or was stopped.
+    pub fn on_race_finished(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.active = state.active.saturating_sub(1);
+    }
+
+    /// Feeds a throughput sample taken while segments are still running,
+    /// so parallelism can rise before any of them completes.
+    pub fn record_throughput(&self, bytes_per_sec: f64) {
+        let mut state = self.state.lock().unwrap();
+        self.adjust(&mut state, bytes_per_sec);
+    }
+
+    fn adjust(&self, state: &mut SchedulerState, speed: f64) {
+        state.recent_speeds.push_back(speed);
         if state.recent_speeds.len() > self.throughput_window {
             state.recent_speeds.pop_front();
         }
+        state.interval_speeds.push(speed);
 
         let now = Instant::now();
         if now.duration_since(state.last_adjustment) < self.adjustment_interval {
             return;
         }
         state.last_adjustment = now;
+        let interval_speeds = std::mem::take(&mut state.interval_speeds);
+
+        if state.ramping {
+            let per_conn = interval_speeds.iter().sum::<f64>() / interval_speeds.len() as f64;
+            let total = per_conn * state.active.max(1) as f64;
+            if total >= state.ramp_total * RAMP_GAIN
+                && state.target_parallelism < self.max_parallelism
+            {
+                state.ramp_total = total;
+                // Double, adding at least four connections.
+                let increase = state.target_parallelism.max(4);
+                state.target_parallelism =
+                    (state.target_parallelism + increase).min(self.max_parallelism);
+                return;
+            }
+            state.ramping = false;
+        }
 
         if state.recent_speeds.is_empty() {
             return;
@@ -136,6 +191,28 @@ impl Scheduler {
         }
     }
 
+    /// Puts a segment back at the front of the queue, e.g. after its data
+    /// failed verification.
+    pub fn requeue(&self, segment: SegmentTask) {
+        self.state.lock().unwrap().pending.push_front(segment);
+    }
+
+    /// Whether segment `id` is waiting in the queue.
+    pub fn is_queued(&self, id: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.pending.iter().any(|segment| segment.id == id)
+    }
+
+    /// Connections the target allows beyond those running, when nothing is
+    /// queued for them.
+    pub fn idle_slots(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        if !state.pending.is_empty() {
+            return 0;
+        }
+        state.target_parallelism.saturating_sub(state.active)
+    }
+
     pub fn has_remaining(&self) -> bool {
         let state = self.state.lock().unwrap();
         !state.pending.is_empty() || state.active > 0
@@ -150,3 +227,55 @@ impl Scheduler {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_throughput_opens_idle_slots() {
+        let scheduler = Scheduler::new(Vec::new(), 1, 8);
+        assert_eq!(scheduler.idle_slots(), 1);
+        scheduler.state.lock().unwrap().last_adjustment -= Duration::from_secs(2);
+        scheduler.record_throughput(100_000_000.0);
+        assert_eq!(scheduler.idle_slots(), 5);
+    }
+
+    #[test]
+    fn ramps_up_while_throughput_rises() {
+        let scheduler = Scheduler::new(Vec::new(), 4, 64);
+        let step = |per_conn: f64, active: usize| {
+            let mut state = scheduler.state.lock().unwrap();
+            state.active = active;
+            state.last_adjustment -= Duration::from_secs(2);
+            drop(state);
+            scheduler.record_throughput(per_conn);
+            scheduler.snapshot().target_parallelism
+        };
+        // 1 MB/s per connection is far below the scale-up threshold, but
+        // every doubling still pays off.
+        assert_eq!(step(1e6, 4), 8);
+        assert_eq!(step(1e6, 8), 16);
+        // Twice the connections, barely more in total: the ramp ends.
+        assert_eq!(step(0.52e6, 16), 16);
+        assert_eq!(step(1e6, 16), 16);
+    }
+
+    #[test]
+    fn races_take_slots_from_segments() {
+        let segment = |id| SegmentTask {
+            id,
+            start: id as u64 * 10,
+            end: id as u64 * 10 + 9,
+            downloaded: 0,
+        };
+        let scheduler = Scheduler::new(vec![segment(0)], 2, 2);
+        assert!(scheduler.next_segment().is_some());
+        scheduler.on_race_started();
+        assert_eq!(scheduler.idle_slots(), 0);
+        scheduler.requeue(segment(1));
+        assert!(scheduler.next_segment().is_none());
+        scheduler.on_race_finished();
+        assert!(scheduler.next_segment().is_some());
+    }
+}
@@ -0,0 +1,290 @@
+//! Detached signatures checked once the download and its checksum are
+//! complete: OpenPGP through `gpgv`, and minisign.
+//!
+//! A checksum from the same mirror as the file only catches transfer errors;
+//! a signature ties the file to a key the user already trusts.
+
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::Engine;
+use log::info;
+use minisign_verify::{PublicKey, Signature};
+use reqwest::{Client, Url};
+use tempfile::NamedTempFile;
+use tokio::task;
+
+use crate::checksum::read_chunks;
+use crate::download::{OutputSink, SinkReader};
+use crate::util::fetch_bytes;
+
+#[derive(Debug, Clone)]
+enum SignatureKey {
+    /// Keyring for `gpgv`; armored keys are converted on the fly.
+    OpenPgp(PathBuf),
+    /// Base64 minisign public key.
+    Minisign(String),
+}
+
+/// Detached signature over the whole output, and the key that must have
+/// made it.
+#[derive(Debug, Clone)]
+pub struct SignatureSpec {
+    source: String,
+    key: SignatureKey,
+}
+
+impl SignatureSpec {
+    /// OpenPGP signature (binary or armored) at path or URL `source`, made by
+    /// a key in `keyring` (`gpg --export`, binary or armored).
+    pub fn openpgp(source: impl Into<String>, keyring: impl AsRef<Path>) -> Result<Self> {
+        let keyring = keyring.as_ref();
+        // gpgv looks up relative keyring names in its home directory.
+        let keyring = keyring
+            .canonicalize()
+            .with_context(|| format!("keyring not found: {}", keyring.display()))?;
+        Ok(Self {
+            source: source.into(),
+            key: SignatureKey::OpenPgp(keyring),
+        })
+    }
+
+    /// Minisign signature at path or URL `source`. `public_key` is the base64
+    /// key or the path of a `minisign.pub` file.
+    pub fn minisign(source: impl Into<String>, public_key: &str) -> Result<Self> {
+        let text = if Path::new(public_key).is_file() {
+            std::fs::read_to_string(public_key)
+                .with_context(|| format!("failed to read {public_key}"))?
+        } else {
+            public_key.to_string()
+        };
+        let key = text
+            .lines()
+            .map(str::trim)
+            .rfind(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+            .ok_or_else(|| anyhow!("minisign public key is empty"))?;
+        PublicKey::from_base64(key).map_err(|err| anyhow!("invalid minisign public key: {err}"))?;
+        Ok(Self {
+            source: source.into(),
+            key: SignatureKey::Minisign(key.to_string()),
+        })
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self.key {
+            SignatureKey::OpenPgp(_) => "openpgp",
+            SignatureKey::Minisign(_) => "minisign",
+        }
+    }
+
+    pub fn display(&self) -> String {
+        self.source.clone()
+    }
+
+    /// Reads the signature from its path, or from its URL through `client`.
+    pub async fn fetch(&self, client: &Client) -> Result<Vec<u8>> {
+        if self.source.starts_with("http://") || self.source.starts_with("https://") {
+            let url = Url::parse(&self.source)
+                .with_context(|| format!("invalid URL: {}", self.source))?;
+            fetch_bytes(client, &url)
+                .await?
+                .ok_or_else(|| anyhow!("signature not found: {url}"))
+        } else {
+            tokio::fs::read(&self.source)
+                .await
+                .with_context(|| format!("failed to read signature {}", self.source))
+        }
+    }
+
+    /// Checks `signature`, as returned by [`SignatureSpec::fetch`], over the
+    /// contents of `sink`.
+    pub async fn verify(&self, signature: Vec<u8>, sink: Arc<dyn OutputSink>) -> Result<()> {
+        let key = self.key.clone();
+        task::spawn_blocking(move || {
+            let data = SinkReader::new(sink.as_ref());
+            match &key {
+                SignatureKey::OpenPgp(keyring) => verify_openpgp(keyring, &signature, data),
+                SignatureKey::Minisign(public_key) => verify_minisign(public_key, &signature, data),
+            }
+        })
+        .await?
+    }
+}
+
+fn verify_minisign(public_key: &str, signature: &[u8], data: impl Read) -> Result<()> {
+    let public_key = PublicKey::from_base64(public_key)
+        .map_err(|err| anyhow!("invalid minisign public key: {err}"))?;
+    let signature = std::str::from_utf8(signature)
+        .ok()
+        .and_then(|text| Signature::decode(text).ok())
+        .ok_or_else(|| anyhow!("invalid minisign signature"))?;
+    let mut verifier = public_key
+        .verify_stream(&signature)
+        .map_err(|err| anyhow!("minisign signature verification failed: {err}"))?;
+    read_chunks(data, |chunk| verifier.update(chunk))?;
+    verifier
+        .finalize()
+        .map_err(|err| anyhow!("minisign signature verification failed: {err}"))?;
+    info!("good minisign signature ({})", signature.trusted_comment());
+    Ok(())
+}
+
+fn verify_openpgp(keyring: &Path, signature: &[u8], mut data: impl Read) -> Result<()> {
+    let mut signature_file = NamedTempFile::new()?;
+    signature_file.write_all(signature)?;
+
+    let keyring_text = std::fs::read(keyring)
+        .with_context(|| format!("failed to read keyring {}", keyring.display()))?;
+    // Kept alive until gpgv has exited.
+    let dearmored = match std::str::from_utf8(&keyring_text) {
+        Ok(text) if text.trim_start().starts_with("-----BEGIN PGP") => {
+            let mut file = NamedTempFile::new()?;
+            file.write_all(&dearmor(text)?)?;
+            Some(file)
+        }
+        _ => None,
+    };
+    let keyring = dearmored.as_ref().map_or(keyring, NamedTempFile::path);
+
+    let mut child = Command::new("gpgv")
+        .arg("--keyring")
+        .arg(keyring)
+        .arg(signature_file.path())
+        .arg("-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to run gpgv; is GnuPG installed?")?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let copied = std::io::copy(&mut data, &mut stdin);
+    drop(stdin);
+    let output = child.wait_with_output()?;
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let messages: Vec<&str> = stderr
+        .lines()
+        .map(|line| line.trim_start_matches("gpgv:").trim())
+        .filter(|line| !line.is_empty())
+        .collect();
+    if !output.status.success() {
+        bail!(
+            "OpenPGP signature verification failed: {}",
+            messages
+                .last()
+                .copied()
+                .unwrap_or("gpgv rejected the signature")
+        );
+    }
+    copied.context("failed to pass the download to gpgv")?;
+    if let Some(good) = messages
+        .iter()
+        .find(|line| line.starts_with("Good signature"))
+    {
+        info!("{good}");
+    }
+    Ok(())
+}
+
+/// Decodes every ASCII-armored block in `text`, skipping armor headers and
+/// checksum lines.
+fn dearmor(text: &str) -> Result<Vec<u8>> {
+    let mut packets = Vec::new();
+    let mut body = String::new();
+    let mut in_block = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with("-----BEGIN PGP") {
+            in_block = true;
+        } else if line.starts_with("-----END PGP") {
+            packets.extend(
+                base64::engine::general_purpose::STANDARD
+                    .decode(&body)
+                    .context("invalid armored key")?,
+            );
+            body.clear();
+            in_block = false;
+        } else if in_block && !line.is_empty() && !line.contains(": ") && !line.starts_with('=') {
+            body.push_str(line);
+        }
+    }
+    if packets.is_empty() {
+        bail!("no armored key found");
+    }
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::MemorySink;
+
+    const PAYLOAD: &[u8] = b"kdownload signed payload\n";
+
+    const MINISIGN_KEY: &str = "RWQIbF4pF/RTAB4TRl6K/49v0YuD6+Bxuhyk2oEg7qu93iY2ubtBuREZ";
+    const MINISIGN_SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQIbF4pF/RTAMVPGqa05nwLYNoK8d89AwjRfpqxr0IS8uGdB5UvD5O17E9nUw7qjY7BMiwP9X7WRL3UcuEgxI89vz4MhKGaggU=
+trusted comment: timestamp:1792000000\tfile:payload.txt
+aiWXvqvI70cQX65fXdgtm6lcDagXUMkT8Y8xUILxTjSbBvs3trCOzBo5Q/vVur7Nxe0oyfmaArk7tjeVX0rtBA==
+";
+
+    const OPENPGP_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----
+
+mDMEatJ2IRYJKwYBBAHaRw8BAQdA5z74AcJbpzk9ro0gKgQ/aPFUvskCkRh3WnV8
+Hnl94GC0IWtkb3dubG9hZCB0ZXN0IDx0ZXN0QGV4YW1wbGUuY29tPoiQBBMWCAA4
+FiEEFlTjba8AKE1752eBl8UFdb6o4dYFAmrSdiECGwMFCwkIBwIGFQoJCAsCBBYC
+AwECHgECF4AACgkQl8UFdb6o4dZZ7AEAoK430I9oNfLkVtdBztT1yz7+2NalrQYn
+UVkGslOhUZUA/ishLIsv0L51wqaghgDowhcU5LSPr0Wupli/CjSj2foF
+=BnDi
+-----END PGP PUBLIC KEY BLOCK-----
+";
+    const OPENPGP_SIGNATURE: &str = "-----BEGIN PGP SIGNATURE-----
+
+iHUEABYIAB0WIQQWVONtrwAoTXvnZ4GXxQV1vqjh1gUCatJ2LAAKCRCXxQV1vqjh
+1vLIAP97eiWyNTPjMV+QW278bA7we8UaHe6yWM/627d/OPLUuAEA7Px/UeO4uuPB
+frKEUOf8QpQnfT1xSU/eK/rzGyP2BQ4=
+=7ZIn
+-----END PGP SIGNATURE-----
+";
+
+    fn sink_with(data: &[u8]) -> Arc<dyn OutputSink> {
+        let sink = MemorySink::new();
+        sink.write_all_at(data, 0).unwrap();
+        Arc::new(sink)
+    }
+
+    #[tokio::test]
+    async fn checks_minisign_signatures() {
+        let spec = SignatureSpec::minisign("payload.txt.minisig", MINISIGN_KEY).unwrap();
+        let signature = MINISIGN_SIGNATURE.as_bytes().to_vec();
+        spec.verify(signature.clone(), sink_with(PAYLOAD))
+            .await
+            .expect("good signature");
+        assert!(spec
+            .verify(signature, sink_with(b"tampered payload\n"))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn checks_openpgp_signatures_with_an_armored_keyring() {
+        if Command::new("gpgv").arg("--version").output().is_err() {
+            eprintln!("gpgv not installed; skipping");
+            return;
+        }
+        let mut keyring = NamedTempFile::new().unwrap();
+        keyring.write_all(OPENPGP_KEY.as_bytes()).unwrap();
+        let spec = SignatureSpec::openpgp("payload.txt.asc", keyring.path()).unwrap();
+        let signature = OPENPGP_SIGNATURE.as_bytes().to_vec();
+        spec.verify(signature.clone(), sink_with(PAYLOAD))
+            .await
+            .expect("good signature");
+        let err = spec
+            .verify(signature, sink_with(b"tampered payload\n"))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("BAD signature"), "{err}");
+    }
+}
@@ -0,0 +1,89 @@
+//! Minimal HTTP server for tests that need a live mirror.
+
+use std::convert::Infallible;
+use std::net::TcpListener;
+use std::sync::Arc;
+
+use hyper::header::{self, HeaderValue};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+/// Serves `handler` on a free local port for the rest of the test and
+/// returns the base URL, e.g. `http://127.0.0.1:40123`.
+pub(crate) fn spawn<F>(handler: F) -> String
+where
+    F: Fn(&Request<Body>) -> Response<Body> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+    listener
+        .set_nonblocking(true)
+        .expect("non-blocking listener");
+    let addr = listener.local_addr().unwrap();
+    let handler = Arc::new(handler);
+    let make_service = make_service_fn(move |_| {
+        let handler = handler.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = handler(&req);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    let server = Server::from_tcp(listener)
+        .expect("test server")
+        .serve(make_service);
+    tokio::spawn(server);
+    format!("http://{addr}")
+}
+
+/// Answers `req` with `data` like a static file server: `206` for a
+/// satisfiable `Range`, `200` otherwise, and headers only for `HEAD`.
+pub(crate) fn file(req: &Request<Body>, data: &[u8]) -> Response<Body> {
+    let total = data.len() as u64;
+    let range = requested_range(req, total);
+    let body = match range {
+        Some((first, last)) => data[first as usize..=last as usize].to_vec(),
+        None => data.to_vec(),
+    };
+    let length = body.len();
+    let mut response = if req.method() == Method::HEAD {
+        Response::new(Body::empty())
+    } else {
+        Response::new(Body::from(body))
+    };
+    let headers = response.headers_mut();
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+    if let Some((first, last)) = range {
+        headers.insert(
+            header::CONTENT_RANGE,
+            HeaderValue::from_str(&format!("bytes {first}-{last}/{total}")).unwrap(),
+        );
+        *response.status_mut() = StatusCode::PARTIAL_CONTENT;
+    }
+    response
+}
+
+/// The `first..=last` bytes a `Range: bytes=...` header asks for.
+pub(crate) fn requested_range(req: &Request<Body>, total: u64) -> Option<(u64, u64)> {
+    let (first, last) = req
+        .headers()
+        .get(header::RANGE)?
+        .to_str()
+        .ok()?
+        .strip_prefix("bytes=")?
+        .split_once('-')?;
+    let first: u64 = first.parse().ok()?;
+    let last = match last {
+        "" => total.checked_sub(1)?,
+        last => last.parse::<u64>().ok()?.min(total.checked_sub(1)?),
+    };
+    (first <= last).then_some((first, last))
+}
+
+/// An empty response with `status`.
+pub(crate) fn status(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
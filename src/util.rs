@@ -5,6 +5,8 @@ use anyhow::{anyhow, Context, Result};
 use reqwest::Url;
 
 const DEFAULT_FILENAME: &str = "download.bin";
+/// Appended to the output name for its part map.
+pub const PARTMAP_SUFFIX: &str = ".kdl.partmap";
 
 pub fn infer_output_path(provided: Option<PathBuf>, urls: &[Url]) -> Result<PathBuf> {
     let primary = urls
@@ -51,12 +53,7 @@ pub fn infer_output_path(provided: Option<PathBuf>, urls: &[Url]) -> Result<Path
 
 fn filename_from_url(url: &Url) -> String {
     url.path_segments()
-        .and_then(|segments| {
-            segments
-                .filter(|s| !s.is_empty())
-                .last()
-                .map(|s| s.to_string())
-        })
+        .and_then(|mut segments| segments.rfind(|s| !s.is_empty()).map(|s| s.to_string()))
         .filter(|name| !name.ends_with('/'))
         .filter(|name| !name.trim().is_empty())
         .unwrap_or_else(|| DEFAULT_FILENAME.to_string())
@@ -67,10 +64,18 @@ pub fn derive_partmap_path(output: &Path) -> PathBuf {
         .file_name()
         .map(|os| os.to_os_string())
         .unwrap_or_else(|| DEFAULT_FILENAME.into());
-    name.push(".kdl.partmap");
+    name.push(PARTMAP_SUFFIX);
     output.with_file_name(name)
 }
 
+/// The output a part map belongs to, or `None` if `partmap` is not named
+/// like one.
+pub fn output_for_partmap(partmap: &Path) -> Option<PathBuf> {
+    let name = partmap.file_name()?.to_str()?;
+    let output = name.strip_suffix(PARTMAP_SUFFIX)?;
+    (!output.is_empty()).then(|| partmap.with_file_name(output))
+}
+
 pub fn parse_bandwidth_limit(input: &str) -> Result<u64> {
     let normalized = input
         .trim()
@@ -141,3 +146,42 @@ pub fn ensure_parent_dir(path: &Path) -> Result<()> {
     }
     Ok(())
 }
+
+/// Fetches a small file such as a checksum sidecar or a signature. Client
+/// errors (404 and friends) mean the file does not exist and yield `None`.
+pub async fn fetch_bytes(client: &reqwest::Client, url: &Url) -> Result<Option<Vec<u8>>> {
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .with_context(|| format!("failed to fetch {url}"))?;
+    if response.status().is_client_error() {
+        return Ok(None);
+    }
+    Ok(Some(response.error_for_status()?.bytes().await?.to_vec()))
+}
+
+/// Like [`fetch_bytes`], for text files.
+pub async fn fetch_text(client: &reqwest::Client, url: &Url) -> Result<Option<String>> {
+    match fetch_bytes(client, url).await? {
+        Some(bytes) => Ok(Some(
+            String::from_utf8(bytes).with_context(|| format!("{url} is not a text file"))?,
+        )),
+        None => Ok(None),
+    }
+}
+
+/// Reads a text document from an http(s) URL or a local path.
+pub async fn read_text_source(source: &str) -> Result<String> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        let response = reqwest::get(source)
+            .await
+            .with_context(|| format!("failed to fetch {source}"))?
+            .error_for_status()?;
+        Ok(response.text().await?)
+    } else {
+        tokio::fs::read_to_string(source)
+            .await
+            .with_context(|| format!("failed to read {source}"))
+    }
+}